@@ -0,0 +1,25 @@
+use std::{env, io, process};
+use timed_transfer::{HandleRegistry, Mailbox};
+
+fn main() -> Result<(), io::Error> {
+    let mailbox = Mailbox::open()?.with_registry(HandleRegistry::default());
+
+    match env::args().nth(1).as_deref() {
+        Some("list") => {
+            for entry in mailbox.registry().unwrap().entries()? {
+                println!("{} {}", entry.pid, entry.handle);
+            }
+        }
+        Some("reclaim") => {
+            for entry in timed_transfer::reclaim_stale(&mailbox)? {
+                println!("released handle {} of process {}", entry.handle, entry.pid);
+            }
+        }
+        _ => {
+            eprintln!("usage: gpu <list|reclaim>");
+            process::exit(2);
+        }
+    }
+
+    Ok(())
+}
@@ -6,17 +6,22 @@ use std::{
 use timed_transfer::{
     batch, dma,
     gpio::{self, Pin},
-    platform, smi, Mailbox,
+    platform::Platform,
+    smi, GpuMem, Mailbox,
 };
 
+// Far longer than a frame of the longest strips takes.
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
 struct Ws2812<'a> {
-    transfer: batch::Transfer<'a>,
+    transfer: batch::Transfer<GpuMem<'a>>,
     data: Vec<u32>,
 }
 
 impl<'a> Ws2812<'a> {
     pub fn new(mailbox: &'a Mailbox, len: usize) -> Result<Self, io::Error> {
-        let transfer = batch::Transfer::new(mailbox, len * 72)?;
+        // The next frame is written while the current one is output.
+        let transfer = batch::Transfer::new_buffered(mailbox, len * 72, 2)?;
         let mut data = vec![0; transfer.size()];
         data.iter_mut().step_by(3).for_each(|v| *v = 0xffffffff);
         Ok(Self { transfer, data })
@@ -27,25 +32,27 @@ impl<'a> Ws2812<'a> {
         smi_controller: &'b mut smi::Controller,
         smi_device: &'b mut SmiDevice,
         dma_channel: &'b mut DmaChannel,
-    ) -> ConfiguredWs2812<'b, SmiDevice, DmaChannel>
+    ) -> Result<ConfiguredWs2812<'b, SmiDevice, DmaChannel>, batch::ConfigureError>
     where
         'a: 'b,
     {
-        ConfiguredWs2812 {
+        let size = self.transfer.size();
+        Ok(ConfiguredWs2812 {
             transfer: self.transfer.configure(
                 smi_controller,
                 smi_device,
                 dma_channel,
+                None,
                 Duration::from_nanos(400),
-                self.transfer.size(),
-            ),
+                size,
+            )?,
             data: &mut self.data,
-        }
+        })
     }
 }
 
 struct ConfiguredWs2812<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
-    transfer: batch::ConfiguredTransfer<'a, SmiDevice, DmaChannel>,
+    transfer: batch::ConfiguredTransfer<'a, GpuMem<'a>, SmiDevice, DmaChannel>,
     data: &'a mut [u32],
 }
 
@@ -70,9 +77,12 @@ impl<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel>
         }
     }
 
-    pub fn show(&mut self) {
-        self.transfer.set_data(self.data);
-        self.transfer.start();
+    /// Outputs the colors once the last output is finished.
+    pub fn show(&mut self) -> Result<(), io::Error> {
+        self.transfer.set_data(self.data)?;
+        self.transfer.wait(FRAME_TIMEOUT)?;
+        self.transfer.start()?;
+        Ok(())
     }
 }
 
@@ -81,10 +91,10 @@ fn main() -> Result<(), io::Error> {
 
     ctrlc::set_handler(move || tx.send(()).unwrap()).expect("Error setting Ctrl-C handler");
 
-    let platform = platform::RASPBERRY_PI_ZERO_1;
+    let platform = Platform::detect()?;
 
     let mut smi = smi::Peripheral::open(&platform)?;
-    let mut dma = dma::Peripheral::open(&platform)?;
+    let dma = dma::Peripheral::open(&platform)?;
     let mut gpio = gpio::Peripheral::open(&platform)?;
     let mailbox = Mailbox::open()?;
 
@@ -112,11 +122,9 @@ fn main() -> Result<(), io::Error> {
     // Set the number of leds based on the longest strip.
     let mut strips = Ws2812::new(&mailbox, 30)?;
 
-    let mut strips = strips.configure(
-        &mut smi.controller,
-        &mut smi.devices.device0,
-        &mut dma.channels.channel5,
-    );
+    let mut smi_device = smi.devices.device0()?;
+    let mut dma_channel = dma.channels.channel5()?;
+    let mut strips = strips.configure(&mut smi.controller, &mut smi_device, &mut dma_channel)?;
 
     let mut time = 0;
 
@@ -139,7 +147,7 @@ fn main() -> Result<(), io::Error> {
         }
         time += 1;
 
-        strips.show();
+        strips.show()?;
     }
 
     Ok(())
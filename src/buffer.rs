@@ -0,0 +1,270 @@
+use std::{
+    io,
+    marker::PhantomData,
+    mem, ops, ptr, slice,
+    sync::atomic::{self, AtomicBool, Ordering},
+};
+
+use crate::{
+    gpu::GpuMem,
+    mailbox::MailboxError,
+    mem::{DmaMemory, MemMap},
+    Mailbox,
+};
+
+/// Types that can be shared with the DMA engine as plain bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value and the type must not contain padding.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A typed view of DMA memory.
+///
+/// The buffer can be marked as in flight while a DMA transfer reads from it, mutable access
+/// panics until the transfer is finished.
+pub struct DmaBuffer<T: Pod, M: DmaMemory> {
+    memory: M,
+    len: usize,
+    in_flight: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: Pod> DmaBuffer<T, GpuMem<'a>> {
+    pub fn alloc(mailbox: &'a Mailbox, len: usize) -> Result<Self, MailboxError> {
+        let memory = GpuMem::alloc(mailbox, byte_len::<T>(len)?)?;
+        Ok(Self::new(memory, len)?)
+    }
+}
+
+impl<T: Pod, M: DmaMemory> DmaBuffer<T, M> {
+    /// Uses the first `len` elements of `memory`, the rest stays available through
+    /// [`DmaBuffer::memory`].
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if `len` elements don't fit into `memory`
+    /// or `memory` is not aligned for `T`.
+    pub fn new(memory: M, len: usize) -> Result<Self, io::Error> {
+        if byte_len::<T>(len)? > memory.size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DMA memory is too small for the buffer",
+            ));
+        }
+        if !memory.memmap().virt.cast::<T>().is_aligned() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DMA memory is not aligned for the buffer",
+            ));
+        }
+
+        Ok(Self {
+            memory,
+            len,
+            in_flight: AtomicBool::new(false),
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memmap(&self) -> &MemMap {
+        self.memory.memmap()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.memory.memmap().virt.cast(), self.len) }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the buffer is in flight.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        assert!(!self.is_in_flight(), "DMA buffer is in flight");
+        unsafe { slice::from_raw_parts_mut(self.memory.memmap().virt.cast(), self.len) }
+    }
+
+    /// Copies all elements from `src`, which must have the same length as the buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer is in flight or the lengths differ.
+    pub fn copy_from_slice(&mut self, src: &[T]) {
+        self.as_mut_slice().copy_from_slice(src);
+    }
+
+    pub fn is_in_flight(&self) -> bool {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Marks the buffer as read or written by DMA.
+    ///
+    /// Setting the flag also makes sure all previous writes are done before the caller
+    /// starts the transfer. Only the transfers clear the flag, once the DMA is done with
+    /// the buffer.
+    pub(crate) fn set_in_flight(&self, in_flight: bool) {
+        if in_flight {
+            atomic::fence(Ordering::SeqCst);
+            self.memory.flush();
+        } else {
+            self.memory.begin_cpu_access();
+        }
+        self.in_flight.store(in_flight, Ordering::Release);
+    }
+
+    /// Copies `src` to the start of the buffer without checking the in flight flag.
+    ///
+    /// # Safety
+    ///
+    /// No other reference into the buffer may exist.
+    pub(crate) unsafe fn write_unchecked(&self, src: &[T]) {
+        assert!(src.len() <= self.len);
+        ptr::copy_nonoverlapping(src.as_ptr(), self.memory.memmap().virt.cast(), src.len());
+    }
+}
+
+/// Size of `len` elements of `T` in bytes.
+fn byte_len<T>(len: usize) -> Result<usize, io::Error> {
+    len.checked_mul(mem::size_of::<T>()).ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "DMA buffer length overflows",
+    ))
+}
+
+impl<T: Pod, M: DmaMemory> ops::Index<usize> for DmaBuffer<T, M> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.as_slice()[index]
+    }
+}
+
+impl<T: Pod, M: DmaMemory> ops::IndexMut<usize> for DmaBuffer<T, M> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut self.as_mut_slice()[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Soc, sim::Board};
+
+    // Heap memory starting `offset` bytes into an aligned allocation.
+    struct HeapMem {
+        _storage: Vec<u64>,
+        memmap: MemMap,
+        size: usize,
+    }
+
+    impl HeapMem {
+        fn new(size: usize, offset: usize) -> HeapMem {
+            let mut storage = vec![0u64; (size + offset).div_ceil(8)];
+            let virt = storage.as_mut_ptr().cast::<u32>().wrapping_byte_add(offset);
+            HeapMem {
+                _storage: storage,
+                memmap: MemMap {
+                    bus: virt,
+                    phys: virt,
+                    virt,
+                },
+                size,
+            }
+        }
+    }
+
+    impl DmaMemory for HeapMem {
+        fn size(&self) -> usize {
+            self.size
+        }
+
+        fn memmap(&self) -> &MemMap {
+            &self.memmap
+        }
+    }
+
+    #[test]
+    fn unaligned_memory_is_rejected() {
+        let err = DmaBuffer::<u64, _>::new(HeapMem::new(64, 4), 8)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        assert!(DmaBuffer::<u32, _>::new(HeapMem::new(64, 4), 16).is_ok());
+    }
+
+    #[test]
+    fn slices_are_copied() {
+        let mut buffer = DmaBuffer::<u16, _>::new(HeapMem::new(64, 0), 4).unwrap();
+        buffer.copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(buffer.as_slice(), [1, 2, 3, 4]);
+
+        buffer[2] = 5;
+        assert_eq!(buffer[2], 5);
+        assert_eq!(buffer.memory().size(), 64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn copies_need_the_same_length() {
+        let mut buffer = DmaBuffer::<u32, _>::new(HeapMem::new(64, 0), 4).unwrap();
+        buffer.copy_from_slice(&[1, 2, 3]);
+    }
+
+    #[test]
+    fn in_flight_buffers_are_only_readable() {
+        let mut buffer = DmaBuffer::<u32, _>::new(HeapMem::new(64, 0), 4).unwrap();
+        buffer.set_in_flight(true);
+        assert!(buffer.is_in_flight());
+        assert_eq!(buffer.as_slice(), [0; 4]);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            buffer.copy_from_slice(&[1; 4]);
+        }));
+        assert!(result.is_err());
+        assert_eq!(buffer.as_slice(), [0; 4]);
+
+        buffer.set_in_flight(false);
+        buffer.copy_from_slice(&[1; 4]);
+        assert_eq!(buffer.as_slice(), [1; 4]);
+    }
+
+    #[test]
+    fn oversized_lengths_are_rejected() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+
+        for len in [usize::MAX / 2, 1025] {
+            let memory = GpuMem::alloc(&mailbox, 4096).unwrap();
+            let err = DmaBuffer::<u32, _>::new(memory, len).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let err = DmaBuffer::<u64, GpuMem>::alloc(&mailbox, usize::MAX / 4)
+            .err()
+            .unwrap();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput);
+
+        let buffer = DmaBuffer::<u32, _>::new(GpuMem::alloc(&mailbox, 4096).unwrap(), 1024);
+        assert_eq!(buffer.unwrap().len(), 1024);
+    }
+}
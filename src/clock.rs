@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use crate::{
+    gpu::MailboxRef,
+    mailbox::{ClockId, Mailbox, MailboxError},
+};
+
+/// Holds a firmware clock at a fixed rate, the previous rate is restored on drop.
+///
+/// Pass the guard to [`batch::Transfer::configure`](crate::batch::Transfer::configure) so the
+/// clock stays pinned for as long as the transfer is configured.
+pub struct ClockGuard<'a> {
+    mailbox: MailboxRef<'a>,
+    clock: ClockId,
+    previous: u32,
+    /// Rate the firmware chose for the requested rate.
+    set: u32,
+    rate: u32,
+}
+
+impl<'a> ClockGuard<'a> {
+    /// Sets the clock to `rate` Hz, without raising the other clocks to their turbo rates.
+    pub fn lock(mailbox: &'a Mailbox, clock: ClockId, rate: u32) -> Result<Self, MailboxError> {
+        Self::lock_in(MailboxRef::Borrowed(mailbox), clock, rate)
+    }
+
+    /// Holds the clock at its current rate.
+    pub fn pin(mailbox: &'a Mailbox, clock: ClockId) -> Result<Self, MailboxError> {
+        let rate = mailbox.clock_rate(clock)?;
+        Self::lock(mailbox, clock, rate)
+    }
+
+    fn lock_in(mailbox: MailboxRef<'a>, clock: ClockId, rate: u32) -> Result<Self, MailboxError> {
+        let previous = mailbox.clock_rate(clock)?;
+        let set = mailbox.set_clock_rate(clock, rate, true)?;
+
+        let mut guard = ClockGuard {
+            mailbox,
+            clock,
+            previous,
+            set,
+            rate: set,
+        };
+        // The guard restores the previous rate if measuring fails.
+        guard.rate = guard.mailbox.measured_clock_rate(clock)?;
+
+        Ok(guard)
+    }
+
+    pub fn clock(&self) -> ClockId {
+        self.clock
+    }
+
+    /// Returns the measured rate in Hz.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+impl ClockGuard<'static> {
+    /// Same as [`ClockGuard::lock`], but keeps `mailbox` alive instead of borrowing it.
+    pub fn lock_shared(
+        mailbox: Arc<Mailbox>,
+        clock: ClockId,
+        rate: u32,
+    ) -> Result<Self, MailboxError> {
+        Self::lock_in(MailboxRef::Shared(mailbox), clock, rate)
+    }
+
+    /// Same as [`ClockGuard::pin`], but keeps `mailbox` alive instead of borrowing it.
+    pub fn pin_shared(mailbox: Arc<Mailbox>, clock: ClockId) -> Result<Self, MailboxError> {
+        let rate = mailbox.clock_rate(clock)?;
+        Self::lock_shared(mailbox, clock, rate)
+    }
+}
+
+impl<'a> Drop for ClockGuard<'a> {
+    fn drop(&mut self) {
+        if self.set != self.previous {
+            // Restored the same way it was set by `lock`.
+            let _ = self.mailbox.set_clock_rate(self.clock, self.previous, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailbox::Tag,
+        platform::Soc,
+        sim::{Board, SIM_DEFAULT_CLOCK_RATE, SIM_MAX_CLOCK_RATE},
+        test_util::RecordingFirmware,
+    };
+
+    #[test]
+    fn previous_rate_is_restored_without_turbo() {
+        let board = Board::new(Soc::Bcm2837);
+        let (firmware, mailbox) = RecordingFirmware::new(&board, &[]);
+
+        let guard = ClockGuard::lock(&mailbox, ClockId::Core, SIM_MAX_CLOCK_RATE).unwrap();
+        assert_eq!(guard.rate(), SIM_MAX_CLOCK_RATE);
+        assert_eq!(
+            mailbox.clock_rate(ClockId::Core).unwrap(),
+            SIM_MAX_CLOCK_RATE
+        );
+        drop(guard);
+
+        assert_eq!(
+            mailbox.clock_rate(ClockId::Core).unwrap(),
+            SIM_DEFAULT_CLOCK_RATE
+        );
+        let requests = firmware.requests(Tag::SetClockRate);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1],
+            [ClockId::Core as u32, SIM_DEFAULT_CLOCK_RATE, 1]
+        );
+    }
+
+    #[test]
+    fn pinned_clocks_are_not_set_again() {
+        let board = Board::new(Soc::Bcm2837);
+        let (firmware, mailbox) = RecordingFirmware::new(&board, &[]);
+
+        let guard = ClockGuard::pin(&mailbox, ClockId::Core).unwrap();
+        assert_eq!(guard.rate(), SIM_DEFAULT_CLOCK_RATE);
+        drop(guard);
+
+        assert_eq!(firmware.requests(Tag::SetClockRate).len(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Clone, Copy)]
 pub struct Field<T> {
     offset: usize,
     mask: T,
@@ -1,95 +1,423 @@
-use std::io;
+use std::{error, fmt, io, ops::Deref, sync::Arc};
+
+mod pool;
+mod registry;
+
+pub use pool::*;
+pub use registry::*;
 
 use super::{
-    mailbox::{Mailbox, MEM_FLAG_DIRECT, MEM_FLAG_ZERO},
-    mem::{map_phys_to_virt, unmap_phys_to_virt, MemMap},
-    platform::PAGE_SIZE,
+    mailbox::{Mailbox, MailboxError, BUS_ALIAS_MASK, MEM_FLAG_DIRECT, MEM_FLAG_ZERO},
+    mem::{DmaMemory, MemMap},
+    platform::{Platform, PAGE_SIZE},
 };
 
+/// The mailbox memory was allocated through, either borrowed or shared.
+pub enum MailboxRef<'a> {
+    Borrowed(&'a Mailbox),
+    Shared(Arc<Mailbox>),
+}
+
+impl<'a> Deref for MailboxRef<'a> {
+    type Target = Mailbox;
+
+    fn deref(&self) -> &Mailbox {
+        match self {
+            MailboxRef::Borrowed(mailbox) => mailbox,
+            MailboxRef::Shared(mailbox) => mailbox,
+        }
+    }
+}
+
 pub struct GpuMem<'a> {
-    mailbox: &'a Mailbox,
+    mailbox: MailboxRef<'a>,
     handle: u32,
     size: usize,
+    flags: u32,
+    map_offset: usize,
     memmap: MemMap,
+    freed: bool,
 }
 
 impl<'a> GpuMem<'a> {
-    pub fn alloc(mailbox: &Mailbox, size: usize) -> Result<GpuMem, io::Error> {
-        let size = size.next_multiple_of(PAGE_SIZE);
+    pub fn alloc(mailbox: &Mailbox, size: usize) -> Result<GpuMem<'_>, MailboxError> {
+        GpuMem::builder().alloc(mailbox, size)
+    }
+
+    /// Allocates memory that keeps `mailbox` alive instead of borrowing it.
+    pub fn alloc_shared(
+        mailbox: Arc<Mailbox>,
+        size: usize,
+    ) -> Result<GpuMem<'static>, MailboxError> {
+        GpuMem::builder().alloc_shared(mailbox, size)
+    }
+
+    pub fn builder() -> GpuMemBuilder {
+        GpuMemBuilder {
+            flags: MEM_FLAG_DIRECT,
+            align: PAGE_SIZE,
+            zero: true,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+
+    pub fn mailbox(&self) -> &Mailbox {
+        &self.mailbox
+    }
 
-        let mut data = [0u32; 9];
+    /// Unmaps, unlocks and releases the memory.
+    ///
+    /// Releasing is attempted even if unlocking fails, so the allocation isn't leaked, the
+    /// error then reports both results.
+    pub fn free(mut self) -> Result<(), FreeError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), FreeError> {
+        // Every step is only attempted once, even if it fails.
+        self.freed = true;
 
-        data[0] = 36;
-        data[1] = 0x00000000;
-        data[2] = 0x3000c;
-        data[3] = 12;
-        data[4] = 12;
-        data[5] = size as u32;
-        data[6] = PAGE_SIZE as u32;
-        data[7] = MEM_FLAG_DIRECT | MEM_FLAG_ZERO;
-        data[8] = 0x00000000;
+        unsafe {
+            self.mailbox.memory().unmap(
+                self.memmap.virt.byte_sub(self.map_offset),
+                self.size + self.map_offset,
+            )
+        };
 
-        unsafe { mailbox.send(data.as_ptr() as *const libc::c_void) }?;
+        let unlock = self.mailbox.unlock_memory(self.handle);
+        let release = self.mailbox.release_memory(self.handle);
 
-        let handle = data[5];
+        // Handles the firmware still knows stay registered, so they can be reclaimed.
+        let unregister = match (&release, self.mailbox.registry()) {
+            (Ok(()), Some(registry)) => registry.unregister(self.handle),
+            _ => Ok(()),
+        };
 
-        data[0] = 28;
-        data[1] = 0x00000000;
-        data[2] = 0x3000d;
-        data[3] = 4;
-        data[4] = 4;
-        data[5] = handle;
-        data[6] = 0x00000000;
+        match (unlock, release) {
+            (Err(unlock), release) => Err(FreeError::Unlock {
+                unlock,
+                release: release.err(),
+            }),
+            (Ok(()), Err(err)) => Err(FreeError::Release(err)),
+            (Ok(()), Ok(())) => unregister.map_err(FreeError::Unregister),
+        }
+    }
+}
 
-        unsafe { mailbox.send(data.as_ptr() as *const libc::c_void) }?;
+impl<'a> DmaMemory for GpuMem<'a> {
+    fn size(&self) -> usize {
+        self.size
+    }
 
-        let bus = data[5] as *mut u32;
-        let phys = (data[5] - 0xC0000000) as *mut u32;
-        let virt = unsafe { map_phys_to_virt(phys, size) }?;
+    fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+}
+
+// The mapping is owned by the allocation and the mailbox can be shared between threads.
+unsafe impl<'a> Send for GpuMem<'a> {}
+unsafe impl<'a> Sync for GpuMem<'a> {}
+
+impl<'a> Drop for GpuMem<'a> {
+    fn drop(&mut self) {
+        if !self.freed {
+            // Errors can't be reported from here, use `GpuMem::free` to handle them.
+            let _ = self.release();
+        }
+    }
+}
+
+pub struct GpuMemBuilder {
+    flags: u32,
+    align: usize,
+    zero: bool,
+}
+
+impl GpuMemBuilder {
+    /// Sets the `MEM_FLAG_*` allocation flags, zeroing is controlled by [`GpuMemBuilder::zero`].
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags & !MEM_FLAG_ZERO;
+        self
+    }
+
+    /// Uses the cache alias that is coherent with the ARM of `platform`.
+    pub fn coherent_for(self, platform: &Platform) -> Self {
+        self.flags(platform.soc.coherent_mem_flags())
+    }
+
+    /// Sets the alignment in bytes, must be a power of two.
+    pub fn align(mut self, align: usize) -> Self {
+        assert!(align.is_power_of_two());
+        self.align = align;
+        self
+    }
+
+    pub fn zero(mut self, zero: bool) -> Self {
+        self.zero = zero;
+        self
+    }
+
+    pub fn alloc(self, mailbox: &Mailbox, size: usize) -> Result<GpuMem<'_>, MailboxError> {
+        self.alloc_in(MailboxRef::Borrowed(mailbox), size)
+    }
+
+    pub fn alloc_shared(
+        self,
+        mailbox: Arc<Mailbox>,
+        size: usize,
+    ) -> Result<GpuMem<'static>, MailboxError> {
+        self.alloc_in(MailboxRef::Shared(mailbox), size)
+    }
+
+    fn alloc_in(self, mailbox: MailboxRef<'_>, size: usize) -> Result<GpuMem<'_>, MailboxError> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let flags = if self.zero {
+            self.flags | MEM_FLAG_ZERO
+        } else {
+            self.flags
+        };
+
+        let handle = mailbox.allocate_memory(size as u32, self.align as u32, flags)?;
+
+        // Record the handle before anything else can fail, so it can be reclaimed after a crash.
+        if let Some(registry) = mailbox.registry() {
+            if let Err(err) = registry.register(handle) {
+                let _ = mailbox.release_memory(handle);
+                return Err(err.into());
+            }
+        }
+
+        let bus = match mailbox.lock_memory(handle) {
+            Ok(bus) => bus,
+            Err(err) => {
+                release_unlocked(&mailbox, handle);
+                return Err(err);
+            }
+        };
+
+        // The firmware returns the address in the alias selected by the cache flags, unless
+        // it can't provide it, for example with the L2 cache disabled. The returned alias is
+        // the one DMA has to use. GPU memory is RAM, which every SoC maps the same way.
+        let phys = bus & !BUS_ALIAS_MASK;
+
+        // Mappings have to start at a page boundary, even if the allocation does not.
+        let map_offset = phys as usize % PAGE_SIZE;
+        let map_phys = (phys as usize - map_offset) as *mut u32;
+        let virt = match unsafe { mailbox.memory().map(map_phys, size + map_offset) } {
+            Ok(virt) => virt,
+            Err(err) => {
+                let _ = mailbox.unlock_memory(handle);
+                release_unlocked(&mailbox, handle);
+                return Err(err.into());
+            }
+        };
 
         Ok(GpuMem {
             mailbox,
             handle,
             size,
-            memmap: MemMap { bus, phys, virt },
+            flags,
+            map_offset,
+            memmap: MemMap {
+                bus: bus as *mut u32,
+                phys: phys as *mut u32,
+                virt: unsafe { virt.byte_add(map_offset) },
+            },
+            freed: false,
         })
     }
+}
 
-    pub fn size(&self) -> usize {
-        self.size
+fn release_unlocked(mailbox: &Mailbox, handle: u32) {
+    if mailbox.release_memory(handle).is_ok() {
+        if let Some(registry) = mailbox.registry() {
+            let _ = registry.unregister(handle);
+        }
     }
+}
 
-    pub fn memmap(&self) -> &MemMap {
-        &self.memmap
+#[derive(Debug)]
+pub enum FreeError {
+    /// Unlocking failed, `release` holds the error of the release attempted afterwards.
+    Unlock {
+        unlock: MailboxError,
+        release: Option<MailboxError>,
+    },
+    Release(MailboxError),
+    Unregister(io::Error),
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreeError::Unlock {
+                unlock,
+                release: None,
+            } => write!(f, "failed to unlock GPU memory: {unlock}"),
+            FreeError::Unlock {
+                unlock,
+                release: Some(release),
+            } => write!(
+                f,
+                "failed to unlock GPU memory: {unlock}, releasing it failed as well: {release}"
+            ),
+            FreeError::Release(err) => write!(f, "failed to release GPU memory: {err}"),
+            FreeError::Unregister(err) => {
+                write!(f, "failed to remove GPU memory handle from registry: {err}")
+            }
+        }
     }
 }
 
-impl<'a> Drop for GpuMem<'a> {
-    fn drop(&mut self) {
-        let mailbox = &mut self.mailbox;
+impl error::Error for FreeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FreeError::Unlock { unlock: err, .. } | FreeError::Release(err) => Some(err),
+            FreeError::Unregister(err) => Some(err),
+        }
+    }
+}
+
+impl From<FreeError> for io::Error {
+    fn from(err: FreeError) -> Self {
+        match err {
+            FreeError::Unlock { unlock: err, .. } | FreeError::Release(err) => err.into(),
+            FreeError::Unregister(err) => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailbox::{bus_alias, Tag, MEM_FLAG_COHERENT, MEM_FLAG_L1_NONALLOCATING},
+        platform::Soc,
+        sim::Board,
+        test_util::RecordingFirmware,
+    };
+
+    #[test]
+    fn memory_is_released_once() {
+        let board = Board::new(Soc::Bcm2837);
+        let (firmware, mailbox) = RecordingFirmware::new(&board, &[]);
+
+        GpuMem::alloc(&mailbox, PAGE_SIZE).unwrap().free().unwrap();
+        assert_eq!(firmware.requests(Tag::UnlockMemory).len(), 1);
+        assert_eq!(firmware.requests(Tag::ReleaseMemory).len(), 1);
 
-        unsafe { unmap_phys_to_virt(self.memmap.virt, self.size) };
+        drop(GpuMem::alloc(&mailbox, PAGE_SIZE).unwrap());
+        assert_eq!(firmware.requests(Tag::UnlockMemory).len(), 2);
+        assert_eq!(firmware.requests(Tag::ReleaseMemory).len(), 2);
+    }
+
+    #[test]
+    fn failed_unlock_still_releases() {
+        let board = Board::new(Soc::Bcm2837);
+        let (firmware, mailbox) = RecordingFirmware::new(&board, &[Tag::UnlockMemory]);
+
+        let err = GpuMem::alloc(&mailbox, PAGE_SIZE)
+            .unwrap()
+            .free()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FreeError::Unlock {
+                unlock: MailboxError::Failed(Tag::UnlockMemory),
+                release: None,
+            }
+        ));
+        assert_eq!(firmware.requests(Tag::ReleaseMemory).len(), 1);
+        assert_eq!(
+            err.to_string(),
+            "failed to unlock GPU memory: tag UnlockMemory failed"
+        );
+    }
+
+    #[test]
+    fn both_errors_are_reported() {
+        let board = Board::new(Soc::Bcm2837);
+        let (_, mailbox) = RecordingFirmware::new(&board, &[Tag::UnlockMemory, Tag::ReleaseMemory]);
+
+        let err = GpuMem::alloc(&mailbox, PAGE_SIZE)
+            .unwrap()
+            .free()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FreeError::Unlock {
+                release: Some(MailboxError::Failed(Tag::ReleaseMemory)),
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "failed to unlock GPU memory: tag UnlockMemory failed, releasing it failed as well: \
+             tag ReleaseMemory failed"
+        );
 
-        let mut data = [0u32; 9];
+        let (_, mailbox) = RecordingFirmware::new(&board, &[Tag::ReleaseMemory]);
+        let err = GpuMem::alloc(&mailbox, PAGE_SIZE)
+            .unwrap()
+            .free()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FreeError::Release(MailboxError::Failed(Tag::ReleaseMemory))
+        ));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn bus_address_uses_the_alias_of_the_flags() {
+        let board = Board::new(Soc::Bcm2835);
+        let mailbox = board.mailbox();
+
+        for flags in [
+            MEM_FLAG_DIRECT,
+            MEM_FLAG_COHERENT,
+            MEM_FLAG_L1_NONALLOCATING,
+        ] {
+            let memory = GpuMem::builder()
+                .flags(flags)
+                .alloc(&mailbox, PAGE_SIZE)
+                .unwrap();
+
+            let bus = memory.memmap().bus as u32;
+            assert_eq!(bus & BUS_ALIAS_MASK, bus_alias(flags));
+            assert_eq!(memory.memmap().phys as u32, bus & !BUS_ALIAS_MASK);
+        }
+    }
 
-        data[0] = 28;
-        data[1] = 0x00000000;
-        data[2] = 0x3000e;
-        data[3] = 4;
-        data[4] = 4;
-        data[5] = self.handle;
-        data[6] = 0x00000000;
+    #[test]
+    fn returned_alias_is_accepted() {
+        let board = Board::new(Soc::Bcm2835);
+        board.disable_l2_cache();
+        let mailbox = board.mailbox();
 
-        unsafe { mailbox.send(data.as_ptr() as *const libc::c_void).unwrap() };
+        let memory = GpuMem::builder()
+            .coherent_for(&board.platform())
+            .alloc(&mailbox, PAGE_SIZE)
+            .unwrap();
 
-        data[0] = 28;
-        data[1] = 0x00000000;
-        data[2] = 0x3000f;
-        data[3] = 4;
-        data[4] = 4;
-        data[5] = self.handle;
-        data[6] = 0x00000000;
+        let bus = memory.memmap().bus as u32;
+        let phys = memory.memmap().phys as usize;
+        assert_eq!(bus & BUS_ALIAS_MASK, bus_alias(MEM_FLAG_DIRECT));
+        assert_eq!(phys, (bus & !BUS_ALIAS_MASK) as usize);
 
-        unsafe { mailbox.send(data.as_ptr() as *const libc::c_void).unwrap() };
+        unsafe { memory.memmap().virt.write_volatile(0x5eb1a7ed) };
+        let virt = board.simulated_memory().virt(phys).unwrap();
+        assert_eq!(unsafe { virt.read_volatile() }, 0x5eb1a7ed);
     }
 }
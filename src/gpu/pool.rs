@@ -0,0 +1,285 @@
+use std::sync::Mutex;
+
+use crate::{
+    gpu::GpuMem,
+    mailbox::MailboxError,
+    mem::{DmaMemory, MemMap},
+    Mailbox,
+};
+
+/// Hands out sub-regions of a single GPU memory allocation.
+///
+/// Regions are placed with a first fit search and returned to the pool when dropped.
+pub struct GpuPool<'a> {
+    gpu_mem: GpuMem<'a>,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    // Sorted by offset, adjacent ranges are merged.
+    free: Vec<(usize, usize)>,
+    allocations: usize,
+}
+
+impl<'a> GpuPool<'a> {
+    pub fn new(mailbox: &'a Mailbox, size: usize) -> Result<Self, MailboxError> {
+        Ok(Self::from_gpu_mem(GpuMem::alloc(mailbox, size)?))
+    }
+
+    pub fn from_gpu_mem(gpu_mem: GpuMem<'a>) -> Self {
+        let size = gpu_mem.size();
+        Self {
+            gpu_mem,
+            state: Mutex::new(PoolState {
+                free: vec![(0, size)],
+                allocations: 0,
+            }),
+        }
+    }
+
+    pub fn gpu_mem(&self) -> &GpuMem<'a> {
+        &self.gpu_mem
+    }
+
+    /// Allocates `size` bytes aligned to `align` bytes in the bus address space.
+    ///
+    /// Returns `None` if no free range is large enough.
+    pub fn alloc(&self, size: usize, align: usize) -> Option<PoolRegion<'_>> {
+        assert!(align.is_power_of_two());
+        let size = size.max(1);
+        let bus = self.gpu_mem.memmap().bus as usize;
+
+        let mut state = self.state.lock().unwrap();
+        let (index, offset) = state
+            .free
+            .iter()
+            .enumerate()
+            .find_map(|(i, &(start, len))| {
+                let offset = (bus + start).next_multiple_of(align) - bus;
+                (offset + size <= start + len).then_some((i, offset))
+            })?;
+
+        let (start, len) = state.free.remove(index);
+        if offset + size < start + len {
+            state
+                .free
+                .insert(index, (offset + size, start + len - offset - size));
+        }
+        if start < offset {
+            state.free.insert(index, (start, offset - start));
+        }
+        state.allocations += 1;
+
+        let memmap = self.gpu_mem.memmap();
+        Some(PoolRegion {
+            pool: self,
+            offset,
+            size,
+            memmap: MemMap {
+                bus: memmap.bus.wrapping_byte_add(offset),
+                phys: memmap.phys.wrapping_byte_add(offset),
+                virt: memmap.virt.wrapping_byte_add(offset),
+            },
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.state.lock().unwrap();
+        let free = state.free.iter().map(|&(_, len)| len).sum::<usize>();
+
+        PoolStats {
+            size: self.gpu_mem.size(),
+            used: self.gpu_mem.size() - free,
+            free,
+            largest_free: state.free.iter().map(|&(_, len)| len).max().unwrap_or(0),
+            free_ranges: state.free.len(),
+            allocations: state.allocations,
+        }
+    }
+
+    fn release(&self, offset: usize, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        let index = state.free.partition_point(|&(start, _)| start < offset);
+        state.free.insert(index, (offset, size));
+        state.allocations -= 1;
+
+        if index + 1 < state.free.len() && offset + size == state.free[index + 1].0 {
+            let (_, len) = state.free.remove(index + 1);
+            state.free[index].1 += len;
+        }
+        if index > 0 && state.free[index - 1].0 + state.free[index - 1].1 == offset {
+            let (_, len) = state.free.remove(index);
+            state.free[index - 1].1 += len;
+        }
+    }
+}
+
+/// A region of a [`GpuPool`], returned to the pool on drop.
+pub struct PoolRegion<'a> {
+    pool: &'a GpuPool<'a>,
+    offset: usize,
+    size: usize,
+    memmap: MemMap,
+}
+
+impl<'a> PoolRegion<'a> {
+    /// Offset of the region from the start of the pool.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+}
+
+impl<'a> DmaMemory for PoolRegion<'a> {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+}
+
+impl<'a> Drop for PoolRegion<'a> {
+    fn drop(&mut self) {
+        self.pool.release(self.offset, self.size);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub largest_free: usize,
+    pub free_ranges: usize,
+    pub allocations: usize,
+}
+
+impl PoolStats {
+    /// Share of the free memory that can't be used by an allocation of the largest free
+    /// size, 0 if all free memory is contiguous.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f32 / self.free as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Soc, sim::Board};
+
+    #[test]
+    fn regions_are_placed_first_fit() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let pool = GpuPool::new(&mailbox, 4096).unwrap();
+
+        let a = pool.alloc(100, 4).unwrap();
+        let b = pool.alloc(100, 4).unwrap();
+        let c = pool.alloc(100, 4).unwrap();
+        assert_eq!((a.offset(), b.offset(), c.offset()), (0, 100, 200));
+        assert_eq!(
+            b.memmap().bus,
+            pool.gpu_mem().memmap().bus.wrapping_byte_add(100)
+        );
+
+        // The gap left by `a` is the first fit for smaller regions only.
+        drop(a);
+        assert_eq!(pool.alloc(200, 4).unwrap().offset(), 300);
+        let d = pool.alloc(50, 4).unwrap();
+        assert_eq!(d.offset(), 0);
+
+        // Aligned regions skip to the next multiple, keeping the range before.
+        let e = pool.alloc(10, 256).unwrap();
+        assert_eq!(e.offset(), 512);
+        assert_eq!(pool.alloc(10, 4).unwrap().offset(), 52);
+    }
+
+    #[test]
+    fn freed_neighbours_are_merged() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let pool = GpuPool::new(&mailbox, 4096).unwrap();
+
+        let a = pool.alloc(1024, 4).unwrap();
+        let b = pool.alloc(1024, 4).unwrap();
+        let c = pool.alloc(1024, 4).unwrap();
+        drop(a);
+        drop(c);
+        assert_eq!(pool.stats().free_ranges, 2);
+
+        // Freeing the middle region merges it with both neighbours.
+        drop(b);
+        let stats = pool.stats();
+        assert_eq!(stats.free_ranges, 1);
+        assert_eq!(stats.largest_free, 4096);
+        assert_eq!(pool.alloc(4096, 4).unwrap().offset(), 0);
+    }
+
+    #[test]
+    fn exhausted_pools_fail_allocations() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let pool = GpuPool::new(&mailbox, 4096).unwrap();
+
+        assert!(pool.alloc(4097, 4).is_none());
+        let _a = pool.alloc(2048, 4).unwrap();
+        let b = pool.alloc(2048, 4).unwrap();
+        assert!(pool.alloc(1, 1).is_none());
+
+        drop(b);
+        assert!(pool.alloc(2049, 4).is_none());
+        assert!(pool.alloc(2048, 4).is_some());
+    }
+
+    #[test]
+    fn stats_count_fragmentation() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let pool = GpuPool::new(&mailbox, 4096).unwrap();
+
+        let stats = pool.stats();
+        assert_eq!(
+            stats,
+            PoolStats {
+                size: 4096,
+                used: 0,
+                free: 4096,
+                largest_free: 4096,
+                free_ranges: 1,
+                allocations: 0,
+            }
+        );
+        assert_eq!(stats.fragmentation(), 0.0);
+
+        let a = pool.alloc(1024, 4).unwrap();
+        let _b = pool.alloc(1024, 4).unwrap();
+        drop(a);
+
+        let stats = pool.stats();
+        assert_eq!(stats.used, 1024);
+        assert_eq!(stats.free, 3072);
+        assert_eq!(stats.largest_free, 2048);
+        assert_eq!(stats.free_ranges, 2);
+        assert_eq!(stats.allocations, 1);
+        assert_eq!(stats.fragmentation(), 1.0 - 2048.0 / 3072.0);
+
+        assert!(pool.alloc(3072, 4).is_none());
+        let _d = pool.alloc(1024, 4).unwrap();
+        let _e = pool.alloc(2048, 4).unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.free, 0);
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::mailbox::{Mailbox, MailboxError};
+
+/// Default location of the handle registry, `/run` is cleared on reboot together with the
+/// VideoCore memory.
+pub const GPU_HANDLE_REGISTRY_PATH: &str = "/run/timed-transfer/gpu-handles";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandleEntry {
+    pub pid: u32,
+    pub handle: u32,
+    /// Inode of the PID namespace `pid` belongs to, 0 if it is unknown.
+    pub pid_namespace: u64,
+}
+
+/// Persists the handles of GPU memory allocations together with the owning process, so
+/// allocations leaked by killed processes can be released later.
+///
+/// The file contains one `<pid> <handle> <pid namespace>` line per allocation and is locked
+/// with `flock` while it is accessed.
+#[derive(Clone, Debug)]
+pub struct HandleRegistry {
+    path: PathBuf,
+}
+
+impl HandleRegistry {
+    pub fn new(path: impl Into<PathBuf>) -> HandleRegistry {
+        HandleRegistry { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `handle` as owned by the current process.
+    pub fn register(&self, handle: u32) -> Result<(), io::Error> {
+        let mut file = self.lock()?;
+        file.seek(io::SeekFrom::End(0))?;
+        writeln!(file, "{} {} {}", process::id(), handle, pid_namespace())
+    }
+
+    pub fn unregister(&self, handle: u32) -> Result<(), io::Error> {
+        let mut file = self.lock()?;
+        let mut entries = read_entries(&mut file)?;
+        entries.retain(|entry| entry.handle != handle);
+        write_entries(&mut file, &entries)
+    }
+
+    pub fn entries(&self) -> Result<Vec<HandleEntry>, io::Error> {
+        read_entries(&mut self.lock()?)
+    }
+
+    /// Unlocks and releases all handles whose process no longer exists in `<root>/proc`.
+    ///
+    /// Returns the reclaimed entries. Handles the firmware doesn't know anymore are dropped
+    /// from the registry as well.
+    ///
+    /// Only entries registered from the PID namespace of the current process are checked,
+    /// the PIDs of other namespaces (for example other containers sharing `/run`) can't be
+    /// looked up in `/proc` and their memory may still be in use.
+    pub fn reclaim_stale(
+        &self,
+        mailbox: &Mailbox,
+        root: impl AsRef<Path>,
+    ) -> Result<Vec<HandleEntry>, MailboxError> {
+        let proc = root.as_ref().join("proc");
+        let mut file = self.lock()?;
+        let mut entries = read_entries(&mut file)?;
+        let namespace = pid_namespace();
+        let mut reclaimed = Vec::new();
+        let mut result = Ok(());
+
+        entries.retain(|entry| {
+            if result.is_err()
+                || entry.pid_namespace != namespace
+                || proc.join(entry.pid.to_string()).exists()
+            {
+                return true;
+            }
+
+            // The memory might not be locked anymore if the process was killed while freeing it.
+            let _ = mailbox.unlock_memory(entry.handle);
+
+            match mailbox.release_memory(entry.handle) {
+                Ok(()) => {
+                    reclaimed.push(*entry);
+                    false
+                }
+                Err(MailboxError::Failed(_)) => false,
+                Err(err) => {
+                    result = Err(err);
+                    true
+                }
+            }
+        });
+
+        write_entries(&mut file, &entries)?;
+        result.map(|()| reclaimed)
+    }
+
+    fn lock(&self) -> Result<File, io::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+
+        // The lock is released when the file is closed.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(file)
+    }
+}
+
+impl Default for HandleRegistry {
+    fn default() -> Self {
+        HandleRegistry::new(GPU_HANDLE_REGISTRY_PATH)
+    }
+}
+
+/// Releases the GPU memory leaked by processes that no longer exist.
+pub fn reclaim_stale(mailbox: &Mailbox) -> Result<Vec<HandleEntry>, MailboxError> {
+    match mailbox.registry() {
+        Some(registry) => registry.reclaim_stale(mailbox, "/"),
+        None => HandleRegistry::default().reclaim_stale(mailbox, "/"),
+    }
+}
+
+/// Returns the inode identifying the PID namespace of the current process.
+fn pid_namespace() -> u64 {
+    fs::metadata("/proc/self/ns/pid")
+        .map(|metadata| metadata.ino())
+        .unwrap_or(0)
+}
+
+fn read_entries(file: &mut File) -> Result<Vec<HandleEntry>, io::Error> {
+    let mut contents = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut contents)?;
+
+    // Lines that can't be parsed are left over from a process killed while writing.
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            Some(HandleEntry {
+                pid: fields.next()?.parse().ok()?,
+                handle: fields.next()?.parse().ok()?,
+                pid_namespace: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+fn write_entries(file: &mut File, entries: &[HandleEntry]) -> Result<(), io::Error> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents += &format!("{} {} {}\n", entry.pid, entry.handle, entry.pid_namespace);
+    }
+
+    file.rewind()?;
+    file.set_len(0)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpu::GpuMem, platform::Soc, sim::Board, test_util::TempDir};
+
+    fn registry(dir: &TempDir) -> HandleRegistry {
+        HandleRegistry::new(dir.path().join("gpu-handles"))
+    }
+
+    #[test]
+    fn allocations_are_registered() {
+        let dir = TempDir::new();
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox().with_registry(registry(&dir));
+
+        let memory = GpuMem::builder().alloc(&mailbox, 4096).unwrap();
+        let entries = registry(&dir).entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].pid, process::id());
+        assert_eq!(entries[0].pid_namespace, pid_namespace());
+
+        memory.free().unwrap();
+        assert_eq!(registry(&dir).entries().unwrap(), []);
+    }
+
+    #[test]
+    fn handles_of_dead_processes_are_reclaimed() {
+        let dir = TempDir::new();
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let namespace = pid_namespace();
+
+        let [alive, dead, foreign] = [(); 3].map(|()| {
+            let handle = mailbox.allocate_memory(4096, 4096, 0).unwrap();
+            mailbox.lock_memory(handle).unwrap();
+            handle
+        });
+        dir.write("proc/100/stat", b"");
+        fs::write(
+            registry(&dir).path(),
+            format!(
+                "100 {alive} {namespace}\n200 {dead} {namespace}\n200 {foreign} {}\n",
+                namespace + 1
+            ),
+        )
+        .unwrap();
+
+        let reclaimed = registry(&dir).reclaim_stale(&mailbox, dir.path()).unwrap();
+        assert_eq!(
+            reclaimed,
+            [HandleEntry {
+                pid: 200,
+                handle: dead,
+                pid_namespace: namespace,
+            }]
+        );
+        assert!(mailbox.release_memory(dead).is_err());
+
+        let handles: Vec<_> = registry(&dir)
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.handle)
+            .collect();
+        assert_eq!(handles, [alive, foreign]);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    path::Path,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    mailbox::{bus_alias, BUS_ALIAS_MASK},
+    mem::{DmaMemory, MemMap},
+    platform::{Platform, PAGE_SIZE},
+};
+
+pub const DMA_HEAP_CMA_PATH: &str = "/dev/dma_heap/linux,cma";
+pub const VCSM_CMA_PATH: &str = "/dev/vcsm-cma";
+
+const DMA_HEAP_IOCTL_ALLOC: libc::c_ulong = 0xC0184800;
+const VC_SM_CMA_IOCTL_MEM_IMPORT_DMABUF: libc::c_ulong = 0x80404A5B;
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x40086200;
+
+const DMA_BUF_SYNC_RW: u64 = 3;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+#[repr(C)]
+struct DmaHeapAllocationData {
+    len: u64,
+    fd: u32,
+    fd_flags: u32,
+    heap_flags: u64,
+}
+
+#[repr(C)]
+struct VcsmImportDmabuf {
+    dmabuf_fd: i32,
+    cached: u32,
+    name: [u8; 32],
+    handle: i32,
+    vc_handle: u32,
+    size: u32,
+    pad: u32,
+    dma_addr: u64,
+}
+
+/// Looks up the bus address of a dma-buf.
+pub trait DmaBufImporter {
+    /// Returns the bus address of the contiguous buffer behind `fd`, together with an
+    /// optional handle that has to stay open while the buffer is used by DMA.
+    fn import(&self, fd: BorrowedFd<'_>, size: usize) -> Result<(u64, Option<OwnedFd>), io::Error>;
+}
+
+/// Imports dma-bufs into the VideoCore shared memory driver, which reports their address
+/// without any special privileges.
+pub struct Vcsm {
+    file: File,
+}
+
+impl Vcsm {
+    pub fn open() -> Result<Vcsm, io::Error> {
+        Self::open_path(VCSM_CMA_PATH)
+    }
+
+    pub fn open_path(path: impl AsRef<Path>) -> Result<Vcsm, io::Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Vcsm { file })
+    }
+}
+
+impl DmaBufImporter for Vcsm {
+    fn import(&self, fd: BorrowedFd<'_>, size: usize) -> Result<(u64, Option<OwnedFd>), io::Error> {
+        let mut name = [0; 32];
+        name[..15].copy_from_slice(b"timed-transfer\0");
+        let mut data = VcsmImportDmabuf {
+            dmabuf_fd: fd.as_raw_fd(),
+            cached: 0,
+            name,
+            handle: -1,
+            vc_handle: 0,
+            size: 0,
+            pad: 0,
+            dma_addr: 0,
+        };
+
+        let result = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                VC_SM_CMA_IOCTL_MEM_IMPORT_DMABUF,
+                &mut data,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The import is released when the returned handle is closed.
+        let handle = unsafe { OwnedFd::from_raw_fd(data.handle) };
+
+        // The driver only imports contiguous buffers, make sure it covers the whole mapping.
+        if (data.size as usize) < size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "buffer is not physically contiguous",
+            ));
+        }
+
+        Ok((data.dma_addr, Some(handle)))
+    }
+}
+
+/// Allocates DMA memory from a Linux dma-heap, without the mailbox and `/dev/mem`.
+///
+/// Only heaps that hand out physically contiguous memory, like the CMA heap, can be used.
+/// The bus addresses of the buffers are looked up through `importer`.
+pub struct DmaHeap<I: DmaBufImporter = Vcsm> {
+    file: File,
+    importer: I,
+    alias: u32,
+}
+
+impl DmaHeap {
+    /// Opens the CMA heap and imports its buffers through `/dev/vcsm-cma`.
+    pub fn open(platform: &Platform) -> Result<DmaHeap, io::Error> {
+        Self::open_path(platform, DMA_HEAP_CMA_PATH, Vcsm::open()?)
+    }
+}
+
+impl<I: DmaBufImporter> DmaHeap<I> {
+    pub fn open_path(
+        platform: &Platform,
+        path: impl AsRef<Path>,
+        importer: I,
+    ) -> Result<DmaHeap<I>, io::Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(DmaHeap {
+            file,
+            importer,
+            alias: bus_alias(platform.soc.coherent_mem_flags()),
+        })
+    }
+
+    pub fn alloc(&self, size: usize) -> Result<DmaHeapMem, io::Error> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let mut data = DmaHeapAllocationData {
+            len: size as u64,
+            fd: 0,
+            fd_flags: (libc::O_RDWR | libc::O_CLOEXEC) as u32,
+            heap_flags: 0,
+        };
+
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), DMA_HEAP_IOCTL_ALLOC, &mut data) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(data.fd as i32) };
+        DmaHeapMem::from_dma_buf(fd, size, &self.importer, self.alias)
+    }
+}
+
+/// A mapped dma-buf.
+pub struct DmaHeapMem {
+    fd: OwnedFd,
+    /// Keeps the buffer imported while it is used.
+    _import: Option<OwnedFd>,
+    size: usize,
+    memmap: MemMap,
+    /// Set between the start and the end of a CPU access to the dma-buf.
+    cpu_access: AtomicBool,
+}
+
+impl DmaHeapMem {
+    /// Maps `size` bytes of the buffer behind `fd` and gets its address from `importer`.
+    ///
+    /// `alias` is the bus address alias the DMA engine uses to access RAM. Fails if the
+    /// buffer is not physically contiguous.
+    pub fn from_dma_buf(
+        fd: OwnedFd,
+        size: usize,
+        importer: &impl DmaBufImporter,
+        alias: u32,
+    ) -> Result<DmaHeapMem, io::Error> {
+        let (dma_addr, import) = importer.import(fd.as_fd(), size)?;
+
+        // Addresses above 4 GiB are outside of the range of all aliases.
+        let phys = match u32::try_from(dma_addr) {
+            Ok(dma_addr) => (dma_addr & !BUS_ALIAS_MASK) as usize,
+            Err(_) => usize::MAX,
+        };
+
+        // The legacy DMA engine can only address the first gigabyte.
+        if phys.saturating_add(size) > (!BUS_ALIAS_MASK as usize) + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "buffer is outside of the DMA address range",
+            ));
+        }
+
+        let virt = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+
+        if virt == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let virt = virt as *mut u32;
+
+        let mem = DmaHeapMem {
+            fd,
+            _import: import,
+            size,
+            memmap: MemMap {
+                bus: (phys as u32 | alias) as *mut u32,
+                phys: phys as *mut u32,
+                virt,
+            },
+            cpu_access: AtomicBool::new(false),
+        };
+        // The buffer starts out owned by the CPU, until it is flushed for DMA.
+        mem.begin_cpu_access();
+        Ok(mem)
+    }
+
+    fn sync(&self, flags: u64) -> Result<(), io::Error> {
+        let result = unsafe { libc::ioctl(self.fd.as_raw_fd(), DMA_BUF_IOCTL_SYNC, &flags) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+// The mapping is owned by the buffer.
+unsafe impl Send for DmaHeapMem {}
+unsafe impl Sync for DmaHeapMem {}
+
+impl DmaMemory for DmaHeapMem {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+
+    // Syncing fails for buffers that are no dma-buf, these have nothing to synchronize.
+    fn flush(&self) {
+        if self.cpu_access.swap(false, Ordering::AcqRel) {
+            let _ = self.sync(DMA_BUF_SYNC_END | DMA_BUF_SYNC_RW);
+        }
+    }
+
+    fn begin_cpu_access(&self) {
+        if !self.cpu_access.swap(true, Ordering::AcqRel) {
+            let _ = self.sync(DMA_BUF_SYNC_START | DMA_BUF_SYNC_RW);
+        }
+    }
+}
+
+impl Drop for DmaHeapMem {
+    fn drop(&mut self) {
+        self.flush();
+        unsafe { libc::munmap(self.memmap.virt as *mut libc::c_void, self.size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, os::unix::fs::FileExt};
+
+    use super::*;
+    use crate::{
+        buffer::DmaBuffer,
+        mailbox::{MEM_FLAG_DIRECT, MEM_FLAG_L1_NONALLOCATING},
+        platform::Soc,
+        sim::Board,
+    };
+
+    /// Hands out memfds at consecutive fake bus addresses in the uncached alias.
+    struct FakeHeap {
+        next: Cell<u64>,
+    }
+
+    impl FakeHeap {
+        fn new(start: u64) -> FakeHeap {
+            FakeHeap {
+                next: Cell::new(start),
+            }
+        }
+
+        fn alloc(&self, size: usize) -> Result<DmaHeapMem, io::Error> {
+            let fd = unsafe { libc::memfd_create(c"fake-dma-heap".as_ptr(), libc::MFD_CLOEXEC) };
+            assert!(fd >= 0);
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            File::from(fd.try_clone().unwrap())
+                .set_len(size as u64)
+                .unwrap();
+
+            DmaHeapMem::from_dma_buf(fd, size, self, bus_alias(MEM_FLAG_L1_NONALLOCATING))
+        }
+    }
+
+    impl DmaBufImporter for FakeHeap {
+        fn import(
+            &self,
+            _fd: BorrowedFd<'_>,
+            size: usize,
+        ) -> Result<(u64, Option<OwnedFd>), io::Error> {
+            let addr = self.next.get();
+            self.next.set(addr + size as u64);
+            Ok((addr, None))
+        }
+    }
+
+    #[test]
+    fn bus_address_comes_from_the_importer() {
+        let heap = FakeHeap::new((bus_alias(MEM_FLAG_DIRECT) | 0x1000_0000) as u64);
+        let first = heap.alloc(2 * PAGE_SIZE).unwrap();
+        let second = heap.alloc(PAGE_SIZE).unwrap();
+
+        assert_eq!(first.memmap().phys as usize, 0x1000_0000);
+        assert_eq!(first.memmap().bus as u32, 0x5000_0000);
+        assert_eq!(second.memmap().phys as usize, 0x1000_2000);
+        assert_eq!(second.size(), PAGE_SIZE);
+
+        // The mapping is shared with the dma-buf.
+        unsafe { first.memmap().virt.byte_add(PAGE_SIZE).write(0xc0ffee) };
+        first.flush();
+        let mut word = [0; 4];
+        File::from(first.fd.try_clone().unwrap())
+            .read_exact_at(&mut word, PAGE_SIZE as u64)
+            .unwrap();
+        assert_eq!(u32::from_ne_bytes(word), 0xc0ffee);
+    }
+
+    #[test]
+    fn cpu_access_brackets_the_dma() {
+        let heap = FakeHeap::new(0x1000_0000);
+        let buffer = DmaBuffer::<u32, _>::new(heap.alloc(PAGE_SIZE).unwrap(), 16).unwrap();
+        assert!(buffer.memory().cpu_access.load(Ordering::Acquire));
+
+        buffer.set_in_flight(true);
+        assert!(!buffer.memory().cpu_access.load(Ordering::Acquire));
+        buffer.memory().flush();
+        assert!(!buffer.memory().cpu_access.load(Ordering::Acquire));
+
+        buffer.set_in_flight(false);
+        assert!(buffer.memory().cpu_access.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn heap_allocations_use_the_heap_ioctl() {
+        let board = Board::new(Soc::Bcm2837);
+        let heap = DmaHeap::open_path(&board.platform(), "/dev/null", FakeHeap::new(0)).unwrap();
+
+        // Only dma-heaps implement the allocation.
+        let err = heap.alloc(PAGE_SIZE).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+        assert_eq!(heap.importer.next.get(), 0);
+    }
+
+    #[test]
+    fn buffers_outside_of_the_dma_range_are_rejected() {
+        let heap = FakeHeap::new(0x3fff_f000);
+        let err = heap.alloc(2 * PAGE_SIZE).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let heap = FakeHeap::new(0x1_0000_0000);
+        let err = heap.alloc(PAGE_SIZE).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
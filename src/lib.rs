@@ -1,13 +1,24 @@
+mod buffer;
+mod clock;
 mod field;
 mod gpu;
+mod heap;
 mod mailbox;
 mod mem;
 mod peripheral;
 mod transfer;
 
+#[cfg(test)]
+mod test_util;
+
 pub mod platform;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 
+pub use buffer::*;
+pub use clock::*;
 pub use gpu::*;
+pub use heap::*;
 pub use mailbox::*;
 pub use mem::*;
 pub use peripheral::dma;
@@ -1,7 +1,15 @@
 use std::{
+    error, fmt,
     fs::{File, OpenOptions},
     io,
     os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    slice,
+    sync::Arc,
+};
+
+use crate::{
+    gpu::HandleRegistry,
+    mem::{DevMem, RegisterBackend},
 };
 
 pub const MEM_FLAG_DISCARDABLE: u32 = 1 << 0;
@@ -13,23 +21,255 @@ pub const MEM_FLAG_ZERO: u32 = 1 << 4;
 pub const MEM_FLAG_NO_INIT: u32 = 1 << 5;
 pub const MEM_FLAG_HINT_PERMALOCK: u32 = 1 << 6;
 
-pub struct Mailbox {
+pub const MEM_FLAG_CACHE_MASK: u32 = MEM_FLAG_DIRECT | MEM_FLAG_COHERENT;
+
+pub const BUS_ALIAS_MASK: u32 = 0xC0000000;
+
+/// Returns the bus address alias of memory allocated with `flags`.
+pub fn bus_alias(flags: u32) -> u32 {
+    match flags & MEM_FLAG_CACHE_MASK {
+        MEM_FLAG_DIRECT => 0xC0000000,
+        MEM_FLAG_COHERENT => 0x80000000,
+        MEM_FLAG_L1_NONALLOCATING => 0x40000000,
+        _ => 0x00000000,
+    }
+}
+
+pub const PROPERTY_REQUEST: u32 = 0x00000000;
+pub const PROPERTY_RESPONSE_SUCCESS: u32 = 0x80000000;
+pub const PROPERTY_RESPONSE_ERROR: u32 = 0x80000001;
+pub const PROPERTY_TAG_RESPONSE: u32 = 0x80000000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tag {
+    GetFirmwareRevision = 0x00000001,
+    GetBoardModel = 0x00010001,
+    GetBoardRevision = 0x00010002,
+    GetBoardSerial = 0x00010004,
+    GetArmMemory = 0x00010005,
+    GetVcMemory = 0x00010006,
+    GetClockRate = 0x00030002,
+    GetMaxClockRate = 0x00030004,
+    GetMinClockRate = 0x00030007,
+    GetClockRateMeasured = 0x00030047,
+    SetClockRate = 0x00038002,
+    GetDmaChannels = 0x00060001,
+    AllocateMemory = 0x0003000c,
+    LockMemory = 0x0003000d,
+    UnlockMemory = 0x0003000e,
+    ReleaseMemory = 0x0003000f,
+}
+
+impl Tag {
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// Size of the value buffer in words, large enough for both the request and the response.
+    pub fn buffer_len(self) -> usize {
+        match self {
+            Tag::GetFirmwareRevision | Tag::GetBoardModel | Tag::GetBoardRevision => 1,
+            Tag::GetDmaChannels => 1,
+            Tag::GetBoardSerial | Tag::GetArmMemory | Tag::GetVcMemory => 2,
+            Tag::GetClockRate
+            | Tag::GetMaxClockRate
+            | Tag::GetMinClockRate
+            | Tag::GetClockRateMeasured => 2,
+            Tag::SetClockRate => 3,
+            Tag::AllocateMemory => 3,
+            Tag::LockMemory | Tag::UnlockMemory | Tag::ReleaseMemory => 1,
+        }
+    }
+}
+
+/// Builds a property tag buffer for [`Mailbox::call`].
+pub struct PropertyMessage {
+    buffer: Vec<u32>,
+    tags: Vec<(Tag, usize)>,
+}
+
+impl PropertyMessage {
+    pub fn new() -> PropertyMessage {
+        PropertyMessage {
+            buffer: vec![0, PROPERTY_REQUEST],
+            tags: Vec::new(),
+        }
+    }
+
+    /// Appends a tag with its request values.
+    pub fn tag(mut self, tag: Tag, request: &[u32]) -> PropertyMessage {
+        let len = tag.buffer_len().max(request.len());
+
+        self.tags.push((tag, self.buffer.len()));
+        self.buffer.extend([tag.id(), len as u32 * 4, 0]);
+        self.buffer.extend(request);
+        self.buffer
+            .resize(self.buffer.len() + len - request.len(), 0);
+
+        self
+    }
+
+    /// Returns the response values of the tag at `index`, in the order the tags were added.
+    ///
+    /// Returns `None` if there is no tag at `index`.
+    pub fn response(&self, index: usize) -> Option<&[u32]> {
+        let &(_, offset) = self.tags.get(index)?;
+        let len = self.response_len(index)?;
+        self.buffer.get(offset + 3..offset + 3 + len.div_ceil(4))
+    }
+
+    /// Returns the length in bytes the firmware reported for the response of the tag at
+    /// `index`.
+    pub fn response_len(&self, index: usize) -> Option<usize> {
+        let &(_, offset) = self.tags.get(index)?;
+        Some((self.buffer[offset + 2] & !PROPERTY_TAG_RESPONSE) as usize)
+    }
+
+    fn validate(&self) -> Result<(), MailboxError> {
+        if self.buffer[1] != PROPERTY_RESPONSE_SUCCESS {
+            return Err(MailboxError::Response(self.buffer[1]));
+        }
+
+        for &(tag, offset) in &self.tags {
+            let code = self.buffer[offset + 2];
+            if code & PROPERTY_TAG_RESPONSE == 0 {
+                return Err(MailboxError::TagNotProcessed(tag));
+            }
+
+            let len = (code & !PROPERTY_TAG_RESPONSE) as usize;
+            if len > self.buffer[offset + 1] as usize {
+                return Err(MailboxError::ResponseTruncated { tag, len });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PropertyMessage {
+    fn default() -> Self {
+        PropertyMessage::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum MailboxError {
+    Io(io::Error),
+    /// The firmware did not accept the request, holds the buffer response code.
+    Response(u32),
+    TagNotProcessed(Tag),
+    ResponseTruncated {
+        tag: Tag,
+        len: usize,
+    },
+    /// The firmware processed the tag but reported a failure in its response.
+    Failed(Tag),
+    InsufficientMemory {
+        required: usize,
+        available: usize,
+    },
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailboxError::Io(err) => write!(f, "mailbox request failed: {err}"),
+            MailboxError::Response(code) => {
+                write!(f, "mailbox request rejected with code {code:#010x}")
+            }
+            MailboxError::TagNotProcessed(tag) => write!(f, "tag {tag:?} was not processed"),
+            MailboxError::ResponseTruncated { tag, len } => {
+                write!(f, "response of tag {tag:?} was truncated to {len} bytes")
+            }
+            MailboxError::Failed(tag) => write!(f, "tag {tag:?} failed"),
+            MailboxError::InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "{required} bytes of GPU memory required, only {available} bytes available"
+            ),
+        }
+    }
+}
+
+impl error::Error for MailboxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MailboxError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MailboxError {
+    fn from(err: io::Error) -> Self {
+        MailboxError::Io(err)
+    }
+}
+
+impl From<MailboxError> for io::Error {
+    fn from(err: MailboxError) -> Self {
+        match err {
+            MailboxError::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+/// Clocks managed by the firmware.
+///
+/// PLLD, which drives the SMI clock by default, is not exposed by the firmware, its rate is
+/// read from the PLL registers, see [`smi::Controller::clock_rate`](crate::smi::Controller::clock_rate).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockId {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Hevc = 11,
+    Emmc2 = 12,
+    M2mc = 13,
+    PixelBvb = 14,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub base: u32,
+    pub size: u32,
+}
+
+pub trait MailboxBackend: Send + Sync {
+    /// Sends a property tag buffer, the response is written back into `buffer`.
+    fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error>;
+}
+
+/// Talks to the VideoCore firmware through `/dev/vcio`.
+pub struct Vcio {
     file: File,
 }
 
-impl Mailbox {
-    pub fn open() -> Result<Mailbox, io::Error> {
+impl Vcio {
+    pub fn open() -> Result<Vcio, io::Error> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .custom_flags(libc::O_SYNC)
             .open("/dev/vcio")?;
 
-        Ok(Mailbox { file })
+        Ok(Vcio { file })
     }
+}
 
-    pub unsafe fn send(&self, ptr: *const libc::c_void) -> Result<i32, io::Error> {
-        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), 0xC0046400, ptr) };
+impl MailboxBackend for Vcio {
+    fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error> {
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), 0xC0046400, buffer.as_mut_ptr()) };
 
         if result < 0 {
             return Err(io::Error::last_os_error());
@@ -38,3 +278,340 @@ impl Mailbox {
         Ok(result)
     }
 }
+
+pub struct Mailbox {
+    backend: Box<dyn MailboxBackend>,
+    memory: Arc<dyn RegisterBackend>,
+    registry: Option<HandleRegistry>,
+}
+
+impl Mailbox {
+    /// Opens `/dev/vcio`.
+    ///
+    /// GPU memory handles are only recorded if a registry is attached with
+    /// [`Mailbox::with_registry`].
+    pub fn open() -> Result<Mailbox, io::Error> {
+        Ok(Mailbox::with_backend(Vcio::open()?, Arc::new(DevMem)))
+    }
+
+    /// Creates a mailbox whose allocations are mapped through `memory`.
+    pub fn with_backend(
+        backend: impl MailboxBackend + 'static,
+        memory: Arc<dyn RegisterBackend>,
+    ) -> Mailbox {
+        Mailbox {
+            backend: Box::new(backend),
+            memory,
+            registry: None,
+        }
+    }
+
+    /// Records the handles of GPU memory allocated through this mailbox in `registry`.
+    pub fn with_registry(mut self, registry: HandleRegistry) -> Mailbox {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn memory(&self) -> &Arc<dyn RegisterBackend> {
+        &self.memory
+    }
+
+    pub fn registry(&self) -> Option<&HandleRegistry> {
+        self.registry.as_ref()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid property tag buffer.
+    pub unsafe fn send(&self, ptr: *const libc::c_void) -> Result<i32, io::Error> {
+        let len = (ptr as *const u32).read() as usize / 4;
+        let buffer = slice::from_raw_parts_mut(ptr as *mut u32, len);
+
+        self.backend.send(buffer)
+    }
+
+    /// Sends the message and validates the response codes of the buffer and every tag.
+    pub fn call(&self, message: &mut PropertyMessage) -> Result<(), MailboxError> {
+        message.buffer.push(0);
+        message.buffer[0] = message.buffer.len() as u32 * 4;
+        message.buffer[1] = PROPERTY_REQUEST;
+
+        let result = self.backend.send(&mut message.buffer);
+        message.buffer.pop();
+        result?;
+
+        message.validate()
+    }
+
+    fn call_single(&self, tag: Tag, request: &[u32]) -> Result<Vec<u32>, MailboxError> {
+        let mut message = PropertyMessage::new().tag(tag, request);
+        self.call(&mut message)?;
+        Ok(message.response(0).unwrap_or_default().to_vec())
+    }
+
+    fn call_words<const N: usize>(&self, tag: Tag) -> Result<[u32; N], MailboxError> {
+        let mut message = PropertyMessage::new().tag(tag, &[]);
+        self.call(&mut message)?;
+
+        let len = message.response_len(0).unwrap_or_default();
+        if len < N * 4 {
+            return Err(MailboxError::ResponseTruncated { tag, len });
+        }
+        Ok(message.response(0).unwrap_or_default()[..N]
+            .try_into()
+            .unwrap())
+    }
+
+    pub fn firmware_revision(&self) -> Result<u32, MailboxError> {
+        let [revision] = self.call_words(Tag::GetFirmwareRevision)?;
+        Ok(revision)
+    }
+
+    pub fn board_model(&self) -> Result<u32, MailboxError> {
+        let [model] = self.call_words(Tag::GetBoardModel)?;
+        Ok(model)
+    }
+
+    /// Returns the board revision code, see [`Platform::from_revision`](crate::platform::Platform::from_revision).
+    pub fn board_revision(&self) -> Result<u32, MailboxError> {
+        let [revision] = self.call_words(Tag::GetBoardRevision)?;
+        Ok(revision)
+    }
+
+    pub fn board_serial(&self) -> Result<u64, MailboxError> {
+        let [low, high] = self.call_words(Tag::GetBoardSerial)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+
+    pub fn arm_memory(&self) -> Result<MemoryRange, MailboxError> {
+        let [base, size] = self.call_words(Tag::GetArmMemory)?;
+        Ok(MemoryRange { base, size })
+    }
+
+    pub fn vc_memory(&self) -> Result<MemoryRange, MailboxError> {
+        let [base, size] = self.call_words(Tag::GetVcMemory)?;
+        Ok(MemoryRange { base, size })
+    }
+
+    /// Returns the mask of DMA channels the firmware leaves to the ARM, bit `n` is channel `n`.
+    pub fn dma_channels(&self) -> Result<u32, MailboxError> {
+        let [mask] = self.call_words(Tag::GetDmaChannels)?;
+        Ok(mask)
+    }
+
+    /// Fails with [`MailboxError::InsufficientMemory`] when the VideoCore memory split is
+    /// smaller than `required` bytes.
+    pub fn check_vc_memory(&self, required: usize) -> Result<(), MailboxError> {
+        let available = self.vc_memory()?.size as usize;
+        if available < required {
+            return Err(MailboxError::InsufficientMemory {
+                required,
+                available,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn call_clock(&self, tag: Tag, request: &[u32]) -> Result<u32, MailboxError> {
+        match self.call_single(tag, request)?[..] {
+            [_, rate, ..] if rate != 0 => Ok(rate),
+            _ => Err(MailboxError::Failed(tag)),
+        }
+    }
+
+    /// Returns the rate the clock is set to, in Hz.
+    pub fn clock_rate(&self, clock: ClockId) -> Result<u32, MailboxError> {
+        self.call_clock(Tag::GetClockRate, &[clock as u32])
+    }
+
+    /// Returns the rate the clock actually runs at, in Hz.
+    pub fn measured_clock_rate(&self, clock: ClockId) -> Result<u32, MailboxError> {
+        self.call_clock(Tag::GetClockRateMeasured, &[clock as u32])
+    }
+
+    pub fn max_clock_rate(&self, clock: ClockId) -> Result<u32, MailboxError> {
+        self.call_clock(Tag::GetMaxClockRate, &[clock as u32])
+    }
+
+    pub fn min_clock_rate(&self, clock: ClockId) -> Result<u32, MailboxError> {
+        self.call_clock(Tag::GetMinClockRate, &[clock as u32])
+    }
+
+    /// Sets the clock rate, returns the rate the firmware chose.
+    pub fn set_clock_rate(
+        &self,
+        clock: ClockId,
+        rate: u32,
+        skip_turbo: bool,
+    ) -> Result<u32, MailboxError> {
+        self.call_clock(Tag::SetClockRate, &[clock as u32, rate, skip_turbo as u32])
+    }
+
+    /// Allocates GPU memory, returns its handle.
+    pub fn allocate_memory(&self, size: u32, align: u32, flags: u32) -> Result<u32, MailboxError> {
+        match self.call_single(Tag::AllocateMemory, &[size, align, flags])?[..] {
+            [handle, ..] if handle != 0 => Ok(handle),
+            _ => Err(MailboxError::Failed(Tag::AllocateMemory)),
+        }
+    }
+
+    /// Locks GPU memory in place, returns its bus address.
+    pub fn lock_memory(&self, handle: u32) -> Result<u32, MailboxError> {
+        match self.call_single(Tag::LockMemory, &[handle])?[..] {
+            [bus, ..] if bus != 0 => Ok(bus),
+            _ => Err(MailboxError::Failed(Tag::LockMemory)),
+        }
+    }
+
+    pub fn unlock_memory(&self, handle: u32) -> Result<(), MailboxError> {
+        match self.call_single(Tag::UnlockMemory, &[handle])?[..] {
+            [0, ..] => Ok(()),
+            _ => Err(MailboxError::Failed(Tag::UnlockMemory)),
+        }
+    }
+
+    pub fn release_memory(&self, handle: u32) -> Result<(), MailboxError> {
+        match self.call_single(Tag::ReleaseMemory, &[handle])?[..] {
+            [0, ..] => Ok(()),
+            _ => Err(MailboxError::Failed(Tag::ReleaseMemory)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Soc, sim::*};
+
+    // Answers every tag with a fixed response code and value.
+    struct FixedResponse {
+        code: u32,
+        value: u32,
+    }
+
+    impl MailboxBackend for FixedResponse {
+        fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error> {
+            buffer[1] = PROPERTY_RESPONSE_SUCCESS;
+            buffer[2 + 2] = self.code;
+            buffer[2 + 3] = self.value;
+            Ok(0)
+        }
+    }
+
+    fn mailbox(code: u32, value: u32) -> Mailbox {
+        Mailbox::with_backend(FixedResponse { code, value }, Arc::new(DevMem))
+    }
+
+    #[test]
+    fn responses_are_looked_up_by_index() {
+        let mut message = PropertyMessage::new()
+            .tag(Tag::GetBoardRevision, &[])
+            .tag(Tag::GetClockRate, &[4]);
+        assert_eq!(message.buffer[2..5], [Tag::GetBoardRevision.id(), 4, 0]);
+        assert_eq!(message.buffer[6..10], [Tag::GetClockRate.id(), 8, 0, 4]);
+
+        message.buffer[4] = PROPERTY_TAG_RESPONSE | 4;
+        message.buffer[5] = 0xa02082;
+        assert_eq!(message.response(0), Some(&[0xa02082][..]));
+        assert_eq!(message.response_len(0), Some(4));
+        assert_eq!(message.response(1), Some(&[][..]));
+        assert_eq!(message.response(2), None);
+        assert_eq!(message.response_len(2), None);
+    }
+
+    #[test]
+    fn unprocessed_tags_are_rejected() {
+        let err = mailbox(0, 0).board_revision().unwrap_err();
+        assert!(matches!(
+            err,
+            MailboxError::TagNotProcessed(Tag::GetBoardRevision)
+        ));
+    }
+
+    #[test]
+    fn truncated_responses_report_their_length() {
+        // Longer than the value buffer of the tag.
+        let err = mailbox(PROPERTY_TAG_RESPONSE | 12, 0)
+            .board_revision()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MailboxError::ResponseTruncated {
+                tag: Tag::GetBoardRevision,
+                len: 12
+            }
+        ));
+
+        // Shorter than the values the tag returns.
+        let err = mailbox(PROPERTY_TAG_RESPONSE | 4, 0)
+            .board_serial()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MailboxError::ResponseTruncated {
+                tag: Tag::GetBoardSerial,
+                len: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn board_queries() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+
+        assert_eq!(mailbox.firmware_revision().unwrap(), SIM_FIRMWARE_REVISION);
+        assert_eq!(mailbox.board_revision().unwrap(), 0xa02082);
+        assert_eq!(mailbox.board_serial().unwrap(), SIM_BOARD_SERIAL);
+        assert_eq!(
+            mailbox.arm_memory().unwrap(),
+            MemoryRange {
+                base: 0,
+                size: SIM_GPU_MEM_BASE
+            }
+        );
+        assert_eq!(
+            mailbox.vc_memory().unwrap(),
+            MemoryRange {
+                base: SIM_GPU_MEM_BASE,
+                size: SIM_GPU_MEM_SIZE
+            }
+        );
+    }
+
+    #[test]
+    fn vc_memory_is_checked_against_the_split() {
+        let mailbox = Board::new(Soc::Bcm2711).mailbox();
+
+        assert!(mailbox.check_vc_memory(SIM_GPU_MEM_SIZE as usize).is_ok());
+        let err = mailbox
+            .check_vc_memory(SIM_GPU_MEM_SIZE as usize + 1)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MailboxError::InsufficientMemory { required, available }
+                if required == SIM_GPU_MEM_SIZE as usize + 1
+                    && available == SIM_GPU_MEM_SIZE as usize
+        ));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn rejected_requests_return_the_response_code() {
+        struct Rejecting;
+
+        impl MailboxBackend for Rejecting {
+            fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error> {
+                buffer[1] = PROPERTY_RESPONSE_ERROR;
+                Ok(0)
+            }
+        }
+
+        let mailbox = Mailbox::with_backend(Rejecting, Arc::new(DevMem));
+        assert!(matches!(
+            mailbox.firmware_revision(),
+            Err(MailboxError::Response(PROPERTY_RESPONSE_ERROR))
+        ));
+    }
+}
@@ -1,10 +1,14 @@
 use std::{
+    alloc::{self, Layout},
     fs::OpenOptions,
     io,
     os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
     ptr,
+    sync::{Arc, Mutex},
 };
 
+use crate::platform::PAGE_SIZE;
+
 #[derive(Clone, Copy, Debug)]
 pub struct MemMap {
     pub bus: *mut u32,
@@ -12,6 +16,25 @@ pub struct MemMap {
     pub virt: *mut u32,
 }
 
+/// Memory that is physically contiguous and can be accessed by the DMA engine.
+pub trait DmaMemory {
+    fn size(&self) -> usize;
+
+    fn memmap(&self) -> &MemMap;
+
+    /// Makes writes of the CPU visible to DMA, needed if the memory is mapped cached.
+    ///
+    /// The CPU must not access the memory again until [`DmaMemory::begin_cpu_access`].
+    fn flush(&self) {}
+
+    /// Gives the memory back to the CPU once DMA is done with it, making writes of the DMA
+    /// visible to the CPU.
+    fn begin_cpu_access(&self) {}
+}
+
+/// # Safety
+///
+/// `phys` must be the physical address of memory that is safe to access from userspace.
 pub unsafe fn map_phys_to_virt(phys: *const u32, size: usize) -> Result<*mut u32, io::Error> {
     let file = OpenOptions::new()
         .read(true)
@@ -37,6 +60,264 @@ pub unsafe fn map_phys_to_virt(phys: *const u32, size: usize) -> Result<*mut u32
     Ok(result as *mut u32)
 }
 
+/// # Safety
+///
+/// `ptr` and `size` must describe a mapping returned by [`map_phys_to_virt`] that is no longer used.
 pub unsafe fn unmap_phys_to_virt(ptr: *mut u32, size: usize) {
     libc::munmap(ptr as *mut libc::c_void, size);
 }
+
+pub trait RegisterBackend: Send + Sync {
+    /// # Safety
+    ///
+    /// Same as [`map_phys_to_virt`].
+    unsafe fn map(&self, phys: *const u32, size: usize) -> Result<*mut u32, io::Error>;
+
+    /// # Safety
+    ///
+    /// `virt` and `size` must describe a mapping returned by [`RegisterBackend::map`] that is no longer used.
+    unsafe fn unmap(&self, virt: *mut u32, size: usize);
+
+    /// # Safety
+    ///
+    /// `virt` must point into a mapping returned by [`RegisterBackend::map`].
+    unsafe fn read(&self, virt: *const u32) -> u32 {
+        virt.read_volatile()
+    }
+
+    /// # Safety
+    ///
+    /// `virt` must point into a mapping returned by [`RegisterBackend::map`].
+    unsafe fn write(&self, virt: *mut u32, value: u32) {
+        virt.write_volatile(value)
+    }
+}
+
+/// Maps physical memory through `/dev/mem`.
+pub struct DevMem;
+
+impl RegisterBackend for DevMem {
+    unsafe fn map(&self, phys: *const u32, size: usize) -> Result<*mut u32, io::Error> {
+        map_phys_to_virt(phys, size)
+    }
+
+    unsafe fn unmap(&self, virt: *mut u32, size: usize) {
+        unmap_phys_to_virt(virt, size)
+    }
+}
+
+/// Backs physical memory with zeroed heap memory.
+///
+/// Mapping the same physical address again returns the same memory, so registers written
+/// through one mapping can be inspected through another or with [`SimulatedMem::virt`].
+#[derive(Default)]
+pub struct SimulatedMem {
+    regions: Mutex<Vec<SimulatedRegion>>,
+}
+
+struct SimulatedRegion {
+    phys: usize,
+    len: usize,
+    virt: *mut u32,
+}
+
+unsafe impl Send for SimulatedRegion {}
+
+impl SimulatedMem {
+    pub fn new() -> SimulatedMem {
+        SimulatedMem::default()
+    }
+
+    /// Returns the heap address backing `phys`, if it has been mapped.
+    pub fn virt(&self, phys: usize) -> Option<*mut u32> {
+        let regions = self.regions.lock().unwrap();
+        let region = regions
+            .iter()
+            .find(|region| (region.phys..region.phys + region.len * 4).contains(&phys))?;
+        Some(region.virt.wrapping_byte_add(phys - region.phys))
+    }
+
+    /// Returns the physical address backed by the heap address `virt`.
+    pub fn phys(&self, virt: *const u32) -> Option<usize> {
+        let regions = self.regions.lock().unwrap();
+        let region = regions.iter().find(|region| {
+            (region.virt as usize..region.virt as usize + region.len * 4).contains(&(virt as usize))
+        })?;
+        Some(region.phys + (virt as usize - region.virt as usize))
+    }
+
+    pub fn read(&self, phys: usize) -> Option<u32> {
+        self.virt(phys).map(|virt| unsafe { virt.read_volatile() })
+    }
+
+    pub fn write(&self, phys: usize, value: u32) -> Option<()> {
+        self.virt(phys)
+            .map(|virt| unsafe { virt.write_volatile(value) })
+    }
+}
+
+impl RegisterBackend for SimulatedMem {
+    unsafe fn map(&self, phys: *const u32, size: usize) -> Result<*mut u32, io::Error> {
+        let phys = phys as usize;
+        let len = size.div_ceil(4);
+        let mut regions = self.regions.lock().unwrap();
+
+        for region in regions.iter() {
+            let start = region.phys;
+            let end = region.phys + region.len * 4;
+            if phys >= start && phys + len * 4 <= end {
+                return Ok(region.virt.byte_add(phys - start));
+            }
+            if phys < end && start < phys + len * 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "mapping partially overlaps an existing simulated region",
+                ));
+            }
+        }
+
+        // Page aligned like real mappings, so aligned structures can be placed in them.
+        let virt = alloc::alloc_zeroed(region_layout(len)) as *mut u32;
+        if virt.is_null() {
+            alloc::handle_alloc_error(region_layout(len));
+        }
+        regions.push(SimulatedRegion { phys, len, virt });
+
+        Ok(virt)
+    }
+
+    unsafe fn unmap(&self, _virt: *mut u32, _size: usize) {
+        // Regions stay alive until the backend is dropped, so their contents can be inspected.
+    }
+}
+
+impl Drop for SimulatedMem {
+    fn drop(&mut self) {
+        for region in self.regions.get_mut().unwrap().drain(..) {
+            unsafe { alloc::dealloc(region.virt as *mut u8, region_layout(region.len)) };
+        }
+    }
+}
+
+fn region_layout(len: usize) -> Layout {
+    Layout::from_size_align(len.max(1) * 4, PAGE_SIZE).unwrap()
+}
+
+/// Memory mapped through a [`RegisterBackend`], unmapped on drop.
+pub struct Mapping {
+    backend: Arc<dyn RegisterBackend>,
+    memmap: MemMap,
+    size: usize,
+}
+
+impl Mapping {
+    /// # Safety
+    ///
+    /// Same as [`RegisterBackend::map`].
+    pub unsafe fn new(
+        backend: Arc<dyn RegisterBackend>,
+        bus: *mut u32,
+        phys: *mut u32,
+        size: usize,
+    ) -> Result<Mapping, io::Error> {
+        let virt = backend.map(phys, size)?;
+
+        Ok(Mapping {
+            backend,
+            memmap: MemMap { bus, phys, virt },
+            size,
+        })
+    }
+
+    pub fn memmap(&self) -> &MemMap {
+        &self.memmap
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn backend(&self) -> &Arc<dyn RegisterBackend> {
+        &self.backend
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.size);
+        unsafe { self.backend.read(self.memmap.virt.byte_add(offset)) }
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        assert!(offset + 4 <= self.size);
+        unsafe { self.backend.write(self.memmap.virt.byte_add(offset), value) }
+    }
+}
+
+// The mapping only hands out addresses, accessing them is already unsafe.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { self.backend.unmap(self.memmap.virt, self.size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_of_the_same_address_share_memory() {
+        let memory = Arc::new(SimulatedMem::new());
+        let first =
+            unsafe { Mapping::new(memory.clone(), ptr::null_mut(), 0x1000 as _, 0x100) }.unwrap();
+        let second =
+            unsafe { Mapping::new(memory.clone(), ptr::null_mut(), 0x1080 as _, 0x10) }.unwrap();
+
+        first.write(0x84, 0x1234);
+        assert_eq!(second.read(0x4), 0x1234);
+        assert_eq!(memory.read(0x1084), Some(0x1234));
+        assert_eq!(memory.write(0x1088, 5), Some(()));
+        assert_eq!(second.read(0x8), 5);
+
+        let virt = memory.virt(0x1084).unwrap();
+        assert_eq!(virt, first.memmap().virt.wrapping_byte_add(0x84));
+        assert_eq!(memory.phys(virt), Some(0x1084));
+
+        // Regions are kept after unmapping, so their contents can be inspected.
+        drop(first);
+        drop(second);
+        assert_eq!(memory.read(0x1084), Some(0x1234));
+    }
+
+    #[test]
+    fn unmapped_addresses_are_not_accessible() {
+        let memory = SimulatedMem::new();
+        let virt = unsafe { memory.map(0x2000 as _, 8) }.unwrap();
+
+        assert_eq!(memory.read(0x2000), Some(0));
+        assert_eq!(memory.read(0x2008), None);
+        assert_eq!(memory.read(0x1ffc), None);
+        assert_eq!(memory.write(0x3000, 1), None);
+        assert_eq!(memory.phys(virt.wrapping_byte_add(8)), None);
+        assert_eq!(memory.virt(0x3000), None);
+    }
+
+    #[test]
+    fn partial_overlaps_are_rejected() {
+        let memory = SimulatedMem::new();
+        unsafe { memory.map(0x1000 as _, 0x100) }.unwrap();
+
+        let err = unsafe { memory.map(0x10c0 as _, 0x100) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(unsafe { memory.map(0x1100 as _, 0x100) }.is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn mapping_accesses_are_bounds_checked() {
+        let memory = Arc::new(SimulatedMem::new());
+        let mapping = unsafe { Mapping::new(memory, ptr::null_mut(), 0x1000 as _, 0x10) }.unwrap();
+        mapping.read(0x10);
+    }
+}
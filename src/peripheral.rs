@@ -1,3 +1,7 @@
+mod claim;
+
 pub mod dma;
 pub mod gpio;
 pub mod smi;
+
+pub use claim::{Resource, TakeError};
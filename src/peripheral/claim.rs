@@ -0,0 +1,172 @@
+use std::{
+    error, fmt, io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+/// Kind of the indexed resources of a peripheral.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    DmaChannel,
+    SmiDevice,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::DmaChannel => write!(f, "DMA channel"),
+            Resource::SmiDevice => write!(f, "SMI device"),
+        }
+    }
+}
+
+/// Tracks which of the `count` resources of a peripheral are taken.
+pub(crate) struct Claims {
+    resource: Resource,
+    count: u32,
+    mask: Arc<AtomicU32>,
+}
+
+impl Claims {
+    pub(crate) fn new(resource: Resource, count: u32) -> Claims {
+        Claims {
+            resource,
+            count,
+            mask: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub(crate) fn take(&self, index: u32) -> Result<Claim, TakeError> {
+        if index >= self.count {
+            return Err(TakeError::InvalidIndex(self.resource, index));
+        }
+
+        if self.mask.fetch_or(1 << index, Ordering::AcqRel) & (1 << index) != 0 {
+            return Err(TakeError::Taken(self.resource, index));
+        }
+
+        Ok(Claim {
+            mask: self.mask.clone(),
+            index,
+        })
+    }
+}
+
+/// A taken resource, which is released on drop.
+pub(crate) struct Claim {
+    mask: Arc<AtomicU32>,
+    index: u32,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.mask.fetch_and(!(1 << self.index), Ordering::AcqRel);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TakeError {
+    InvalidIndex(Resource, u32),
+    Taken(Resource, u32),
+}
+
+impl fmt::Display for TakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TakeError::InvalidIndex(resource, index) => write!(f, "there is no {resource} {index}"),
+            TakeError::Taken(resource, index) => write!(f, "{resource} {index} is already taken"),
+        }
+    }
+}
+
+impl error::Error for TakeError {}
+
+impl From<TakeError> for io::Error {
+    fn from(err: TakeError) -> Self {
+        match err {
+            TakeError::InvalidIndex(..) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            TakeError::Taken(..) => io::Error::new(io::ErrorKind::ResourceBusy, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dma, platform::Soc, sim::Board, smi};
+
+    #[test]
+    fn typed_and_indexed_access_share_the_claims() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+
+        let channel = dma.channels.channel5().unwrap();
+        assert_eq!(
+            dma.channels.take(5).err(),
+            Some(TakeError::Taken(Resource::DmaChannel, 5))
+        );
+        drop(channel);
+        let channel = dma.channels.take(5).unwrap();
+        assert!(dma.channels.channel5().is_err());
+        drop(channel);
+        assert!(dma.channels.channel5().is_ok());
+
+        let device = smi.devices.take(0).unwrap();
+        assert_eq!(
+            smi.devices.device0().err(),
+            Some(TakeError::Taken(Resource::SmiDevice, 0))
+        );
+        drop(device);
+        assert!(smi.devices.device0().is_ok());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn all_typed_resources_can_be_taken_as_fields() {
+        fn index<C: dma::IndexedChannel>(_: &C) -> u32 {
+            C::INDEX
+        }
+
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+
+        let channel = dma.channels.channel5().unwrap();
+        assert!(dma.channels.take_all().is_err());
+        // Failing releases the channels taken so far.
+        assert!(dma.channels.channel0().is_ok());
+        drop(channel);
+
+        let channels = dma.channels.take_all().unwrap();
+        assert_eq!(index(&channels.channel14), 14);
+        assert!(dma.channels.take(3).is_err());
+        let dma::AllChannels { channel3, .. } = channels;
+        assert!(dma.channels.take(3).is_err());
+        drop(channel3);
+        assert!(dma.channels.take(3).is_ok());
+
+        let devices = smi.devices.take_all().unwrap();
+        assert_eq!(<smi::Device2 as smi::IndexedDevice>::INDEX, 2);
+        assert!(smi.devices.take(2).is_err());
+        drop(devices);
+        assert!(smi.devices.take(2).is_ok());
+    }
+
+    #[test]
+    fn indices_are_checked() {
+        let claims = Claims::new(Resource::SmiDevice, 4);
+        assert_eq!(
+            claims.take(4).err(),
+            Some(TakeError::InvalidIndex(Resource::SmiDevice, 4))
+        );
+        assert_eq!(
+            TakeError::InvalidIndex(Resource::DmaChannel, 15).to_string(),
+            "there is no DMA channel 15"
+        );
+    }
+}
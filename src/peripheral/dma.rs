@@ -1,17 +1,33 @@
-use std::io;
+use std::{error, fmt, io, sync::Arc};
 
+use super::claim::{Claim, Claims};
 use crate::{
-    field::{bit, bits, write_bit_field, Field},
-    mem::{map_phys_to_virt, unmap_phys_to_virt, MemMap},
-    platform::{Platform, PAGE_SIZE},
+    field::{bit, bits, read_bit_field, write_bit_field, Field},
+    mem::{DevMem, Mapping, RegisterBackend},
+    platform::{Platform, Soc, PAGE_SIZE},
 };
 
+mod control_block;
+mod copy;
+mod dma4;
+mod reservation;
+
+pub use super::{Resource, TakeError};
+pub use control_block::*;
+pub use copy::*;
+pub use dma4::*;
+pub use reservation::*;
+
 pub const DMA_OFFSET: usize = 0x00007000;
 pub const DMA_ENABLE_OFFSET: usize = 0xFF0;
 pub const DMA_CHANNEL_OFFSET: usize = 0x100;
+pub const DMA_CHANNEL_COUNT: u32 = 15;
 
 pub const DMA_CONTROL_BLOCK_SIZE: usize = 32;
 
+pub const DMA_MAX_LEN: u32 = (1 << 30) - 1;
+pub const DMA_LITE_MAX_LEN: u32 = 0xFFFF;
+
 pub const DMA_CB_TI: usize = 0x00;
 pub const DMA_CB_SOURCE_AD: usize = 0x04;
 pub const DMA_CB_DEST_AD: usize = 0x08;
@@ -23,7 +39,14 @@ pub const DMA_CS: usize = 0x00;
 pub const DMA_CONBLK_AD: usize = 0x04;
 pub const DMA_DEBUG: usize = 0x20;
 
+pub const DMA_DEBUG_LITE: Field<u32> = bit(28);
+pub const DMA_DEBUG_VERSION: Field<u32> = bits(27, 25);
+pub const DMA_DEBUG_DMA_STATE: Field<u32> = bits(24, 16);
 pub const DMA_DEBUG_DMA_ID: Field<u32> = bits(15, 8);
+pub const DMA_DEBUG_OUTSTANDING_WRITES: Field<u32> = bits(7, 4);
+pub const DMA_DEBUG_READ_ERROR: Field<u32> = bit(2);
+pub const DMA_DEBUG_FIFO_ERROR: Field<u32> = bit(1);
+pub const DMA_DEBUG_READ_LAST_NOT_SET_ERROR: Field<u32> = bit(0);
 
 pub const DMA_CS_RESET: Field<u32> = bit(31);
 pub const DMA_CS_ABORT: Field<u32> = bit(30);
@@ -88,66 +111,109 @@ pub const DMA_PERMAP_SLIMBUS_DC8: u8 = 30;
 pub const DMA_PERMAP_SLIMBUS_DC9: u8 = 31;
 
 pub struct Peripheral {
-    regs: MemMap,
     pub channels: Channels,
 }
 
 impl Peripheral {
     pub fn open(base: &Platform) -> Result<Peripheral, io::Error> {
-        let bus = base.bus.wrapping_byte_add(DMA_OFFSET);
-        let phys = base.phys.wrapping_byte_add(DMA_OFFSET);
-        let virt = unsafe { map_phys_to_virt(phys, PAGE_SIZE) }?;
-        let regs = MemMap { bus, phys, virt };
+        Self::open_with(base, Arc::new(DevMem))
+    }
+
+    pub fn open_with(
+        base: &Platform,
+        backend: Arc<dyn RegisterBackend>,
+    ) -> Result<Peripheral, io::Error> {
+        let regs = Arc::new(unsafe {
+            Mapping::new(
+                backend,
+                base.bus.wrapping_byte_add(DMA_OFFSET),
+                base.phys.wrapping_byte_add(DMA_OFFSET),
+                PAGE_SIZE,
+            )
+        }?);
 
         Ok(Peripheral {
-            regs,
             channels: Channels {
-                channel0: Channel0::new(&regs),
-                channel1: Channel1::new(&regs),
-                channel2: Channel2::new(&regs),
-                channel3: Channel3::new(&regs),
-                channel4: Channel4::new(&regs),
-                channel5: Channel5::new(&regs),
-                channel6: Channel6::new(&regs),
-                channel7: Channel7::new(&regs),
-                channel8: Channel8::new(&regs),
-                channel9: Channel9::new(&regs),
-                channel10: Channel10::new(&regs),
-                channel11: Channel11::new(&regs),
-                channel12: Channel12::new(&regs),
-                channel13: Channel13::new(&regs),
-                channel14: Channel14::new(&regs),
+                regs,
+                soc: base.soc,
+                claims: Claims::new(Resource::DmaChannel, DMA_CHANNEL_COUNT),
             },
         })
     }
 }
 
-impl Drop for Peripheral {
-    fn drop(&mut self) {
-        unsafe { unmap_phys_to_virt(self.regs.virt, PAGE_SIZE) };
+/// All channels of the DMA controller, each of which can be taken once at a time.
+///
+/// The typed channels used to be public fields of this struct, they are now taken with the
+/// `channelN` methods. [`Channels::take_all`] still returns them as fields.
+pub struct Channels {
+    regs: Arc<Mapping>,
+    soc: Soc,
+    claims: Claims,
+}
+
+impl Channels {
+    /// Takes the channel at `index`, which can't be taken again until the returned channel
+    /// is dropped.
+    pub fn take(&self, index: u32) -> Result<AnyChannel, TakeError> {
+        Ok(AnyChannel {
+            regs: self.regs.clone(),
+            index,
+            kind: ChannelKind::of(self.soc, index),
+            _claim: self.claims.take(index)?,
+        })
     }
 }
 
-pub struct Channels {
-    pub channel0: Channel0,
-    pub channel1: Channel1,
-    pub channel2: Channel2,
-    pub channel3: Channel3,
-    pub channel4: Channel4,
-    pub channel5: Channel5,
-    pub channel6: Channel6,
-    pub channel7: Channel7,
-    pub channel8: Channel8,
-    pub channel9: Channel9,
-    pub channel10: Channel10,
-    pub channel11: Channel11,
-    pub channel12: Channel12,
-    pub channel13: Channel13,
-    pub channel14: Channel14,
+macro_rules! typed_channels {
+    ($($method:ident: $name:ident),* $(,)?) => {
+        impl Channels {
+            $(
+                /// Takes the channel like [`Channels::take`], with its index in the type.
+                pub fn $method(&self) -> Result<$name, TakeError> {
+                    Ok($name {
+                        channel: self.take($name::INDEX)?,
+                    })
+                }
+            )*
+
+            /// Takes every channel, fails if one of them is already taken.
+            #[deprecated(note = "take the channels that are used with `take` or `channelN`")]
+            pub fn take_all(&self) -> Result<AllChannels, TakeError> {
+                Ok(AllChannels {
+                    $($method: self.$method()?,)*
+                })
+            }
+        }
+
+        /// Every typed channel, as returned by [`Channels::take_all`].
+        pub struct AllChannels {
+            $(pub $method: $name,)*
+        }
+    };
+}
+
+typed_channels! {
+    channel0: Channel0,
+    channel1: Channel1,
+    channel2: Channel2,
+    channel3: Channel3,
+    channel4: Channel4,
+    channel5: Channel5,
+    channel6: Channel6,
+    channel7: Channel7,
+    channel8: Channel8,
+    channel9: Channel9,
+    channel10: Channel10,
+    channel11: Channel11,
+    channel12: Channel12,
+    channel13: Channel13,
+    channel14: Channel14,
 }
 
 pub trait Channel {
-    const INDEX: u32;
+    fn index(&self) -> u32;
+    fn kind(&self) -> ChannelKind;
 
     fn enable(&mut self);
     fn disable(&mut self);
@@ -156,71 +222,292 @@ pub trait Channel {
     fn clear_end(&mut self);
     fn clear_error(&mut self);
     fn start(&mut self);
+    /// Aborts the control block being executed.
+    fn abort(&mut self);
+
+    fn status(&self) -> Status;
+    fn debug(&self) -> DebugInfo;
+    /// Returns the bus address of the control block being executed.
+    fn control_block_address(&self) -> u32;
+    /// Clears the error flags of the `DEBUG` register.
+    fn clear_debug(&mut self);
+
+    /// Returns the error of the last transfer, if the `ERROR` flag is set.
+    fn check_error(&self) -> Result<(), DmaError> {
+        if !self.status().error {
+            return Ok(());
+        }
+
+        Err(self.debug().error())
+    }
+}
+
+/// Channels with their index in the type.
+///
+/// `INDEX` used to be part of [`Channel`], which also covers channels selected at runtime.
+pub trait IndexedChannel: Channel {
+    const INDEX: u32;
+}
+
+/// Engine behind a channel, which decides the register layout and the supported transfers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelKind {
+    Full,
+    /// Lite engines have a smaller `TXFR_LEN` and no 2D mode.
+    Lite,
+    /// DMA4 engines of BCM2711, with their own register and control block layout.
+    Dma4,
+}
+
+impl ChannelKind {
+    pub fn of(soc: Soc, index: u32) -> ChannelKind {
+        match (soc, index) {
+            (_, 0..=6) => ChannelKind::Full,
+            (Soc::Bcm2711, 11..) => ChannelKind::Dma4,
+            _ => ChannelKind::Lite,
+        }
+    }
+
+    /// Returns the largest number of bytes a single control block can transfer.
+    pub fn max_len(self) -> u32 {
+        match self {
+            ChannelKind::Full | ChannelKind::Dma4 => DMA_MAX_LEN,
+            ChannelKind::Lite => DMA_LITE_MAX_LEN,
+        }
+    }
+
+    pub fn supports_2d(self) -> bool {
+        self != ChannelKind::Lite
+    }
+
+    /// Checks that an engine of this kind can execute `block`.
+    pub fn validate(self, block: &ControlBlock) -> Result<(), CapabilityError> {
+        if read_bit_field(block.ti, DMA_TI_TDMODE) == 1 {
+            if !self.supports_2d() {
+                return Err(CapabilityError::TdModeUnsupported(self));
+            }
+        } else if block.txfr_len > self.max_len() {
+            return Err(CapabilityError::LengthTooLarge {
+                len: block.txfr_len,
+                max: self.max_len(),
+                kind: self,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A channel selected at runtime.
+pub struct AnyChannel {
+    regs: Arc<Mapping>,
+    index: u32,
+    kind: ChannelKind,
+    _claim: Claim,
+}
+
+impl AnyChannel {
+    fn read(&self, reg: usize) -> u32 {
+        self.regs
+            .read(self.index as usize * DMA_CHANNEL_OFFSET + reg)
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        self.regs
+            .write(self.index as usize * DMA_CHANNEL_OFFSET + reg, value)
+    }
+}
+
+impl Channel for AnyChannel {
+    fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn kind(&self) -> ChannelKind {
+        self.kind
+    }
+
+    fn enable(&mut self) {
+        let mut enable = self.regs.read(DMA_ENABLE_OFFSET);
+        enable |= 1 << self.index;
+        self.regs.write(DMA_ENABLE_OFFSET, enable);
+    }
+
+    fn disable(&mut self) {
+        let mut enable = self.regs.read(DMA_ENABLE_OFFSET);
+        enable &= !(1 << self.index);
+        self.regs.write(DMA_ENABLE_OFFSET, enable);
+    }
+
+    fn set_control_block_address(&mut self, cba: u32) {
+        match self.kind {
+            ChannelKind::Dma4 => self.write(DMA4_CB, (dma4_address(cba) >> 5) as u32),
+            _ => self.write(DMA_CONBLK_AD, cba),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self.kind {
+            ChannelKind::Dma4 => {
+                let mut debug = self.read(DMA4_DEBUG);
+                write_bit_field(&mut debug, DMA4_DEBUG_RESET, true);
+                self.write(DMA4_DEBUG, debug);
+            }
+            _ => {
+                let mut cs = self.read(DMA_CS);
+                write_bit_field(&mut cs, DMA_CS_RESET, true);
+                self.write(DMA_CS, cs);
+            }
+        }
+    }
+
+    fn clear_end(&mut self) {
+        // END is at the same position on all engines.
+        let mut cs = self.read(DMA_CS);
+        write_bit_field(&mut cs, DMA_CS_END, true);
+        self.write(DMA_CS, cs);
+    }
+
+    fn clear_error(&mut self) {
+        match self.kind {
+            // The DMA4 `ERROR` flag follows the error flags of `DEBUG`.
+            ChannelKind::Dma4 => self.clear_debug(),
+            _ => {
+                let mut cs = self.read(DMA_CS);
+                write_bit_field(&mut cs, DMA_CS_ERROR, true);
+                self.write(DMA_CS, cs);
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        let mut cs = self.read(DMA_CS);
+        write_bit_field(&mut cs, DMA_CS_ACTIVE, true);
+        self.write(DMA_CS, cs);
+    }
+
+    fn abort(&mut self) {
+        // ABORT is at the same position on all engines.
+        let mut cs = self.read(DMA_CS);
+        write_bit_field(&mut cs, DMA_CS_ABORT, true);
+        self.write(DMA_CS, cs);
+    }
+
+    fn status(&self) -> Status {
+        match self.kind {
+            ChannelKind::Dma4 => Status::from_dma4_value(self.read(DMA4_CS)),
+            _ => Status::from_value(self.read(DMA_CS)),
+        }
+    }
+
+    fn debug(&self) -> DebugInfo {
+        match self.kind {
+            ChannelKind::Dma4 => DebugInfo::from_dma4_value(self.read(DMA4_DEBUG)),
+            _ => DebugInfo::from_value(self.read(DMA_DEBUG)),
+        }
+    }
+
+    fn control_block_address(&self) -> u32 {
+        match self.kind {
+            ChannelKind::Dma4 => match self.read(DMA4_CB) {
+                0 => 0,
+                cb => dma4_bus_address((cb as u64) << 5),
+            },
+            _ => self.read(DMA_CONBLK_AD),
+        }
+    }
+
+    fn clear_debug(&mut self) {
+        let mut debug = 0;
+        match self.kind {
+            ChannelKind::Dma4 => {
+                write_bit_field(&mut debug, DMA4_DEBUG_READ_CB_ERROR, true);
+                write_bit_field(&mut debug, DMA4_DEBUG_READ_ERROR, true);
+                write_bit_field(&mut debug, DMA4_DEBUG_FIFO_ERROR, true);
+                write_bit_field(&mut debug, DMA4_DEBUG_WRITE_ERROR, true);
+                // Keep the other writable bits of the register.
+                debug |= self.read(DMA4_DEBUG) & !0xF;
+                self.write(DMA4_DEBUG, debug);
+            }
+            _ => {
+                write_bit_field(&mut debug, DMA_DEBUG_READ_ERROR, true);
+                write_bit_field(&mut debug, DMA_DEBUG_FIFO_ERROR, true);
+                write_bit_field(&mut debug, DMA_DEBUG_READ_LAST_NOT_SET_ERROR, true);
+                self.write(DMA_DEBUG, debug);
+            }
+        }
+    }
 }
 
 macro_rules! channel {
     ($name:ident, $index:expr) => {
         pub struct $name {
-            enable_virt: *mut u32,
-            regs: MemMap,
+            channel: AnyChannel,
         }
 
         impl $name {
-            fn new(regs: &MemMap) -> Self {
-                let offset = $index * DMA_CHANNEL_OFFSET;
-
-                Self {
-                    enable_virt: regs.virt.wrapping_byte_add(DMA_ENABLE_OFFSET),
-                    regs: MemMap {
-                        bus: regs.bus.wrapping_byte_add(offset),
-                        phys: regs.phys.wrapping_byte_add(offset),
-                        virt: regs.virt.wrapping_byte_add(offset),
-                    },
-                }
-            }
+            pub const INDEX: u32 = $index;
         }
 
-        impl Channel for $name {
+        impl IndexedChannel for $name {
             const INDEX: u32 = $index;
+        }
+
+        impl Channel for $name {
+            fn index(&self) -> u32 {
+                $index
+            }
+
+            fn kind(&self) -> ChannelKind {
+                self.channel.kind()
+            }
 
             fn enable(&mut self) {
-                let mut enable = unsafe { self.enable_virt.read_volatile() };
-                enable |= 1 << $index;
-                unsafe { self.enable_virt.write_volatile(enable) };
+                self.channel.enable()
             }
 
             fn disable(&mut self) {
-                let mut enable = unsafe { self.enable_virt.read_volatile() };
-                enable &= !(1 << $index);
-                unsafe { self.enable_virt.write_volatile(enable) };
+                self.channel.disable()
             }
 
             fn set_control_block_address(&mut self, cba: u32) {
-                unsafe { self.regs.virt.byte_add(DMA_CONBLK_AD).write_volatile(cba) };
+                self.channel.set_control_block_address(cba)
             }
 
             fn reset(&mut self) {
-                let mut cs = unsafe { self.regs.virt.byte_add(DMA_CS).read_volatile() };
-                write_bit_field(&mut cs, DMA_CS_RESET, true);
-                unsafe { self.regs.virt.byte_add(DMA_CS).write_volatile(cs) };
+                self.channel.reset()
             }
 
             fn clear_end(&mut self) {
-                let mut cs = unsafe { self.regs.virt.byte_add(DMA_CS).read_volatile() };
-                write_bit_field(&mut cs, DMA_CS_END, true);
-                unsafe { self.regs.virt.byte_add(DMA_CS).write_volatile(cs) };
+                self.channel.clear_end()
             }
 
             fn clear_error(&mut self) {
-                let mut cs = unsafe { self.regs.virt.byte_add(DMA_CS).read_volatile() };
-                write_bit_field(&mut cs, DMA_CS_ERROR, true);
-                unsafe { self.regs.virt.byte_add(DMA_CS).write_volatile(cs) };
+                self.channel.clear_error()
             }
 
             fn start(&mut self) {
-                let mut cs = unsafe { self.regs.virt.byte_add(DMA_CS).read_volatile() };
-                write_bit_field(&mut cs, DMA_CS_ACTIVE, true);
-                unsafe { self.regs.virt.byte_add(DMA_CS).write_volatile(cs) };
+                self.channel.start()
+            }
+
+            fn abort(&mut self) {
+                self.channel.abort()
+            }
+
+            fn status(&self) -> Status {
+                self.channel.status()
+            }
+
+            fn debug(&self) -> DebugInfo {
+                self.channel.debug()
+            }
+
+            fn control_block_address(&self) -> u32 {
+                self.channel.control_block_address()
+            }
+
+            fn clear_debug(&mut self) {
+                self.channel.clear_debug()
             }
         }
     };
@@ -241,3 +528,356 @@ channel!(Channel11, 11);
 channel!(Channel12, 12);
 channel!(Channel13, 13);
 channel!(Channel14, 14);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    pub active: bool,
+    pub end: bool,
+    pub int: bool,
+    pub dreq: bool,
+    pub paused: bool,
+    pub waiting_for_outstanding_writes: bool,
+    pub error: bool,
+}
+
+impl Status {
+    pub fn from_value(cs: u32) -> Self {
+        let flag = |field| read_bit_field(cs, field) == 1;
+        Self {
+            active: flag(DMA_CS_ACTIVE),
+            end: flag(DMA_CS_END),
+            int: flag(DMA_CS_INT),
+            dreq: flag(DMA_CS_DREQ),
+            paused: flag(DMA_CS_PAUSED),
+            waiting_for_outstanding_writes: flag(DMA_CS_WAITING_FOR_OUTSTANDING_WRITES),
+            error: flag(DMA_CS_ERROR),
+        }
+    }
+
+    pub fn from_dma4_value(cs: u32) -> Self {
+        let flag = |field| read_bit_field(cs, field) == 1;
+        Self {
+            active: flag(DMA4_CS_ACTIVE),
+            end: flag(DMA4_CS_END),
+            int: flag(DMA4_CS_INT),
+            dreq: flag(DMA4_CS_DREQ),
+            paused: flag(DMA4_CS_RD_PAUSED) || flag(DMA4_CS_WR_PAUSED),
+            waiting_for_outstanding_writes: flag(DMA4_CS_WAITING_FOR_OUTSTANDING_WRITES),
+            error: flag(DMA4_CS_ERROR),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub lite: bool,
+    pub version: u8,
+    pub state: u16,
+    pub id: u8,
+    pub outstanding_writes: u8,
+    pub read_error: bool,
+    pub fifo_error: bool,
+    pub read_last_not_set_error: bool,
+    /// Only reported by DMA4 engines.
+    pub read_control_block_error: bool,
+    /// Only reported by DMA4 engines.
+    pub write_error: bool,
+}
+
+impl DebugInfo {
+    pub fn from_value(debug: u32) -> Self {
+        let flag = |field| read_bit_field(debug, field) == 1;
+        Self {
+            lite: flag(DMA_DEBUG_LITE),
+            version: read_bit_field(debug, DMA_DEBUG_VERSION) as u8,
+            state: read_bit_field(debug, DMA_DEBUG_DMA_STATE) as u16,
+            id: read_bit_field(debug, DMA_DEBUG_DMA_ID) as u8,
+            outstanding_writes: read_bit_field(debug, DMA_DEBUG_OUTSTANDING_WRITES) as u8,
+            read_error: flag(DMA_DEBUG_READ_ERROR),
+            fifo_error: flag(DMA_DEBUG_FIFO_ERROR),
+            read_last_not_set_error: flag(DMA_DEBUG_READ_LAST_NOT_SET_ERROR),
+            read_control_block_error: false,
+            write_error: false,
+        }
+    }
+
+    pub fn from_dma4_value(debug: u32) -> Self {
+        let flag = |field| read_bit_field(debug, field) == 1;
+        Self {
+            lite: false,
+            version: read_bit_field(debug, DMA4_DEBUG_VERSION) as u8,
+            state: (read_bit_field(debug, DMA4_DEBUG_W_STATE) << 4
+                | read_bit_field(debug, DMA4_DEBUG_R_STATE)) as u16,
+            id: read_bit_field(debug, DMA4_DEBUG_ID) as u8,
+            outstanding_writes: 0,
+            read_error: flag(DMA4_DEBUG_READ_ERROR),
+            fifo_error: flag(DMA4_DEBUG_FIFO_ERROR),
+            read_last_not_set_error: false,
+            read_control_block_error: flag(DMA4_DEBUG_READ_CB_ERROR),
+            write_error: flag(DMA4_DEBUG_WRITE_ERROR),
+        }
+    }
+
+    /// Returns the error reported by the flags, the one closest to its cause if several are
+    /// set.
+    pub fn error(&self) -> DmaError {
+        if self.read_control_block_error {
+            DmaError::ReadControlBlock
+        } else if self.read_error {
+            DmaError::Read
+        } else if self.write_error {
+            DmaError::Write
+        } else if self.fifo_error {
+            DmaError::Fifo
+        } else if self.read_last_not_set_error {
+            DmaError::ReadLastNotSet
+        } else {
+            DmaError::Unknown
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaError {
+    /// An AXI read returned an error.
+    Read,
+    /// The read FIFO overflowed or underflowed.
+    Fifo,
+    /// The last AXI read of a burst did not signal `RLAST`.
+    ReadLastNotSet,
+    /// Reading a control block failed, only reported by DMA4 engines.
+    ReadControlBlock,
+    /// An AXI write returned an error, only reported by DMA4 engines.
+    Write,
+    /// `ERROR` is set, but the `DEBUG` register doesn't say why.
+    Unknown,
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::Read => write!(f, "DMA read error"),
+            DmaError::Fifo => write!(f, "DMA FIFO error"),
+            DmaError::ReadLastNotSet => write!(f, "DMA read last not set error"),
+            DmaError::ReadControlBlock => write!(f, "DMA control block read error"),
+            DmaError::Write => write!(f, "DMA write error"),
+            DmaError::Unknown => write!(f, "unknown DMA error"),
+        }
+    }
+}
+
+impl error::Error for DmaError {}
+
+impl From<DmaError> for io::Error {
+    fn from(err: DmaError) -> Self {
+        io::Error::other(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The transfer is longer than a single control block of the channel can handle.
+    LengthTooLarge {
+        len: u32,
+        max: u32,
+        kind: ChannelKind,
+    },
+    /// 2D mode was requested on a channel without it.
+    TdModeUnsupported(ChannelKind),
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityError::LengthTooLarge { len, max, kind } => write!(
+                f,
+                "transfer of {len} bytes exceeds the {max} byte limit of {kind:?} DMA channels"
+            ),
+            CapabilityError::TdModeUnsupported(kind) => {
+                write!(f, "{kind:?} DMA channels don't support 2D mode")
+            }
+        }
+    }
+}
+
+impl error::Error for CapabilityError {}
+
+impl From<CapabilityError> for io::Error {
+    fn from(err: CapabilityError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_kinds_per_soc() {
+        for soc in [Soc::Bcm2835, Soc::Bcm2836, Soc::Bcm2837, Soc::Bcm2711] {
+            for index in 0..DMA_CHANNEL_COUNT {
+                let expected = match index {
+                    0..=6 => ChannelKind::Full,
+                    11.. if soc == Soc::Bcm2711 => ChannelKind::Dma4,
+                    _ => ChannelKind::Lite,
+                };
+                assert_eq!(ChannelKind::of(soc, index), expected, "{soc:?} {index}");
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_are_validated_per_kind() {
+        let ti = TransferInfo::new();
+        let max = ControlBlock::new(ti, 0, 0, DMA_LITE_MAX_LEN);
+        let long = ControlBlock::new(ti, 0, 0, DMA_LITE_MAX_LEN + 1);
+        let two_d = ControlBlock::new_2d(ti, 0, 0, 0xffff, 0x3fff, 0, 0);
+
+        for kind in [ChannelKind::Full, ChannelKind::Dma4] {
+            assert_eq!(kind.validate(&max), Ok(()));
+            assert_eq!(kind.validate(&long), Ok(()));
+            assert_eq!(kind.validate(&two_d), Ok(()));
+            assert_eq!(
+                kind.validate(&ControlBlock::new(ti, 0, 0, DMA_MAX_LEN + 1)),
+                Err(CapabilityError::LengthTooLarge {
+                    len: DMA_MAX_LEN + 1,
+                    max: DMA_MAX_LEN,
+                    kind,
+                })
+            );
+        }
+
+        let lite = ChannelKind::Lite;
+        assert_eq!(lite.validate(&max), Ok(()));
+        assert_eq!(
+            lite.validate(&long),
+            Err(CapabilityError::LengthTooLarge {
+                len: DMA_LITE_MAX_LEN + 1,
+                max: DMA_LITE_MAX_LEN,
+                kind: lite,
+            })
+        );
+        assert_eq!(
+            lite.validate(&two_d),
+            Err(CapabilityError::TdModeUnsupported(lite))
+        );
+        // Small 2D blocks are rejected as well.
+        let small = ControlBlock::new_2d(ti, 0, 0, 4, 1, 0, 0);
+        assert_eq!(
+            lite.validate(&small),
+            Err(CapabilityError::TdModeUnsupported(lite))
+        );
+    }
+
+    #[test]
+    fn status_flags() {
+        assert_eq!(Status::from_value(0), Status::default());
+        assert_eq!(
+            Status::from_value(0x0000_0103),
+            Status {
+                active: true,
+                end: true,
+                error: true,
+                ..Status::default()
+            }
+        );
+        assert_eq!(
+            Status::from_value(0x1077_007c),
+            Status {
+                int: true,
+                dreq: true,
+                paused: true,
+                waiting_for_outstanding_writes: true,
+                ..Status::default()
+            }
+        );
+
+        // DMA4 moves the error bit and splits the pause flag.
+        assert!(Status::from_dma4_value(1 << 10).error);
+        assert!(!Status::from_dma4_value(1 << 8).error);
+        assert!(Status::from_dma4_value(1 << 4).paused);
+        assert!(Status::from_dma4_value(1 << 5).paused);
+        assert!(Status::from_dma4_value(1 << 7).waiting_for_outstanding_writes);
+    }
+
+    #[test]
+    fn debug_errors() {
+        let cases = [
+            (0b001, DmaError::ReadLastNotSet),
+            (0b010, DmaError::Fifo),
+            (0b100, DmaError::Read),
+            (0b000, DmaError::Unknown),
+        ];
+        for (value, error) in cases {
+            assert_eq!(DebugInfo::from_value(value).error(), error, "{value:#x}");
+        }
+
+        let cases = [
+            (0b0001, DmaError::Write),
+            (0b0010, DmaError::Fifo),
+            (0b0100, DmaError::Read),
+            (0b1000, DmaError::ReadControlBlock),
+        ];
+        for (value, error) in cases {
+            assert_eq!(
+                DebugInfo::from_dma4_value(value).error(),
+                error,
+                "{value:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn debug_fields() {
+        // Lite channel 7 of version 2, in state 3 with 5 outstanding writes.
+        assert_eq!(
+            DebugInfo::from_value(0x1403_0750),
+            DebugInfo {
+                lite: true,
+                version: 2,
+                state: 3,
+                id: 7,
+                outstanding_writes: 5,
+                ..DebugInfo::default()
+            }
+        );
+
+        let debug = DebugInfo::from_dma4_value(0x1b0c_c000);
+        assert_eq!((debug.version, debug.id, debug.state), (1, 11, 3 << 4 | 3));
+        assert!(!debug.lite);
+    }
+
+    #[test]
+    fn errors_closest_to_the_cause_come_first() {
+        let all = DebugInfo {
+            read_error: true,
+            fifo_error: true,
+            read_last_not_set_error: true,
+            read_control_block_error: true,
+            write_error: true,
+            ..DebugInfo::default()
+        };
+        assert_eq!(all.error(), DmaError::ReadControlBlock);
+
+        let debug = DebugInfo {
+            read_control_block_error: false,
+            ..all
+        };
+        assert_eq!(debug.error(), DmaError::Read);
+
+        let debug = DebugInfo {
+            read_error: false,
+            ..debug
+        };
+        assert_eq!(debug.error(), DmaError::Write);
+
+        let debug = DebugInfo {
+            write_error: false,
+            ..debug
+        };
+        assert_eq!(debug.error(), DmaError::Fifo);
+
+        assert_eq!(DebugInfo::from_value(0b111).error(), DmaError::Read);
+        assert_eq!(DebugInfo::from_value(0b011).error(), DmaError::Fifo);
+    }
+}
@@ -0,0 +1,395 @@
+use std::{mem, ptr};
+
+use crate::{
+    field::{bits, read_bit_field, write_bit_field, Field},
+    gpu::GpuMem,
+    mailbox::{Mailbox, MailboxError, BUS_ALIAS_MASK},
+    mem::DmaMemory,
+};
+
+use super::*;
+
+pub const DMA_CB_TXFR_LEN_YLENGTH: Field<u32> = bits(29, 16);
+pub const DMA_CB_TXFR_LEN_XLENGTH: Field<u32> = bits(15, 0);
+
+pub const DMA_CB_STRIDE_D_STRIDE: Field<u32> = bits(31, 16);
+pub const DMA_CB_STRIDE_S_STRIDE: Field<u32> = bits(15, 0);
+
+/// Value of the `TI` word of a control block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferInfo(u32);
+
+macro_rules! transfer_info_flag {
+    ($name:ident, $field:expr) => {
+        pub fn $name(mut self, value: bool) -> Self {
+            write_bit_field(&mut self.0, $field, value);
+            self
+        }
+    };
+}
+
+impl TransferInfo {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn from_value(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    transfer_info_flag!(no_wide_bursts, DMA_TI_NO_WIDE_BURSTS);
+    transfer_info_flag!(src_ignore, DMA_TI_SRC_IGNORE);
+    transfer_info_flag!(src_dreq, DMA_TI_SRC_DREQ);
+    transfer_info_flag!(src_width_128, DMA_TI_SRC_WIDTH);
+    transfer_info_flag!(src_inc, DMA_TI_SRC_INC);
+    transfer_info_flag!(dest_ignore, DMA_TI_DEST_IGNORE);
+    transfer_info_flag!(dest_dreq, DMA_TI_DEST_DREQ);
+    transfer_info_flag!(dest_width_128, DMA_TI_DEST_WIDTH);
+    transfer_info_flag!(dest_inc, DMA_TI_DEST_INC);
+    transfer_info_flag!(wait_resp, DMA_TI_WAIT_RESP);
+    transfer_info_flag!(tdmode, DMA_TI_TDMODE);
+    transfer_info_flag!(inten, DMA_TI_INTEN);
+
+    /// Adds `waits` dummy cycles after each write, at most 31.
+    pub fn waits(mut self, waits: u8) -> Self {
+        assert!(waits < 32);
+        write_bit_field(&mut self.0, DMA_TI_WAITS, waits);
+        self
+    }
+
+    /// Selects the peripheral whose DREQ paces the transfer, one of `DMA_PERMAP_*`.
+    pub fn permap(mut self, permap: u8) -> Self {
+        assert!(permap < 32);
+        write_bit_field(&mut self.0, DMA_TI_PERMAP, permap);
+        self
+    }
+
+    /// Sets the burst length in words, at most 15.
+    pub fn burst_length(mut self, burst_length: u8) -> Self {
+        assert!(burst_length < 16);
+        write_bit_field(&mut self.0, DMA_TI_BURST_LENGTH, burst_length);
+        self
+    }
+}
+
+/// A DMA control block as read by the DMA engine.
+#[repr(C, align(32))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlBlock {
+    pub ti: u32,
+    pub source_ad: u32,
+    pub dest_ad: u32,
+    pub txfr_len: u32,
+    pub stride: u32,
+    pub nextconbk: u32,
+    pub reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    pub fn new(ti: TransferInfo, source_ad: u32, dest_ad: u32, txfr_len: u32) -> Self {
+        Self {
+            ti: ti.value(),
+            source_ad,
+            dest_ad,
+            txfr_len,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a 2D block transferring `ylength` rows of `xlength` bytes, `TDMODE` is set.
+    ///
+    /// The strides are added to the addresses after each row.
+    pub fn new_2d(
+        ti: TransferInfo,
+        source_ad: u32,
+        dest_ad: u32,
+        xlength: u16,
+        ylength: u16,
+        src_stride: i16,
+        dest_stride: i16,
+    ) -> Self {
+        assert!(ylength < 1 << 14);
+
+        let mut txfr_len = 0;
+        write_bit_field(&mut txfr_len, DMA_CB_TXFR_LEN_XLENGTH, xlength);
+        write_bit_field(&mut txfr_len, DMA_CB_TXFR_LEN_YLENGTH, ylength);
+
+        let mut stride = 0;
+        write_bit_field(&mut stride, DMA_CB_STRIDE_S_STRIDE, src_stride as u16);
+        write_bit_field(&mut stride, DMA_CB_STRIDE_D_STRIDE, dest_stride as u16);
+
+        Self {
+            ti: ti.tdmode(true).value(),
+            source_ad,
+            dest_ad,
+            txfr_len,
+            stride,
+            ..Default::default()
+        }
+    }
+
+    pub fn transfer_info(&self) -> TransferInfo {
+        TransferInfo(self.ti)
+    }
+
+    /// Number of bytes transferred, taking `TDMODE` into account.
+    pub fn len(&self) -> usize {
+        if read_bit_field(self.ti, DMA_TI_TDMODE) == 0 {
+            return self.txfr_len as usize;
+        }
+
+        let xlength = read_bit_field(self.txfr_len, DMA_CB_TXFR_LEN_XLENGTH) as usize;
+        let ylength = read_bit_field(self.txfr_len, DMA_CB_TXFR_LEN_YLENGTH) as usize;
+        xlength * ylength
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Control blocks linked in DMA memory.
+///
+/// Pushed blocks are linked to the previous one through `NEXTCONBK`, the last block ends the
+/// transfer unless it is linked elsewhere with [`CbChain::link`].
+pub struct CbChain<M: DmaMemory> {
+    memory: M,
+    len: usize,
+}
+
+impl<'a> CbChain<GpuMem<'a>> {
+    pub fn alloc(mailbox: &'a Mailbox, capacity: usize) -> Result<Self, MailboxError> {
+        Ok(Self::new(GpuMem::alloc(
+            mailbox,
+            capacity * DMA_CONTROL_BLOCK_SIZE,
+        )?))
+    }
+}
+
+impl<M: DmaMemory> CbChain<M> {
+    pub fn new(memory: M) -> Self {
+        assert!((memory.memmap().bus as usize).is_multiple_of(DMA_CONTROL_BLOCK_SIZE));
+        Self { memory, len: 0 }
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.memory.size() / mem::size_of::<ControlBlock>()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bus address of the block at `index`, as used for `CONBLK_AD` and `NEXTCONBK`.
+    pub fn bus(&self, index: usize) -> u32 {
+        assert!(index < self.len);
+        self.memory
+            .memmap()
+            .bus
+            .wrapping_byte_add(index * mem::size_of::<ControlBlock>()) as u32
+    }
+
+    /// Returns the index of the block at bus address `bus`, regardless of its alias.
+    pub fn index_of(&self, bus: u32) -> Option<usize> {
+        let base = self.memory.memmap().bus as u32 & !BUS_ALIAS_MASK;
+        let offset = (bus & !BUS_ALIAS_MASK).checked_sub(base)? as usize;
+        let index = offset / mem::size_of::<ControlBlock>();
+        (offset.is_multiple_of(mem::size_of::<ControlBlock>()) && index < self.len).then_some(index)
+    }
+
+    /// Appends `block` and links the previous block to it, returns the index of the block.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chain is full.
+    pub fn push(&mut self, block: ControlBlock) -> usize {
+        assert!(self.len < self.capacity(), "control block chain is full");
+
+        let index = self.len;
+        self.len += 1;
+        self.write(
+            index,
+            ControlBlock {
+                nextconbk: 0,
+                ..block
+            },
+        );
+
+        if index > 0 {
+            self.link(index - 1, Some(index));
+        }
+
+        index
+    }
+
+    pub fn get(&self, index: usize) -> ControlBlock {
+        assert!(index < self.len);
+        unsafe { ptr::read_volatile(self.block_ptr(index)) }
+    }
+
+    /// Changes the block at `index`, the link to the next block is kept.
+    pub fn update(&mut self, index: usize, f: impl FnOnce(&mut ControlBlock)) {
+        let mut block = self.get(index);
+        let nextconbk = block.nextconbk;
+        f(&mut block);
+        block.nextconbk = nextconbk;
+        self.write(index, block);
+    }
+
+    /// Links the block at `from` to the block at `to`, `None` ends the transfer after `from`.
+    pub fn link(&mut self, from: usize, to: Option<usize>) {
+        let mut block = self.get(from);
+        block.nextconbk = to.map_or(0, |to| self.bus(to));
+        self.write(from, block);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn write(&mut self, index: usize, block: ControlBlock) {
+        unsafe { ptr::write_volatile(self.block_ptr(index), block) }
+    }
+
+    fn block_ptr(&self, index: usize) -> *mut ControlBlock {
+        self.memory
+            .memmap()
+            .virt
+            .cast::<ControlBlock>()
+            .wrapping_add(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Soc, sim::Board};
+
+    #[test]
+    fn transfer_info_flags_use_the_datasheet_bits() {
+        type Flag = fn(TransferInfo, bool) -> TransferInfo;
+        let flags: [(Flag, u32); 12] = [
+            (TransferInfo::inten, 0),
+            (TransferInfo::tdmode, 1),
+            (TransferInfo::wait_resp, 3),
+            (TransferInfo::dest_inc, 4),
+            (TransferInfo::dest_width_128, 5),
+            (TransferInfo::dest_dreq, 6),
+            (TransferInfo::dest_ignore, 7),
+            (TransferInfo::src_inc, 8),
+            (TransferInfo::src_width_128, 9),
+            (TransferInfo::src_dreq, 10),
+            (TransferInfo::src_ignore, 11),
+            (TransferInfo::no_wide_bursts, 26),
+        ];
+        for (flag, bit) in flags {
+            assert_eq!(flag(TransferInfo::new(), true).value(), 1 << bit);
+            assert_eq!(
+                flag(TransferInfo::from_value(!0), false).value(),
+                !(1 << bit)
+            );
+        }
+
+        let ti = TransferInfo::new()
+            .waits(31)
+            .permap(DMA_PERMAP_SMI)
+            .burst_length(15);
+        assert_eq!(ti.value(), 31 << 21 | 4 << 16 | 15 << 12);
+        assert_eq!(ti.waits(1).value(), 1 << 21 | 4 << 16 | 15 << 12);
+    }
+
+    #[test]
+    #[should_panic]
+    fn transfer_info_fields_are_checked() {
+        TransferInfo::new().burst_length(16);
+    }
+
+    #[test]
+    fn block_lengths() {
+        let ti = TransferInfo::new().src_inc(true);
+        let block = ControlBlock::new(ti, 0x1000, 0x2000, 64);
+        assert_eq!(block.len(), 64);
+        assert_eq!(block.transfer_info(), ti);
+        assert!(ControlBlock::new(ti, 0, 0, 0).is_empty());
+
+        let block = ControlBlock::new_2d(ti, 0x1000, 0x2000, 16, 3, -8, 4);
+        assert_eq!(block.ti, ti.tdmode(true).value());
+        assert_eq!(block.txfr_len, 3 << 16 | 16);
+        assert_eq!(block.stride, 4 << 16 | 0xfff8);
+        assert_eq!(block.len(), 48);
+
+        // Without TDMODE the whole word is the length.
+        let block = ControlBlock {
+            ti: ti.value(),
+            ..block
+        };
+        assert_eq!(block.len(), 3 << 16 | 16);
+    }
+
+    #[test]
+    fn chained_blocks_are_linked() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let mut chain = CbChain::alloc(&mailbox, 4).unwrap();
+        // The allocation is rounded up to whole pages.
+        assert_eq!(chain.capacity(), 128);
+        let bus = chain.memory().memmap().bus as u32;
+
+        let block = ControlBlock {
+            nextconbk: 0xDEAD_0000,
+            ..ControlBlock::new(TransferInfo::new(), 0, 0, 4)
+        };
+        for index in 0..3 {
+            assert_eq!(chain.push(block), index);
+        }
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain.bus(2), bus + 64);
+        assert_eq!(chain.get(0).nextconbk, bus + 32);
+        assert_eq!(chain.get(1).nextconbk, bus + 64);
+        // The last block ends the transfer, whatever link it was pushed with.
+        assert_eq!(chain.get(2).nextconbk, 0);
+
+        chain.link(2, Some(0));
+        assert_eq!(chain.get(2).nextconbk, bus);
+        chain.update(2, |block| block.txfr_len = 8);
+        assert_eq!(chain.get(2).nextconbk, bus);
+        assert_eq!(chain.get(2).len(), 8);
+        chain.link(0, None);
+        assert_eq!(chain.get(0).nextconbk, 0);
+
+        assert_eq!(chain.index_of(bus + 32), Some(1));
+        assert_eq!(chain.index_of((bus + 64) & !BUS_ALIAS_MASK), Some(2));
+        assert_eq!(chain.index_of(bus + 36), None);
+        assert_eq!(chain.index_of(bus + 96), None);
+        assert_eq!(chain.index_of(bus - 32), None);
+
+        chain.clear();
+        assert!(chain.is_empty());
+        assert_eq!(chain.index_of(bus), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn full_chains_panic() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let mut chain = CbChain::alloc(&mailbox, 1).unwrap();
+        let capacity = chain.capacity();
+
+        for _ in 0..=capacity {
+            chain.push(ControlBlock::default());
+        }
+    }
+}
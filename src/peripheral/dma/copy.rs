@@ -0,0 +1,451 @@
+use std::{
+    error, fmt, io, mem, ptr, thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    gpu::GpuMem,
+    mailbox::MailboxError,
+    mem::{DmaMemory, MemMap},
+};
+
+use super::*;
+
+/// Copies `len` bytes from the start of `src` to the start of `dst` and waits until the
+/// copy is finished.
+///
+/// Copies longer than a single control block allows for the kind of `channel` are split
+/// into several blocks. The control blocks are allocated through the mailbox of `src`.
+///
+/// If the copy is not finished after `timeout` the channel is reset and
+/// [`CopyError::Timeout`] is returned.
+pub fn copy(
+    channel: &mut impl Channel,
+    src: &GpuMem,
+    dst: &GpuMem,
+    len: usize,
+    timeout: Duration,
+) -> Result<(), CopyError> {
+    check_bounds(len, src)?;
+    check_bounds(len, dst)?;
+
+    let max = channel.kind().max_len() as usize & !0x1F;
+    let blocks: Vec<_> = (0..len.div_ceil(max).max(1))
+        .map(|i| {
+            let offset = i * max;
+            ControlBlock::new(
+                copy_transfer_info(),
+                src.memmap().bus.wrapping_byte_add(offset) as u32,
+                dst.memmap().bus.wrapping_byte_add(offset) as u32,
+                (len - offset).min(max) as u32,
+            )
+        })
+        .collect();
+
+    run(channel, src, blocks, timeout)
+}
+
+/// Copies `ylength` rows of `xlength` bytes from `src` to `dst` and waits until the copy is
+/// finished.
+///
+/// The strides are added to the addresses after each row, so a stride of zero copies
+/// consecutive rows. `timeout` is handled like by [`copy`].
+#[allow(clippy::too_many_arguments)]
+pub fn copy_2d(
+    channel: &mut impl Channel,
+    src: &GpuMem,
+    dst: &GpuMem,
+    xlength: u16,
+    ylength: u16,
+    src_stride: i16,
+    dst_stride: i16,
+    timeout: Duration,
+) -> Result<(), CopyError> {
+    check_bounds(extent_2d(xlength, ylength, src_stride), src)?;
+    check_bounds(extent_2d(xlength, ylength, dst_stride), dst)?;
+
+    let block = ControlBlock::new_2d(
+        copy_transfer_info(),
+        src.memmap().bus as u32,
+        dst.memmap().bus as u32,
+        xlength,
+        ylength,
+        src_stride,
+        dst_stride,
+    );
+
+    run(channel, src, vec![block], timeout)
+}
+
+fn copy_transfer_info() -> TransferInfo {
+    TransferInfo::new()
+        .src_inc(true)
+        .dest_inc(true)
+        .wait_resp(true)
+}
+
+fn check_bounds(len: usize, memory: &impl DmaMemory) -> Result<(), CopyError> {
+    if len > memory.size() {
+        return Err(CopyError::OutOfBounds {
+            len,
+            size: memory.size(),
+        });
+    }
+    Ok(())
+}
+
+// Number of bytes from the start of the first row to the end of the last one, negative
+// strides are rejected through an extent larger than any memory.
+fn extent_2d(xlength: u16, ylength: u16, stride: i16) -> usize {
+    if ylength == 0 {
+        return 0;
+    }
+    let pitch = xlength as i64 + stride as i64;
+    let extent = (ylength as i64 - 1) * pitch + xlength as i64;
+    if pitch < 0 || extent < 0 {
+        usize::MAX
+    } else {
+        extent as usize
+    }
+}
+
+fn run(
+    channel: &mut impl Channel,
+    src: &GpuMem,
+    blocks: Vec<ControlBlock>,
+    timeout: Duration,
+) -> Result<(), CopyError> {
+    for block in &blocks {
+        channel.kind().validate(block)?;
+    }
+
+    let memory = GpuMem::alloc(src.mailbox(), blocks.len() * mem::size_of::<ControlBlock>())?;
+    write_blocks(channel.kind(), memory.memmap(), blocks);
+
+    channel.enable();
+    channel.reset();
+    channel.set_control_block_address(memory.memmap().bus as u32);
+    channel.clear_end();
+    channel.clear_error();
+    channel.clear_debug();
+    channel.start();
+
+    // `END` is set after every control block, only an inactive channel is done with the
+    // chain. The control blocks are freed on return and must not be read anymore, so the
+    // channel is stopped on errors and timeouts.
+    let start = Instant::now();
+    loop {
+        let status = channel.status();
+        if status.error {
+            let err = channel.debug().error();
+            channel.abort();
+            channel.reset();
+            return Err(CopyError::Dma(err));
+        }
+        if !status.active {
+            return Ok(());
+        }
+
+        if start.elapsed() >= timeout {
+            channel.abort();
+            channel.reset();
+            return Err(CopyError::Timeout);
+        }
+        thread::yield_now();
+    }
+}
+
+// Links the blocks in order and writes them in the format of the channel.
+fn write_blocks(kind: ChannelKind, memmap: &MemMap, mut blocks: Vec<ControlBlock>) {
+    let count = blocks.len();
+    for (i, block) in blocks.iter_mut().enumerate() {
+        if i + 1 < count {
+            block.nextconbk = memmap
+                .bus
+                .wrapping_byte_add((i + 1) * mem::size_of::<ControlBlock>())
+                as u32;
+        }
+
+        let ptr = memmap
+            .virt
+            .wrapping_byte_add(i * mem::size_of::<ControlBlock>());
+        unsafe {
+            match kind {
+                ChannelKind::Dma4 => ptr::write_volatile(
+                    ptr.cast::<Dma4ControlBlock>(),
+                    Dma4ControlBlock::from_legacy(block),
+                ),
+                _ => ptr::write_volatile(ptr.cast::<ControlBlock>(), *block),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CopyError {
+    /// The copy reaches past the end of the source or destination.
+    OutOfBounds {
+        len: usize,
+        size: usize,
+    },
+    Capability(CapabilityError),
+    Mailbox(MailboxError),
+    Dma(DmaError),
+    /// The copy didn't finish in time.
+    Timeout,
+}
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CopyError::OutOfBounds { len, size } => {
+                write!(f, "copy of {len} bytes exceeds memory of {size} bytes")
+            }
+            CopyError::Capability(err) => write!(f, "{err}"),
+            CopyError::Mailbox(err) => write!(f, "failed to allocate control blocks: {err}"),
+            CopyError::Dma(err) => write!(f, "{err}"),
+            CopyError::Timeout => write!(f, "copy did not finish in time"),
+        }
+    }
+}
+
+impl error::Error for CopyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CopyError::OutOfBounds { .. } | CopyError::Timeout => None,
+            CopyError::Capability(err) => Some(err),
+            CopyError::Mailbox(err) => Some(err),
+            CopyError::Dma(err) => Some(err),
+        }
+    }
+}
+
+impl From<CapabilityError> for CopyError {
+    fn from(err: CapabilityError) -> Self {
+        CopyError::Capability(err)
+    }
+}
+
+impl From<MailboxError> for CopyError {
+    fn from(err: MailboxError) -> Self {
+        CopyError::Mailbox(err)
+    }
+}
+
+impl From<DmaError> for CopyError {
+    fn from(err: DmaError) -> Self {
+        CopyError::Dma(err)
+    }
+}
+
+impl From<CopyError> for io::Error {
+    fn from(err: CopyError) -> Self {
+        match err {
+            CopyError::OutOfBounds { .. } | CopyError::Capability(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+            CopyError::Mailbox(err) => err.into(),
+            CopyError::Dma(err) => err.into(),
+            CopyError::Timeout => io::Error::new(io::ErrorKind::TimedOut, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::{platform::Soc, sim::Board};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Runs the DMA engine of `board` in the background while `f` is executed.
+    fn with_running_board<R>(board: &Board, f: impl FnOnce() -> R) -> R {
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    board.run();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let result = f();
+            done.store(true, Ordering::Release);
+            result
+        })
+    }
+
+    fn words(memory: &GpuMem, count: usize) -> Vec<u32> {
+        (0..count)
+            .map(|i| unsafe { memory.memmap().virt.add(i).read_volatile() })
+            .collect()
+    }
+
+    #[test]
+    fn copy_is_split_into_blocks() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let dma = Peripheral::open_with(&board.platform(), board.memory()).unwrap();
+        // A lite channel, whose blocks are limited to 64 KiB.
+        let mut channel = dma.channels.take(8).unwrap();
+
+        let len = 0x28000;
+        let src = GpuMem::alloc(&mailbox, len).unwrap();
+        let dst = GpuMem::alloc(&mailbox, len).unwrap();
+        for i in 0..len / 4 {
+            unsafe { src.memmap().virt.add(i).write_volatile(i as u32) };
+        }
+
+        with_running_board(&board, || copy(&mut channel, &src, &dst, len, TIMEOUT)).unwrap();
+        assert!(!channel.status().active);
+        assert_eq!(channel.control_block_address(), 0);
+        assert_eq!(words(&dst, len / 4), words(&src, len / 4));
+    }
+
+    /// Reports the scripted statuses in order and records the calls that stop the channel.
+    struct ScriptedChannel {
+        statuses: Vec<Status>,
+        reads: Cell<usize>,
+        stops: Vec<&'static str>,
+    }
+
+    impl ScriptedChannel {
+        fn new(statuses: Vec<Status>) -> Self {
+            ScriptedChannel {
+                statuses,
+                reads: Cell::new(0),
+                stops: Vec::new(),
+            }
+        }
+    }
+
+    impl Channel for ScriptedChannel {
+        fn index(&self) -> u32 {
+            8
+        }
+
+        fn kind(&self) -> ChannelKind {
+            ChannelKind::Lite
+        }
+
+        fn enable(&mut self) {}
+        fn disable(&mut self) {}
+        fn set_control_block_address(&mut self, _cba: u32) {}
+
+        fn reset(&mut self) {
+            self.stops.push("reset");
+        }
+
+        fn clear_end(&mut self) {}
+        fn clear_error(&mut self) {}
+
+        fn start(&mut self) {
+            self.stops.clear();
+        }
+
+        fn abort(&mut self) {
+            self.stops.push("abort");
+        }
+
+        fn status(&self) -> Status {
+            let read = self.reads.get();
+            self.reads.set(read + 1);
+            self.statuses[read.min(self.statuses.len() - 1)]
+        }
+
+        fn debug(&self) -> DebugInfo {
+            DebugInfo {
+                fifo_error: true,
+                ..DebugInfo::default()
+            }
+        }
+
+        fn control_block_address(&self) -> u32 {
+            0
+        }
+
+        fn clear_debug(&mut self) {}
+    }
+
+    #[test]
+    fn copy_waits_until_the_channel_is_inactive() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let src = GpuMem::alloc(&mailbox, 0x28000).unwrap();
+        let dst = GpuMem::alloc(&mailbox, 0x28000).unwrap();
+
+        // `END` is set after each of the three blocks while the channel is still active.
+        let running = Status {
+            active: true,
+            end: true,
+            ..Status::default()
+        };
+        let done = Status {
+            end: true,
+            ..Status::default()
+        };
+        let mut channel = ScriptedChannel::new(vec![running, running, running, done]);
+
+        copy(&mut channel, &src, &dst, 0x28000, TIMEOUT).unwrap();
+        assert_eq!(channel.reads.get(), 4);
+        assert!(channel.stops.is_empty());
+    }
+
+    #[test]
+    fn failed_copies_stop_the_channel() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let src = GpuMem::alloc(&mailbox, 4096).unwrap();
+        let dst = GpuMem::alloc(&mailbox, 4096).unwrap();
+
+        let failed = Status {
+            active: true,
+            error: true,
+            ..Status::default()
+        };
+        let mut channel = ScriptedChannel::new(vec![failed]);
+
+        let result = copy(&mut channel, &src, &dst, 4096, TIMEOUT);
+        assert!(matches!(result, Err(CopyError::Dma(DmaError::Fifo))));
+        assert_eq!(channel.stops, ["abort", "reset"]);
+    }
+
+    #[test]
+    fn copy_2d_uses_the_strides() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let dma = Peripheral::open_with(&board.platform(), board.memory()).unwrap();
+        let mut channel = dma.channels.take(0).unwrap();
+
+        let src = GpuMem::alloc(&mailbox, 4096).unwrap();
+        let dst = GpuMem::alloc(&mailbox, 4096).unwrap();
+        for i in 0..12 {
+            unsafe { src.memmap().virt.add(i).write_volatile(i as u32 + 1) };
+        }
+
+        with_running_board(&board, || {
+            copy_2d(&mut channel, &src, &dst, 8, 3, 8, 0, TIMEOUT)
+        })
+        .unwrap();
+        assert_eq!(words(&dst, 7), [1, 2, 5, 6, 9, 10, 0]);
+    }
+
+    #[test]
+    fn copy_times_out_without_a_running_engine() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+        let dma = Peripheral::open_with(&board.platform(), board.memory()).unwrap();
+        let mut channel = dma.channels.take(0).unwrap();
+
+        let src = GpuMem::alloc(&mailbox, 4096).unwrap();
+        let dst = GpuMem::alloc(&mailbox, 4096).unwrap();
+        let result = copy(&mut channel, &src, &dst, 4096, Duration::from_millis(10));
+        assert!(matches!(result, Err(CopyError::Timeout)));
+        assert!(!channel.status().active);
+    }
+}
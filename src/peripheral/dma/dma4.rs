@@ -0,0 +1,251 @@
+use crate::{
+    field::{bit, bits, read_bit_field, write_bit_field, Field},
+    mailbox::BUS_ALIAS_MASK,
+};
+
+use super::*;
+
+// DMA4 engines of BCM2711, the channel registers share the layout of the legacy channels.
+pub const DMA4_CS: usize = 0x00;
+pub const DMA4_CB: usize = 0x04;
+pub const DMA4_DEBUG: usize = 0x0C;
+pub const DMA4_TI: usize = 0x10;
+pub const DMA4_SRC: usize = 0x14;
+pub const DMA4_SRCI: usize = 0x18;
+pub const DMA4_DEST: usize = 0x1C;
+pub const DMA4_DESTI: usize = 0x20;
+pub const DMA4_LEN: usize = 0x24;
+pub const DMA4_NEXT_CB: usize = 0x28;
+pub const DMA4_DEBUG2: usize = 0x2C;
+
+pub const DMA4_CS_HALT: Field<u32> = bit(31);
+pub const DMA4_CS_ABORT: Field<u32> = bit(30);
+pub const DMA4_CS_DISDEBUG: Field<u32> = bit(29);
+pub const DMA4_CS_WAIT_FOR_OUTSTANDING_WRITES: Field<u32> = bit(28);
+pub const DMA4_CS_OUTSTANDING_TRANSACTIONS: Field<u32> = bit(25);
+pub const DMA4_CS_DMA_BUSY: Field<u32> = bit(24);
+pub const DMA4_CS_PANIC_QOS: Field<u32> = bits(23, 20);
+pub const DMA4_CS_QOS: Field<u32> = bits(19, 16);
+pub const DMA4_CS_ERROR: Field<u32> = bit(10);
+pub const DMA4_CS_WAITING_FOR_OUTSTANDING_WRITES: Field<u32> = bit(7);
+pub const DMA4_CS_DREQ_STOPS_DMA: Field<u32> = bit(6);
+pub const DMA4_CS_WR_PAUSED: Field<u32> = bit(5);
+pub const DMA4_CS_RD_PAUSED: Field<u32> = bit(4);
+pub const DMA4_CS_DREQ: Field<u32> = bit(3);
+pub const DMA4_CS_INT: Field<u32> = bit(2);
+pub const DMA4_CS_END: Field<u32> = bit(1);
+pub const DMA4_CS_ACTIVE: Field<u32> = bit(0);
+
+pub const DMA4_DEBUG_VERSION: Field<u32> = bits(31, 28);
+pub const DMA4_DEBUG_ID: Field<u32> = bits(27, 24);
+pub const DMA4_DEBUG_RESET: Field<u32> = bit(23);
+pub const DMA4_DEBUG_W_STATE: Field<u32> = bits(22, 18);
+pub const DMA4_DEBUG_R_STATE: Field<u32> = bits(17, 14);
+pub const DMA4_DEBUG_DISABLE_CLK_GATE: Field<u32> = bit(11);
+pub const DMA4_DEBUG_ABORT_ON_ERROR: Field<u32> = bit(10);
+pub const DMA4_DEBUG_HALT_ON_ERROR: Field<u32> = bit(9);
+pub const DMA4_DEBUG_INT_ON_ERROR: Field<u32> = bit(8);
+pub const DMA4_DEBUG_READ_CB_ERROR: Field<u32> = bit(3);
+pub const DMA4_DEBUG_READ_ERROR: Field<u32> = bit(2);
+pub const DMA4_DEBUG_FIFO_ERROR: Field<u32> = bit(1);
+pub const DMA4_DEBUG_WRITE_ERROR: Field<u32> = bit(0);
+
+pub const DMA4_TI_D_WAITS: Field<u32> = bits(31, 24);
+pub const DMA4_TI_S_WAITS: Field<u32> = bits(23, 16);
+pub const DMA4_TI_D_DREQ: Field<u32> = bit(15);
+pub const DMA4_TI_S_DREQ: Field<u32> = bit(14);
+pub const DMA4_TI_PERMAP: Field<u32> = bits(13, 9);
+pub const DMA4_TI_WAIT_RD_RESP: Field<u32> = bit(3);
+pub const DMA4_TI_WAIT_RESP: Field<u32> = bit(2);
+pub const DMA4_TI_TDMODE: Field<u32> = bit(1);
+pub const DMA4_TI_INTEN: Field<u32> = bit(0);
+
+// Layout of the SRCI and DESTI words.
+pub const DMA4_I_STRIDE: Field<u32> = bits(31, 16);
+pub const DMA4_I_IGNORE: Field<u32> = bit(15);
+pub const DMA4_I_SIZE: Field<u32> = bits(14, 13);
+pub const DMA4_I_INC: Field<u32> = bit(12);
+pub const DMA4_I_BURST_LENGTH: Field<u32> = bits(11, 8);
+pub const DMA4_I_ADDR: Field<u32> = bits(7, 0);
+
+pub const DMA4_LEN_YLENGTH: Field<u32> = bits(29, 16);
+pub const DMA4_LEN_XLENGTH: Field<u32> = bits(15, 0);
+
+/// Base of the legacy peripherals in the 35 bit address space seen by DMA4 engines.
+pub const DMA4_PERIPHERAL_BASE: u64 = 0x4_0000_0000;
+
+/// Converts a legacy bus address to the address a DMA4 engine uses.
+pub fn dma4_address(bus: u32) -> u64 {
+    if bus & 0xFF000000 == 0x7E000000 {
+        DMA4_PERIPHERAL_BASE | bus as u64
+    } else {
+        (bus & !BUS_ALIAS_MASK) as u64
+    }
+}
+
+/// Converts a DMA4 address back to a legacy bus address, RAM uses the uncached alias.
+pub fn dma4_bus_address(address: u64) -> u32 {
+    if address >= DMA4_PERIPHERAL_BASE {
+        address as u32
+    } else {
+        address as u32 | 0xC0000000
+    }
+}
+
+/// A control block in the format read by DMA4 engines.
+#[repr(C, align(32))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dma4ControlBlock {
+    pub ti: u32,
+    pub src: u32,
+    pub srci: u32,
+    pub dest: u32,
+    pub desti: u32,
+    pub len: u32,
+    pub next_cb: u32,
+    pub reserved: u32,
+}
+
+impl Dma4ControlBlock {
+    /// Translates a legacy control block, its addresses are legacy bus addresses.
+    pub fn from_legacy(block: &ControlBlock) -> Self {
+        let ti = block.ti;
+        let flag = |field| read_bit_field(ti, field) == 1;
+
+        let mut dma4_ti = 0;
+        write_bit_field(&mut dma4_ti, DMA4_TI_D_DREQ, flag(DMA_TI_DEST_DREQ));
+        write_bit_field(&mut dma4_ti, DMA4_TI_S_DREQ, flag(DMA_TI_SRC_DREQ));
+        write_bit_field(
+            &mut dma4_ti,
+            DMA4_TI_PERMAP,
+            read_bit_field(ti, DMA_TI_PERMAP),
+        );
+        write_bit_field(
+            &mut dma4_ti,
+            DMA4_TI_D_WAITS,
+            read_bit_field(ti, DMA_TI_WAITS),
+        );
+        write_bit_field(&mut dma4_ti, DMA4_TI_WAIT_RESP, flag(DMA_TI_WAIT_RESP));
+        write_bit_field(&mut dma4_ti, DMA4_TI_TDMODE, flag(DMA_TI_TDMODE));
+        write_bit_field(&mut dma4_ti, DMA4_TI_INTEN, flag(DMA_TI_INTEN));
+
+        let (src_stride, dest_stride) = if flag(DMA_TI_TDMODE) {
+            (
+                read_bit_field(block.stride, DMA_CB_STRIDE_S_STRIDE),
+                read_bit_field(block.stride, DMA_CB_STRIDE_D_STRIDE),
+            )
+        } else {
+            (0, 0)
+        };
+
+        let burst_length = read_bit_field(ti, DMA_TI_BURST_LENGTH);
+        let info = |address: u64, stride, ignore, width_128, inc| {
+            let mut info = 0;
+            write_bit_field(&mut info, DMA4_I_STRIDE, stride);
+            write_bit_field(&mut info, DMA4_I_IGNORE, ignore);
+            // SIZE 0 is 32 bit, 2 is 128 bit wide accesses.
+            write_bit_field(&mut info, DMA4_I_SIZE, if width_128 { 2u32 } else { 0u32 });
+            write_bit_field(&mut info, DMA4_I_INC, inc);
+            write_bit_field(&mut info, DMA4_I_BURST_LENGTH, burst_length);
+            write_bit_field(&mut info, DMA4_I_ADDR, (address >> 32) as u32);
+            info
+        };
+
+        let src = dma4_address(block.source_ad);
+        let dest = dma4_address(block.dest_ad);
+
+        Self {
+            ti: dma4_ti,
+            src: src as u32,
+            srci: info(
+                src,
+                src_stride,
+                flag(DMA_TI_SRC_IGNORE),
+                flag(DMA_TI_SRC_WIDTH),
+                flag(DMA_TI_SRC_INC),
+            ),
+            dest: dest as u32,
+            desti: info(
+                dest,
+                dest_stride,
+                flag(DMA_TI_DEST_IGNORE),
+                flag(DMA_TI_DEST_WIDTH),
+                flag(DMA_TI_DEST_INC),
+            ),
+            // The 2D layout of LEN matches TXFR_LEN.
+            len: block.txfr_len,
+            next_cb: match block.nextconbk {
+                0 => 0,
+                next => (dma4_address(next) >> 5) as u32,
+            },
+            reserved: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_of_ram_and_peripherals() {
+        assert_eq!(dma4_address(0xC800_1000), 0x0800_1000);
+        assert_eq!(dma4_address(0x0800_1000), 0x0800_1000);
+        assert_eq!(dma4_address(0x7E60_000C), 0x4_7E60_000C);
+
+        assert_eq!(dma4_bus_address(0x0800_1000), 0xC800_1000);
+        assert_eq!(dma4_bus_address(0x4_7E60_000C), 0x7E60_000C);
+    }
+
+    #[test]
+    fn legacy_blocks_are_translated() {
+        let ti = TransferInfo::new()
+            .dest_dreq(true)
+            .permap(DMA_PERMAP_SMI)
+            .waits(2)
+            .wait_resp(true)
+            .inten(true)
+            .src_inc(true)
+            .burst_length(3)
+            .dest_width_128(true);
+        let block = ControlBlock {
+            nextconbk: 0xC800_1020,
+            ..ControlBlock::new(ti, 0xC800_1000, 0x7E60_000C, 256)
+        };
+
+        assert_eq!(
+            Dma4ControlBlock::from_legacy(&block),
+            Dma4ControlBlock {
+                ti: 2 << 24 | 1 << 15 | 4 << 9 | 1 << 2 | 1,
+                src: 0x0800_1000,
+                srci: 1 << 12 | 3 << 8,
+                dest: 0x7E60_000C,
+                desti: 2 << 13 | 3 << 8 | 0x4,
+                len: 256,
+                next_cb: 0x0800_1020 >> 5,
+                reserved: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn strides_are_only_kept_in_2d_mode() {
+        let ti = TransferInfo::new().src_dreq(true).dest_ignore(true);
+        let block = ControlBlock::new_2d(ti, 0xC800_1000, 0xC800_2000, 16, 4, -8, 4);
+
+        let dma4 = Dma4ControlBlock::from_legacy(&block);
+        assert_eq!(dma4.ti, 1 << 14 | 1 << 1);
+        assert_eq!(dma4.srci, 0xfff8 << 16);
+        assert_eq!(dma4.desti, 4 << 16 | 1 << 15);
+        assert_eq!(dma4.len, 4 << 16 | 16);
+        assert_eq!(dma4.next_cb, 0);
+
+        let block = ControlBlock {
+            ti: block.transfer_info().tdmode(false).value(),
+            ..block
+        };
+        let dma4 = Dma4ControlBlock::from_legacy(&block);
+        assert_eq!(dma4.srci, 0);
+        assert_eq!(dma4.desti, 1 << 15);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{error, fmt, fs, io, path::Path};
+
+use crate::mailbox::Mailbox;
+
+use super::*;
+
+pub const SYSFS_DMA_PATH: &str = "sys/class/dma";
+pub const DMA_CHANNEL_MASK_PROPERTY: &str = "brcm,dma-channel-mask";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelOwner {
+    Free,
+    /// Claimed by a kernel driver through the DMA engine.
+    Kernel,
+    /// Used by the VideoCore firmware.
+    Firmware,
+}
+
+/// Which DMA channels are used by the firmware and the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelReservations {
+    arm_mask: u32,
+    kernel_mask: u32,
+}
+
+impl ChannelReservations {
+    /// Queries the firmware and reads the channels claimed by kernel drivers from sysfs.
+    pub fn detect(mailbox: &Mailbox) -> Result<Self, io::Error> {
+        Self::detect_in("/", mailbox)
+    }
+
+    /// Same as [`ChannelReservations::detect`], but reads sysfs below `root`.
+    ///
+    /// The device tree channel mask only lists the channels the kernel DMA driver may hand
+    /// out, usually all channels the firmware leaves to the ARM. A channel is only owned by
+    /// the kernel once a driver requested it, which is shown by the `in_use` attribute of
+    /// its `dma<controller>chan<n>` device. Without these devices no channel is owned by
+    /// the kernel.
+    pub fn detect_in(root: impl AsRef<Path>, mailbox: &Mailbox) -> Result<Self, io::Error> {
+        let arm_mask = mailbox.dma_channels()?;
+        let kernel_mask = kernel_channels_in(root)?;
+        Ok(Self::from_masks(arm_mask, kernel_mask))
+    }
+
+    /// `arm_mask` are the channels the firmware leaves to the ARM, `kernel_mask` the ones
+    /// claimed by kernel drivers.
+    pub fn from_masks(arm_mask: u32, kernel_mask: u32) -> Self {
+        Self {
+            arm_mask,
+            kernel_mask,
+        }
+    }
+
+    pub fn owner(&self, index: u32) -> ChannelOwner {
+        if self.arm_mask & (1 << index) == 0 {
+            ChannelOwner::Firmware
+        } else if self.kernel_mask & (1 << index) != 0 {
+            ChannelOwner::Kernel
+        } else {
+            ChannelOwner::Free
+        }
+    }
+
+    pub fn is_free(&self, index: u32) -> bool {
+        self.owner(index) == ChannelOwner::Free
+    }
+
+    /// Returns the indices of all free channels.
+    pub fn free_channels(&self) -> Vec<u32> {
+        (0..DMA_CHANNEL_COUNT)
+            .filter(|&index| self.is_free(index))
+            .collect()
+    }
+}
+
+/// Returns the mask of the channels in use by kernel drivers.
+fn kernel_channels_in(root: impl AsRef<Path>) -> Result<u32, io::Error> {
+    let class = match fs::read_dir(root.as_ref().join(SYSFS_DMA_PATH)) {
+        Ok(class) => class,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut kernel_mask = 0;
+    for entry in class {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(chan) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("dma"))
+            .and_then(|name| name.split_once("chan"))
+            .and_then(|(_, chan)| chan.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        if fs::read_to_string(entry.path().join("in_use"))?.trim() != "1" {
+            continue;
+        }
+
+        // The driver numbers the channels of a controller in the order of the bits set in its
+        // device tree mask. BCM2711 has a second controller node for the DMA4 channels.
+        let mask = fs::read(
+            entry
+                .path()
+                .join("device/of_node")
+                .join(DMA_CHANNEL_MASK_PROPERTY),
+        )?;
+        let mask: [u8; 4] =
+            mask.get(..4)
+                .and_then(|mask| mask.try_into().ok())
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid DMA channel mask",
+                ))?;
+        let mask = u32::from_be_bytes(mask);
+
+        match (0..DMA_CHANNEL_COUNT)
+            .filter(|index| mask & (1 << index) != 0)
+            .nth(chan as usize)
+        {
+            Some(index) => kernel_mask |= 1 << index,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "DMA channel is not in the channel mask of its controller",
+                ))
+            }
+        }
+    }
+
+    Ok(kernel_mask)
+}
+
+impl Channels {
+    /// Returns `channel` if it is neither used by the firmware nor the kernel.
+    pub fn take_free<C: Channel>(
+        channel: C,
+        reservations: &ChannelReservations,
+    ) -> Result<C, ChannelReservedError> {
+        match reservations.owner(channel.index()) {
+            ChannelOwner::Free => Ok(channel),
+            owner => Err(ChannelReservedError {
+                index: channel.index(),
+                owner,
+            }),
+        }
+    }
+
+    /// Takes the first channel that is free and not already taken.
+    pub fn take_first_free(&self, reservations: &ChannelReservations) -> Option<AnyChannel> {
+        reservations
+            .free_channels()
+            .into_iter()
+            .find_map(|index| self.take(index).ok())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelReservedError {
+    pub index: u32,
+    pub owner: ChannelOwner,
+}
+
+impl fmt::Display for ChannelReservedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let owner = match self.owner {
+            ChannelOwner::Free => "nobody",
+            ChannelOwner::Kernel => "the kernel",
+            ChannelOwner::Firmware => "the firmware",
+        };
+        write!(f, "DMA channel {} is reserved by {owner}", self.index)
+    }
+}
+
+impl error::Error for ChannelReservedError {}
+
+impl From<ChannelReservedError> for io::Error {
+    fn from(err: ChannelReservedError) -> Self {
+        io::Error::new(io::ErrorKind::ResourceBusy, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Soc, sim::Board, test_util::TempDir};
+
+    const FIRMWARE_MASK: [u8; 4] = 0x7f35u32.to_be_bytes();
+
+    fn channel(dir: &TempDir, name: &str, in_use: bool, mask: [u8; 4]) {
+        dir.write(
+            format!("{SYSFS_DMA_PATH}/{name}/in_use"),
+            if in_use { b"1\n" } else { b"0\n" },
+        );
+        dir.write(
+            format!("{SYSFS_DMA_PATH}/{name}/device/of_node/{DMA_CHANNEL_MASK_PROPERTY}"),
+            &mask,
+        );
+    }
+
+    fn detect(dir: &TempDir) -> ChannelReservations {
+        let board = Board::new(Soc::Bcm2837);
+        ChannelReservations::detect_in(dir.path(), &board.mailbox()).unwrap()
+    }
+
+    #[test]
+    fn channels_available_to_the_kernel_are_free() {
+        let dir = TempDir::new();
+        for chan in 0..10 {
+            channel(&dir, &format!("dma0chan{chan}"), false, FIRMWARE_MASK);
+        }
+
+        let reservations = detect(&dir);
+        assert_eq!(
+            reservations.free_channels(),
+            [0, 2, 4, 5, 8, 9, 10, 11, 12, 13, 14]
+        );
+        assert_eq!(reservations.owner(1), ChannelOwner::Firmware);
+    }
+
+    #[test]
+    fn channels_in_use_are_owned_by_the_kernel() {
+        let dir = TempDir::new();
+        channel(&dir, "dma0chan0", true, FIRMWARE_MASK);
+        channel(&dir, "dma0chan1", false, FIRMWARE_MASK);
+        channel(&dir, "dma0chan3", true, FIRMWARE_MASK);
+        channel(&dir, "dma1chan0", true, 0x3000u32.to_be_bytes());
+
+        let reservations = detect(&dir);
+        assert_eq!(reservations.owner(0), ChannelOwner::Kernel);
+        assert_eq!(reservations.owner(2), ChannelOwner::Free);
+        assert_eq!(reservations.owner(5), ChannelOwner::Kernel);
+        assert_eq!(reservations.owner(12), ChannelOwner::Kernel);
+        assert_eq!(reservations.free_channels(), [2, 4, 8, 9, 10, 11, 13, 14]);
+    }
+
+    #[test]
+    fn first_free_channel_is_taken() {
+        let dir = TempDir::new();
+        channel(&dir, "dma0chan0", true, FIRMWARE_MASK);
+
+        let board = Board::new(Soc::Bcm2837);
+        let reservations = ChannelReservations::detect_in(dir.path(), &board.mailbox()).unwrap();
+        let dma = crate::dma::Peripheral::open_with(&board.platform(), board.memory()).unwrap();
+        let channel = dma.channels.take_first_free(&reservations).unwrap();
+        assert_eq!(channel.index(), 2);
+    }
+
+    #[test]
+    fn missing_sysfs_leaves_all_arm_channels_free() {
+        let dir = TempDir::new();
+        assert_eq!(detect(&dir), ChannelReservations::from_masks(0x7f35, 0));
+    }
+}
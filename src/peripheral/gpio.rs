@@ -1,27 +1,35 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use crate::{
-    map_phys_to_virt,
+    mem::{DevMem, Mapping, RegisterBackend},
     platform::{Platform, PAGE_SIZE},
-    unmap_phys_to_virt, MemMap,
 };
 
 pub const GPIO_OFFSET: usize = 0x00200000;
 
 pub struct Peripheral {
-    regs: MemMap,
     pub pins: Pins,
 }
 
 impl Peripheral {
     pub fn open(base: &Platform) -> Result<Peripheral, io::Error> {
-        let bus = base.bus.wrapping_byte_add(GPIO_OFFSET);
-        let phys = base.phys.wrapping_byte_add(GPIO_OFFSET);
-        let virt = unsafe { map_phys_to_virt(phys, PAGE_SIZE) }?;
-        let regs = MemMap { bus, phys, virt };
+        Self::open_with(base, Arc::new(DevMem))
+    }
+
+    pub fn open_with(
+        base: &Platform,
+        backend: Arc<dyn RegisterBackend>,
+    ) -> Result<Peripheral, io::Error> {
+        let regs = Arc::new(unsafe {
+            Mapping::new(
+                backend,
+                base.bus.wrapping_byte_add(GPIO_OFFSET),
+                base.phys.wrapping_byte_add(GPIO_OFFSET),
+                PAGE_SIZE,
+            )
+        }?);
 
         Ok(Peripheral {
-            regs,
             pins: Pins {
                 pin0: Pin0::new(&regs),
                 pin1: Pin1::new(&regs),
@@ -56,12 +64,6 @@ impl Peripheral {
     }
 }
 
-impl Drop for Peripheral {
-    fn drop(&mut self) {
-        unsafe { unmap_phys_to_virt(self.regs.virt, PAGE_SIZE) };
-    }
-}
-
 pub struct Pins {
     pub pin0: Pin0,
     pub pin1: Pin1,
@@ -102,12 +104,12 @@ pub trait Pin {
 macro_rules! pin {
     ($name:ident, $index:expr) => {
         pub struct $name {
-            regs: MemMap,
+            regs: Arc<Mapping>,
         }
 
         impl $name {
-            fn new(regs: &MemMap) -> Self {
-                Self { regs: *regs }
+            fn new(regs: &Arc<Mapping>) -> Self {
+                Self { regs: regs.clone() }
             }
         }
 
@@ -128,9 +130,9 @@ macro_rules! pin {
                     Mode::Alt5 => 0b010u32,
                 };
 
-                let mut gpio = unsafe { self.regs.virt.byte_add(offset).read_volatile() };
+                let mut gpio = self.regs.read(offset);
                 gpio = (gpio & !(0b111 << shift)) | (mode << shift);
-                unsafe { self.regs.virt.byte_add(offset).write_volatile(gpio) };
+                self.regs.write(offset, gpio);
             }
         }
     };
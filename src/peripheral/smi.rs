@@ -1,11 +1,17 @@
-use std::io;
+use std::{io, sync::Arc};
 
+use super::claim::{Claim, Claims};
 use crate::{
     field::{bit, bits, read_bit_field, write_bit_field, Field},
-    mem::{map_phys_to_virt, unmap_phys_to_virt, MemMap},
-    platform::{Platform, PAGE_SIZE},
+    mem::{DevMem, Mapping, RegisterBackend},
+    platform::{Platform, Soc, PAGE_SIZE},
 };
 
+mod pll;
+
+pub use super::{Resource, TakeError};
+pub use pll::*;
+
 pub const SMI_OFFSET: usize = 0x00600000;
 
 pub const SMI_CS: usize = 0x00;
@@ -22,6 +28,8 @@ pub const SMI_DSW2: usize = 0x24;
 pub const SMI_DSR3: usize = 0x28;
 pub const SMI_DSW3: usize = 0x2C;
 
+pub const SMI_DEVICE_COUNT: u32 = 4;
+
 pub const SMI_DC: usize = 0x30;
 
 pub const SMI_DCS: usize = 0x34;
@@ -104,92 +112,119 @@ pub const SMI_CLOCK_DIV_DIVI: Field<u32> = bits(23, 12);
 pub const SMI_CLOCK_DIV_DIVF: Field<u32> = bits(11, 0);
 
 pub struct Peripheral {
-    regs: MemMap,
     pub controller: Controller,
     pub devices: Devices,
 }
 
 impl Peripheral {
     pub fn open(base: &Platform) -> Result<Peripheral, io::Error> {
-        let bus = base.bus.wrapping_byte_add(SMI_OFFSET);
-        let phys = base.phys.wrapping_byte_add(SMI_OFFSET);
-        let virt = unsafe { map_phys_to_virt(phys, PAGE_SIZE) }?;
-        let regs = MemMap { bus, phys, virt };
-
-        let clock_bus = base.bus.wrapping_byte_add(SMI_CLOCK_OFFSET);
-        let clock_phys = base.phys.wrapping_byte_add(SMI_CLOCK_OFFSET);
-        let clock_virt = unsafe { map_phys_to_virt(clock_phys, PAGE_SIZE) }?;
-        let clock_regs = MemMap {
-            bus: clock_bus,
-            phys: clock_phys,
-            virt: clock_virt,
-        };
+        Self::open_with(base, Arc::new(DevMem))
+    }
+
+    pub fn open_with(
+        base: &Platform,
+        backend: Arc<dyn RegisterBackend>,
+    ) -> Result<Peripheral, io::Error> {
+        let regs = Arc::new(unsafe {
+            Mapping::new(
+                backend.clone(),
+                base.bus.wrapping_byte_add(SMI_OFFSET),
+                base.phys.wrapping_byte_add(SMI_OFFSET),
+                PAGE_SIZE,
+            )
+        }?);
+
+        let clock_regs = Arc::new(unsafe {
+            Mapping::new(
+                backend.clone(),
+                base.bus.wrapping_byte_add(SMI_CLOCK_OFFSET),
+                base.phys.wrapping_byte_add(SMI_CLOCK_OFFSET),
+                PAGE_SIZE,
+            )
+        }?);
+
+        let pll_regs = Arc::new(unsafe {
+            Mapping::new(
+                backend,
+                base.bus.wrapping_byte_add(PLL_OFFSET),
+                base.phys.wrapping_byte_add(PLL_OFFSET),
+                PAGE_SIZE,
+            )
+        }?);
 
         Ok(Peripheral {
-            regs,
-            controller: Controller { regs, clock_regs },
+            controller: Controller {
+                regs: regs.clone(),
+                clock_regs,
+                pll_regs,
+                soc: base.soc,
+                clock_source: ClockSource::PllD,
+            },
             devices: Devices {
-                device0: Device0 {
-                    dsr_virt: regs.virt.wrapping_byte_add(SMI_DSR0),
-                    dsw_virt: regs.virt.wrapping_byte_add(SMI_DSW0),
-                },
-                device1: Device1 {
-                    dsr_virt: regs.virt.wrapping_byte_add(SMI_DSR1),
-                    dsw_virt: regs.virt.wrapping_byte_add(SMI_DSW1),
-                },
-                device2: Device2 {
-                    dsr_virt: regs.virt.wrapping_byte_add(SMI_DSR2),
-                    dsw_virt: regs.virt.wrapping_byte_add(SMI_DSW2),
-                },
-                device3: Device3 {
-                    dsr_virt: regs.virt.wrapping_byte_add(SMI_DSR3),
-                    dsw_virt: regs.virt.wrapping_byte_add(SMI_DSW3),
-                },
+                regs,
+                claims: Claims::new(Resource::SmiDevice, SMI_DEVICE_COUNT),
             },
         })
     }
 }
 
-impl Drop for Peripheral {
-    fn drop(&mut self) {
-        unsafe { unmap_phys_to_virt(self.regs.virt, PAGE_SIZE) };
-    }
-}
-
 pub struct Controller {
-    pub(crate) regs: MemMap,
-    clock_regs: MemMap,
+    pub(crate) regs: Arc<Mapping>,
+    clock_regs: Arc<Mapping>,
+    pll_regs: Arc<Mapping>,
+    soc: Soc,
+    clock_source: ClockSource,
 }
 
 impl Controller {
-    pub fn select<D: Device>(&mut self, _device: &D) {
-        let mut a = unsafe { self.regs.virt.byte_add(SMI_A).read_volatile() };
-        write_bit_field(&mut a, SMI_A_DEVICE, D::INDEX);
-        unsafe { self.regs.virt.byte_add(SMI_A).write_volatile(a) };
+    /// Selects the source used by [`Controller::set_clock_divisor`], PLLD by default.
+    ///
+    /// Fails for sources whose rate can't be read from the PLL registers. PLLC also drives
+    /// the core clock, pin it with a [`ClockGuard`](crate::ClockGuard) on
+    /// [`ClockId::Core`](crate::ClockId::Core) and pass it to
+    /// [`Transfer::configure`](crate::batch::Transfer::configure).
+    pub fn set_clock_source(&mut self, source: ClockSource) -> Result<(), io::Error> {
+        if clock_source_rate(self.soc, source, |offset| self.pll_regs.read(offset)).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the rate of clock source {source:?} is unknown"),
+            ));
+        }
+
+        self.clock_source = source;
+        Ok(())
+    }
+
+    pub fn clock_source(&self) -> ClockSource {
+        self.clock_source
+    }
+
+    /// Returns the rate of the clock source in Hz, read from the PLL registers so changes
+    /// made by the firmware are picked up. Returns `None` if the source is stopped.
+    pub fn clock_rate(&self) -> Option<u32> {
+        clock_source_rate(self.soc, self.clock_source, |offset| {
+            self.pll_regs.read(offset)
+        })
+    }
+
+    pub fn select<D: Device>(&mut self, device: &D) {
+        let mut a = self.regs.read(SMI_A);
+        write_bit_field(&mut a, SMI_A_DEVICE, device.index());
+        self.regs.write(SMI_A, a);
     }
 
     pub fn set_clock_divisor(&mut self, divisor: u16) {
         let mut ctl = 0;
         write_bit_field(&mut ctl, SMI_CLOCK_CTL_PASSWD, SMI_CLOCK_PASSWD);
-        unsafe {
-            self.clock_regs
-                .virt
-                .byte_add(SMI_CLOCK_CTL)
-                .write_volatile(ctl)
-        };
+        self.clock_regs.write(SMI_CLOCK_CTL, ctl);
 
         let mut ctl = 0;
         write_bit_field(&mut ctl, SMI_CLOCK_CTL_PASSWD, SMI_CLOCK_PASSWD);
         write_bit_field(&mut ctl, SMI_CLOCK_CTL_KILL, true);
-        unsafe {
-            self.clock_regs
-                .virt
-                .byte_add(SMI_CLOCK_CTL)
-                .write_volatile(ctl)
-        };
+        self.clock_regs.write(SMI_CLOCK_CTL, ctl);
 
         loop {
-            let ctl = unsafe { self.clock_regs.virt.byte_add(SMI_CLOCK_CTL).read_volatile() };
+            let ctl = self.clock_regs.read(SMI_CLOCK_CTL);
             if read_bit_field(ctl, SMI_CLOCK_CTL_BUSY) == 0 {
                 break;
             }
@@ -198,26 +233,16 @@ impl Controller {
         let mut div = 0;
         write_bit_field(&mut div, SMI_CLOCK_DIV_PASSWD, SMI_CLOCK_PASSWD);
         write_bit_field(&mut div, SMI_CLOCK_DIV_DIVI, divisor);
-        unsafe {
-            self.clock_regs
-                .virt
-                .byte_add(SMI_CLOCK_DIV)
-                .write_volatile(div)
-        };
+        self.clock_regs.write(SMI_CLOCK_DIV, div);
 
         let mut ctl = 0;
         write_bit_field(&mut ctl, SMI_CLOCK_CTL_PASSWD, SMI_CLOCK_PASSWD);
-        write_bit_field(&mut ctl, SMI_CLOCK_CTL_SRC, 6u32);
+        write_bit_field(&mut ctl, SMI_CLOCK_CTL_SRC, self.clock_source.value());
         write_bit_field(&mut ctl, SMI_CLOCK_CTL_ENAB, true);
-        unsafe {
-            self.clock_regs
-                .virt
-                .byte_add(SMI_CLOCK_CTL)
-                .write_volatile(ctl)
-        };
+        self.clock_regs.write(SMI_CLOCK_CTL, ctl);
 
         loop {
-            let ctl = unsafe { self.clock_regs.virt.byte_add(SMI_CLOCK_CTL).read_volatile() };
+            let ctl = self.clock_regs.read(SMI_CLOCK_CTL);
             if read_bit_field(ctl, SMI_CLOCK_CTL_BUSY) == 1 {
                 break;
             }
@@ -225,7 +250,7 @@ impl Controller {
     }
 
     pub fn set_dir(&mut self, dir: TransferDir) {
-        let mut cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let mut cs = self.regs.read(SMI_CS);
         write_bit_field(
             &mut cs,
             SMI_CS_WRITE,
@@ -234,46 +259,46 @@ impl Controller {
                 TransferDir::Read => false,
             },
         );
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn enable(&mut self) {
-        let mut cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let mut cs = self.regs.read(SMI_CS);
         write_bit_field(&mut cs, SMI_CS_ENABLE, true);
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn disable(&mut self) {
-        let mut cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let mut cs = self.regs.read(SMI_CS);
         write_bit_field(&mut cs, SMI_CS_ENABLE, false);
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn active(&self) -> bool {
-        let cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let cs = self.regs.read(SMI_CS);
         read_bit_field(cs, SMI_CS_ACTIVE) == 1
     }
 
     pub fn start(&mut self) {
-        let mut cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let mut cs = self.regs.read(SMI_CS);
         write_bit_field(&mut cs, SMI_CS_START, true);
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn clear(&mut self) {
-        let mut cs = unsafe { self.regs.virt.byte_add(SMI_CS).read_volatile() };
+        let mut cs = self.regs.read(SMI_CS);
         write_bit_field(&mut cs, SMI_CS_CLEAR, true);
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn zero(&mut self) {
         let cs = 0;
-        unsafe { self.regs.virt.byte_add(SMI_CS).write_volatile(cs) };
+        self.regs.write(SMI_CS, cs);
     }
 
     pub fn zero_direct(&mut self) {
         let dcs = 0;
-        unsafe { self.regs.virt.byte_add(SMI_DCS).write_volatile(dcs) };
+        self.regs.write(SMI_DCS, dcs);
     }
 
     pub fn set_control(&mut self, control: &Control) {
@@ -284,76 +309,172 @@ impl Controller {
         write_bit_field(&mut dc, SMI_DC_PANICW, control.write_panic_threshold);
         write_bit_field(&mut dc, SMI_DC_REQR, control.read_dreq_threshold);
         write_bit_field(&mut dc, SMI_DC_REQW, control.write_dreq_threshold);
-        unsafe { self.regs.virt.byte_add(SMI_DC).write_volatile(dc) };
+        self.regs.write(SMI_DC, dc);
     }
 
     pub fn set_length(&mut self, length: u32) {
-        unsafe { self.regs.virt.byte_add(SMI_L).write_volatile(length) };
+        self.regs.write(SMI_L, length);
     }
 }
 
+/// All devices of the SMI controller, each of which can be taken once at a time.
+///
+/// The typed devices used to be public fields of this struct, they are now taken with the
+/// `deviceN` methods. [`Devices::take_all`] still returns them as fields.
 pub struct Devices {
-    pub device0: Device0,
-    pub device1: Device1,
-    pub device2: Device2,
-    pub device3: Device3,
+    regs: Arc<Mapping>,
+    claims: Claims,
 }
 
-pub trait Device {
+impl Devices {
+    /// Takes the device at `index`, which can't be taken again until the returned device
+    /// is dropped.
+    pub fn take(&self, index: u32) -> Result<AnyDevice, TakeError> {
+        Ok(AnyDevice {
+            regs: self.regs.clone(),
+            index,
+            _claim: self.claims.take(index)?,
+        })
+    }
+}
+
+macro_rules! typed_devices {
+    ($($method:ident: $name:ident),* $(,)?) => {
+        impl Devices {
+            $(
+                /// Takes the device like [`Devices::take`], with its index in the type.
+                pub fn $method(&self) -> Result<$name, TakeError> {
+                    Ok($name {
+                        device: self.take($name::INDEX)?,
+                    })
+                }
+            )*
+
+            /// Takes every device, fails if one of them is already taken.
+            #[deprecated(note = "take the devices that are used with `take` or `deviceN`")]
+            pub fn take_all(&self) -> Result<AllDevices, TakeError> {
+                Ok(AllDevices {
+                    $($method: self.$method()?,)*
+                })
+            }
+        }
+
+        /// Every typed device, as returned by [`Devices::take_all`].
+        pub struct AllDevices {
+            $(pub $method: $name,)*
+        }
+    };
+}
+
+typed_devices! {
+    device0: Device0,
+    device1: Device1,
+    device2: Device2,
+    device3: Device3,
+}
+
+/// Devices with their index in the type.
+///
+/// `INDEX` used to be part of [`Device`], which also covers devices selected at runtime.
+pub trait IndexedDevice: Device {
     const INDEX: u32;
+}
+
+pub trait Device {
+    fn index(&self) -> u32;
 
     fn set_read_settings(&mut self, settings: &ReadSettings);
     fn set_write_settings(&mut self, settings: &WriteSettings);
 }
 
+/// A device selected at runtime.
+pub struct AnyDevice {
+    regs: Arc<Mapping>,
+    index: u32,
+    _claim: Claim,
+}
+
+impl AnyDevice {
+    fn dsr(&self) -> usize {
+        SMI_DSR0 + self.index as usize * (SMI_DSR1 - SMI_DSR0)
+    }
+
+    fn dsw(&self) -> usize {
+        SMI_DSW0 + self.index as usize * (SMI_DSW1 - SMI_DSW0)
+    }
+}
+
+impl Device for AnyDevice {
+    fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn set_read_settings(&mut self, settings: &ReadSettings) {
+        let mut dsr = 0;
+        write_bit_field(
+            &mut dsr,
+            SMI_DSR_RWIDTH,
+            match settings.width {
+                TransferWidth::Bit8 => 0u32,
+                TransferWidth::Bit16 => 1u32,
+                TransferWidth::Bit18 => 2u32,
+                TransferWidth::Bit9 => 3u32,
+            },
+        );
+        write_bit_field(&mut dsr, SMI_DSR_RSETUP, settings.setup);
+        write_bit_field(&mut dsr, SMI_DSR_RSTROBE, settings.strobe);
+        write_bit_field(&mut dsr, SMI_DSR_RHOLD, settings.hold);
+        write_bit_field(&mut dsr, SMI_DSR_RPACE, settings.pace);
+        write_bit_field(&mut dsr, SMI_DSR_RDREQ, settings.dreq);
+        self.regs.write(self.dsr(), dsr);
+    }
+
+    fn set_write_settings(&mut self, settings: &WriteSettings) {
+        let mut dsw = 0;
+        write_bit_field(
+            &mut dsw,
+            SMI_DSW_WWIDTH,
+            match settings.width {
+                TransferWidth::Bit8 => 0u32,
+                TransferWidth::Bit16 => 1u32,
+                TransferWidth::Bit18 => 2u32,
+                TransferWidth::Bit9 => 3u32,
+            },
+        );
+        write_bit_field(&mut dsw, SMI_DSW_WSETUP, settings.setup);
+        write_bit_field(&mut dsw, SMI_DSW_WSTROBE, settings.strobe);
+        write_bit_field(&mut dsw, SMI_DSW_WHOLD, settings.hold);
+        write_bit_field(&mut dsw, SMI_DSW_WPACE, settings.pace);
+        write_bit_field(&mut dsw, SMI_DSW_WDREQ, settings.dreq);
+        self.regs.write(self.dsw(), dsw);
+    }
+}
+
 macro_rules! device {
     ($name:ident, $index:expr) => {
         pub struct $name {
-            dsr_virt: *mut u32,
-            dsw_virt: *mut u32,
+            device: AnyDevice,
         }
 
-        impl Device for $name {
+        impl $name {
+            pub const INDEX: u32 = $index;
+        }
+
+        impl IndexedDevice for $name {
             const INDEX: u32 = $index;
+        }
+
+        impl Device for $name {
+            fn index(&self) -> u32 {
+                $index
+            }
 
             fn set_read_settings(&mut self, settings: &ReadSettings) {
-                let mut dsr = 0;
-                write_bit_field(
-                    &mut dsr,
-                    SMI_DSR_RWIDTH,
-                    match settings.width {
-                        TransferWidth::Bit8 => 0u32,
-                        TransferWidth::Bit16 => 1u32,
-                        TransferWidth::Bit18 => 2u32,
-                        TransferWidth::Bit9 => 3u32,
-                    },
-                );
-                write_bit_field(&mut dsr, SMI_DSR_RSETUP, settings.setup);
-                write_bit_field(&mut dsr, SMI_DSR_RSTROBE, settings.strobe);
-                write_bit_field(&mut dsr, SMI_DSR_RHOLD, settings.hold);
-                write_bit_field(&mut dsr, SMI_DSR_RPACE, settings.pace);
-                write_bit_field(&mut dsr, SMI_DSR_RDREQ, settings.dreq);
-                unsafe { self.dsr_virt.write_volatile(dsr) };
+                self.device.set_read_settings(settings)
             }
 
             fn set_write_settings(&mut self, settings: &WriteSettings) {
-                let mut dsw = 0;
-                write_bit_field(
-                    &mut dsw,
-                    SMI_DSW_WWIDTH,
-                    match settings.width {
-                        TransferWidth::Bit8 => 0u32,
-                        TransferWidth::Bit16 => 1u32,
-                        TransferWidth::Bit18 => 2u32,
-                        TransferWidth::Bit9 => 3u32,
-                    },
-                );
-                write_bit_field(&mut dsw, SMI_DSW_WSETUP, settings.setup);
-                write_bit_field(&mut dsw, SMI_DSW_WSTROBE, settings.strobe);
-                write_bit_field(&mut dsw, SMI_DSW_WHOLD, settings.hold);
-                write_bit_field(&mut dsw, SMI_DSW_WPACE, settings.pace);
-                write_bit_field(&mut dsw, SMI_DSW_WDREQ, settings.dreq);
-                unsafe { self.dsw_virt.write_volatile(dsw) };
+                self.device.set_write_settings(settings)
             }
         }
     };
@@ -398,6 +519,7 @@ pub enum TransferDir {
     Read,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ClockSource {
     Gnd,
     Oscillator,
@@ -407,6 +529,31 @@ pub enum ClockSource {
     HdmiAuxiliary,
 }
 
+impl ClockSource {
+    pub fn from_value(value: u32) -> Option<ClockSource> {
+        match value {
+            0 => Some(ClockSource::Gnd),
+            1 => Some(ClockSource::Oscillator),
+            4 => Some(ClockSource::PllA),
+            5 => Some(ClockSource::PllC),
+            6 => Some(ClockSource::PllD),
+            7 => Some(ClockSource::HdmiAuxiliary),
+            _ => None,
+        }
+    }
+
+    pub fn value(self) -> u32 {
+        match self {
+            ClockSource::Gnd => 0,
+            ClockSource::Oscillator => 1,
+            ClockSource::PllA => 4,
+            ClockSource::PllC => 5,
+            ClockSource::PllD => 6,
+            ClockSource::HdmiAuxiliary => 7,
+        }
+    }
+}
+
 device!(Device0, 0);
 device!(Device1, 1);
 device!(Device2, 2);
@@ -0,0 +1,159 @@
+use crate::{
+    field::{bit, bits, read_bit_field, Field},
+    platform::Soc,
+};
+
+use super::ClockSource;
+
+/// Offset of the PLL (A2W) registers of the clock manager.
+pub const PLL_OFFSET: usize = 0x00102000;
+
+pub const A2W_PLLA_ANA1: usize = 0x014;
+pub const A2W_PLLC_ANA1: usize = 0x034;
+pub const A2W_PLLD_ANA1: usize = 0x054;
+pub const A2W_PLLA_CTRL: usize = 0x100;
+pub const A2W_PLLC_CTRL: usize = 0x120;
+pub const A2W_PLLD_CTRL: usize = 0x140;
+pub const A2W_PLLA_FRAC: usize = 0x200;
+pub const A2W_PLLC_FRAC: usize = 0x220;
+pub const A2W_PLLD_FRAC: usize = 0x240;
+pub const A2W_PLLA_PER: usize = 0x500;
+pub const A2W_PLLC_PER: usize = 0x520;
+pub const A2W_PLLD_PER: usize = 0x540;
+
+pub const A2W_PLL_ANA1_FB_PREDIV: Field<u32> = bit(14);
+
+pub const A2W_PLL_CTRL_PWRDN: Field<u32> = bit(16);
+pub const A2W_PLL_CTRL_PDIV: Field<u32> = bits(14, 12);
+pub const A2W_PLL_CTRL_NDIV: Field<u32> = bits(9, 0);
+
+pub const A2W_PLL_FRAC: Field<u32> = bits(19, 0);
+const A2W_PLL_FRAC_BITS: u32 = 20;
+
+pub const A2W_PLL_CHANNEL_DISABLE: Field<u32> = bit(8);
+pub const A2W_PLL_CHANNEL_DIV: Field<u32> = bits(7, 0);
+
+/// Returns the rate of `source` in Hz, computed from the PLL registers returned by `read`.
+///
+/// Returns `None` for sources without a known rate and for stopped PLLs.
+pub(crate) fn clock_source_rate(
+    soc: Soc,
+    source: ClockSource,
+    read: impl Fn(usize) -> u32,
+) -> Option<u32> {
+    let (ana1, ctrl, frac, per) = match source {
+        ClockSource::Oscillator => return Some(soc.oscillator_rate()),
+        ClockSource::PllA => (A2W_PLLA_ANA1, A2W_PLLA_CTRL, A2W_PLLA_FRAC, A2W_PLLA_PER),
+        ClockSource::PllC => (A2W_PLLC_ANA1, A2W_PLLC_CTRL, A2W_PLLC_FRAC, A2W_PLLC_PER),
+        ClockSource::PllD => (A2W_PLLD_ANA1, A2W_PLLD_CTRL, A2W_PLLD_FRAC, A2W_PLLD_PER),
+        ClockSource::Gnd | ClockSource::HdmiAuxiliary => return None,
+    };
+
+    let ctrl = read(ctrl);
+    let per = read(per);
+    let pdiv = read_bit_field(ctrl, A2W_PLL_CTRL_PDIV) as u64;
+    if read_bit_field(ctrl, A2W_PLL_CTRL_PWRDN) == 1
+        || pdiv == 0
+        || read_bit_field(per, A2W_PLL_CHANNEL_DISABLE) == 1
+    {
+        return None;
+    }
+
+    // The multiplier is a fixed point number with 20 fractional bits, doubled by the
+    // feedback predivider.
+    let mut multiplier = ((read_bit_field(ctrl, A2W_PLL_CTRL_NDIV) as u64) << A2W_PLL_FRAC_BITS)
+        | read_bit_field(read(frac), A2W_PLL_FRAC) as u64;
+    if read_bit_field(read(ana1), A2W_PLL_ANA1_FB_PREDIV) == 1 {
+        multiplier *= 2;
+    }
+
+    // A channel divisor of zero divides by 256.
+    let div = match read_bit_field(per, A2W_PLL_CHANNEL_DIV) {
+        0 => 256,
+        div => div as u64,
+    };
+
+    let rate = ((soc.oscillator_rate() as u64 * multiplier) >> A2W_PLL_FRAC_BITS) / pdiv / div;
+    u32::try_from(rate).ok().filter(|&rate| rate != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::field::write_bit_field;
+
+    fn plld(ndiv: u32, frac: u32, per: u32) -> HashMap<usize, u32> {
+        let mut ctrl = 0;
+        write_bit_field(&mut ctrl, A2W_PLL_CTRL_PDIV, 1u32);
+        write_bit_field(&mut ctrl, A2W_PLL_CTRL_NDIV, ndiv);
+        HashMap::from([
+            (A2W_PLLD_CTRL, ctrl),
+            (A2W_PLLD_FRAC, frac),
+            (A2W_PLLD_PER, per),
+        ])
+    }
+
+    fn rate(soc: Soc, source: ClockSource, regs: &HashMap<usize, u32>) -> Option<u32> {
+        clock_source_rate(soc, source, |offset| {
+            regs.get(&offset).copied().unwrap_or(0)
+        })
+    }
+
+    #[test]
+    fn plld_rate() {
+        let regs = plld(104, 0x2AAAB, 4);
+        assert_eq!(
+            rate(Soc::Bcm2837, ClockSource::PllD, &regs),
+            Some(500_000_001)
+        );
+
+        let regs = plld(55, 0x8E38E, 4);
+        assert_eq!(
+            rate(Soc::Bcm2711, ClockSource::PllD, &regs),
+            Some(749_999_997)
+        );
+    }
+
+    #[test]
+    fn predivider_and_channel_divisor() {
+        let mut regs = plld(52, 0x15555, 2);
+        assert_eq!(
+            rate(Soc::Bcm2835, ClockSource::PllD, &regs),
+            Some(499_999_996)
+        );
+
+        regs.insert(A2W_PLLD_ANA1, 1 << 14);
+        assert_eq!(
+            rate(Soc::Bcm2835, ClockSource::PllD, &regs),
+            Some(999_999_993)
+        );
+
+        regs.insert(A2W_PLLD_PER, 0);
+        assert_eq!(
+            rate(Soc::Bcm2835, ClockSource::PllD, &regs),
+            Some(7_812_499)
+        );
+    }
+
+    #[test]
+    fn unknown_rates() {
+        let regs = plld(104, 0x2AAAB, 4);
+        assert_eq!(rate(Soc::Bcm2837, ClockSource::PllC, &regs), None);
+        assert_eq!(rate(Soc::Bcm2837, ClockSource::Gnd, &regs), None);
+        assert_eq!(rate(Soc::Bcm2837, ClockSource::HdmiAuxiliary, &regs), None);
+        assert_eq!(
+            rate(Soc::Bcm2711, ClockSource::Oscillator, &regs),
+            Some(54_000_000)
+        );
+
+        let mut stopped = regs.clone();
+        stopped.insert(A2W_PLLD_PER, 4 | 1 << 8);
+        assert_eq!(rate(Soc::Bcm2837, ClockSource::PllD, &stopped), None);
+
+        let mut stopped = regs;
+        stopped.insert(A2W_PLLD_CTRL, stopped[&A2W_PLLD_CTRL] | 1 << 16);
+        assert_eq!(rate(Soc::Bcm2837, ClockSource::PllD, &stopped), None);
+    }
+}
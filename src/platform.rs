@@ -1,36 +1,414 @@
+use std::{error, fmt, fs, io, path::Path};
+
+use crate::mailbox::{BUS_ALIAS_MASK, MEM_FLAG_DIRECT, MEM_FLAG_L1_NONALLOCATING};
+
 pub const RASPBERRY_PI_ZERO_1: Platform = Platform {
     phys: 0x20000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2835,
 };
 
 pub const RASPBERRY_PI_ZERO_2: Platform = Platform {
     phys: 0x3F000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2837,
 };
 
 pub const RASPBERRY_PI_1: Platform = Platform {
     phys: 0x20000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2835,
 };
 
 pub const RASPBERRY_PI_2: Platform = Platform {
     phys: 0x3F000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2836,
 };
 
 pub const RASPBERRY_PI_3: Platform = Platform {
     phys: 0x3F000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2837,
 };
 
 pub const RASPBERRY_PI_4: Platform = Platform {
     phys: 0xFE000000 as *mut u32,
     bus: 0x7E000000 as *mut u32,
+    soc: Soc::Bcm2711,
 };
 
 pub const PAGE_SIZE: usize = 0x1000;
 
+/// Address of the peripherals on the VideoCore bus, the same on every SoC.
+pub const PERIPHERAL_BUS_BASE: u32 = 0x7E000000;
+
+pub const DEVICE_TREE_RANGES_PATH: &str = "proc/device-tree/soc/ranges";
+pub const CPUINFO_PATH: &str = "proc/cpuinfo";
+
 pub struct Platform {
     pub bus: *mut u32,
     pub phys: *mut u32,
+    pub soc: Soc,
+}
+
+impl Platform {
+    /// Detects the platform of the running board.
+    pub fn detect() -> Result<Platform, DetectError> {
+        Self::detect_in("/")
+    }
+
+    /// Detects the platform from the `proc` files found below `root`.
+    ///
+    /// The peripheral addresses are read from the device tree. The revision code
+    /// in `cpuinfo` is used to tell apart SoCs that share the same addresses, and
+    /// as a fallback when the device tree is missing or not recognised.
+    pub fn detect_in(root: impl AsRef<Path>) -> Result<Platform, DetectError> {
+        let root = root.as_ref();
+
+        let revision = match fs::read_to_string(root.join(CPUINFO_PATH)) {
+            Ok(cpuinfo) => parse_cpuinfo_revision(&cpuinfo),
+            Err(_) => None,
+        };
+
+        let platform = fs::read(root.join(DEVICE_TREE_RANGES_PATH))
+            .map_err(DetectError::Io)
+            .and_then(|ranges| Platform::from_device_tree_ranges(&ranges));
+
+        let mut platform = match (platform, revision) {
+            (Ok(platform), _) => platform,
+            // A missing or unknown device tree, the revision code can still identify the board.
+            (Err(_), Some(revision)) => return Platform::from_revision(revision),
+            (Err(err), None) => return Err(err),
+        };
+
+        if let Some(soc) = revision.and_then(|revision| Soc::from_revision(revision).ok()) {
+            if soc.peripheral_base() == platform.phys as u32 {
+                platform.soc = soc;
+            }
+        }
+
+        Ok(platform)
+    }
+
+    /// Parses the raw contents of `/proc/device-tree/soc/ranges`.
+    pub fn from_device_tree_ranges(ranges: &[u8]) -> Result<Platform, DetectError> {
+        let cells = ranges
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+            .collect::<Vec<_>>();
+
+        // BCM2711 uses two cells for the parent address, the upper one is zero.
+        let (bus, phys) = match cells[..] {
+            [bus, 0, phys, _, ..] => (bus, phys),
+            [bus, phys, _, ..] => (bus, phys),
+            _ => return Err(DetectError::InvalidRanges),
+        };
+
+        let soc = Soc::from_peripheral_base(phys)?;
+
+        Ok(Platform {
+            bus: bus as *mut u32,
+            phys: phys as *mut u32,
+            soc,
+        })
+    }
+
+    /// Parses the contents of `/proc/cpuinfo`.
+    pub fn from_cpuinfo(cpuinfo: &str) -> Result<Platform, DetectError> {
+        let revision = parse_cpuinfo_revision(cpuinfo).ok_or(DetectError::MissingRevision)?;
+        Platform::from_revision(revision)
+    }
+
+    /// Creates the platform from a board revision code.
+    pub fn from_revision(revision: u32) -> Result<Platform, DetectError> {
+        Ok(Platform::from_soc(Soc::from_revision(revision)?))
+    }
+
+    pub fn from_soc(soc: Soc) -> Platform {
+        Platform {
+            phys: soc.peripheral_base() as *mut u32,
+            bus: 0x7E000000 as *mut u32,
+            soc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Soc {
+    Bcm2835,
+    Bcm2836,
+    Bcm2837,
+    Bcm2711,
+}
+
+impl Soc {
+    pub fn from_revision(revision: u32) -> Result<Soc, DetectError> {
+        // New-style revision codes have bit 23 set and the processor in bits 12-15.
+        if revision & (1 << 23) != 0 {
+            return match (revision >> 12) & 0xF {
+                0 => Ok(Soc::Bcm2835),
+                1 => Ok(Soc::Bcm2836),
+                2 => Ok(Soc::Bcm2837),
+                3 => Ok(Soc::Bcm2711),
+                _ => Err(DetectError::UnknownRevision(revision)),
+            };
+        }
+
+        // Old-style revision codes are only used by BCM2835 boards, bit 24 is the warranty bit.
+        match revision & 0xFFFFFF {
+            0x0002..=0x0015 => Ok(Soc::Bcm2835),
+            _ => Err(DetectError::UnknownRevision(revision)),
+        }
+    }
+
+    pub fn from_peripheral_base(phys: u32) -> Result<Soc, DetectError> {
+        match phys {
+            0x20000000 => Ok(Soc::Bcm2835),
+            // BCM2836 has the same layout, the revision code is needed to tell them apart.
+            0x3F000000 => Ok(Soc::Bcm2837),
+            0xFE000000 => Ok(Soc::Bcm2711),
+            _ => Err(DetectError::UnknownPeripheralBase(phys)),
+        }
+    }
+
+    pub fn oscillator_rate(&self) -> u32 {
+        match self {
+            Soc::Bcm2711 => 54_000_000,
+            _ => 19_200_000,
+        }
+    }
+
+    /// Returns the allocation flags of GPU memory that stays coherent with the ARM.
+    ///
+    /// The ARM of BCM2835 accesses memory through the VideoCore L2 cache, later SoCs have
+    /// their own caches and need uncached memory.
+    pub fn coherent_mem_flags(&self) -> u32 {
+        match self {
+            Soc::Bcm2835 => MEM_FLAG_L1_NONALLOCATING,
+            _ => MEM_FLAG_DIRECT,
+        }
+    }
+
+    /// Returns the ARM physical address of the RAM or peripheral the VideoCore sees at `bus`.
+    ///
+    /// The VideoCore sees the first GiB of RAM in four aliases, one per cache policy, see
+    /// [`bus_alias`](crate::bus_alias). The ARM of every SoC sees it from address zero, so
+    /// the offset into the alias is the physical address. Peripherals are at
+    /// `0x7E000000` on the bus and at [`Soc::peripheral_base`] for the ARM.
+    pub fn bus_to_phys(&self, bus: u32) -> u32 {
+        if bus & 0xFF000000 == PERIPHERAL_BUS_BASE {
+            self.peripheral_base() + (bus - PERIPHERAL_BUS_BASE)
+        } else {
+            bus & !BUS_ALIAS_MASK
+        }
+    }
+
+    pub fn peripheral_base(&self) -> u32 {
+        match self {
+            Soc::Bcm2835 => 0x20000000,
+            Soc::Bcm2836 | Soc::Bcm2837 => 0x3F000000,
+            Soc::Bcm2711 => 0xFE000000,
+        }
+    }
+}
+
+fn parse_cpuinfo_revision(cpuinfo: &str) -> Option<u32> {
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim() != "Revision" {
+            return None;
+        }
+        u32::from_str_radix(value.trim(), 16).ok()
+    })
+}
+
+#[derive(Debug)]
+pub enum DetectError {
+    Io(io::Error),
+    InvalidRanges,
+    MissingRevision,
+    UnknownPeripheralBase(u32),
+    UnknownRevision(u32),
+}
+
+impl fmt::Display for DetectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetectError::Io(err) => write!(f, "failed to read platform information: {err}"),
+            DetectError::InvalidRanges => write!(f, "invalid device tree ranges"),
+            DetectError::MissingRevision => write!(f, "no revision code in cpuinfo"),
+            DetectError::UnknownPeripheralBase(phys) => {
+                write!(f, "unknown peripheral base address {phys:#010x}")
+            }
+            DetectError::UnknownRevision(revision) => {
+                write!(f, "unknown board revision {revision:#x}")
+            }
+        }
+    }
+}
+
+impl error::Error for DetectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DetectError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DetectError {
+    fn from(err: io::Error) -> Self {
+        DetectError::Io(err)
+    }
+}
+
+impl From<DetectError> for io::Error {
+    fn from(err: DetectError) -> Self {
+        match err {
+            DetectError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::Unsupported, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const PI_ZERO_RANGES: [u32; 6] = [
+        0x7E000000, 0x20000000, 0x02000000, 0x40000000, 0x40000000, 0x00001000,
+    ];
+    const PI_3_RANGES: [u32; 6] = [
+        0x7E000000, 0x3F000000, 0x01000000, 0x40000000, 0x40000000, 0x00001000,
+    ];
+    const PI_4_RANGES: [u32; 12] = [
+        0x7E000000, 0x0, 0xFE000000, 0x01800000, 0x7C000000, 0x0, 0xFC000000, 0x02000000,
+        0x40000000, 0x0, 0xFF800000, 0x00800000,
+    ];
+
+    fn fixture(ranges: Option<&[u32]>, revision: Option<&str>) -> TempDir {
+        let dir = TempDir::new();
+        if let Some(ranges) = ranges {
+            let ranges: Vec<u8> = ranges.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            dir.write(DEVICE_TREE_RANGES_PATH, &ranges);
+        }
+        if let Some(revision) = revision {
+            let cpuinfo = format!(
+                "processor\t: 0\nBogoMIPS\t: 38.40\n\nHardware\t: BCM2835\nRevision\t: {revision}\nModel\t\t: Raspberry Pi\n"
+            );
+            dir.write(CPUINFO_PATH, cpuinfo.as_bytes());
+        }
+        dir
+    }
+
+    fn assert_platform(platform: Platform, phys: u32, soc: Soc) {
+        assert_eq!(platform.phys as u32, phys);
+        assert_eq!(platform.bus as u32, 0x7E000000);
+        assert_eq!(platform.soc, soc);
+    }
+
+    #[test]
+    fn detects_from_device_tree() {
+        let dir = fixture(Some(&PI_ZERO_RANGES), None);
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x20000000,
+            Soc::Bcm2835,
+        );
+
+        let dir = fixture(Some(&PI_3_RANGES), Some("a02082"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x3F000000,
+            Soc::Bcm2837,
+        );
+
+        let dir = fixture(Some(&PI_4_RANGES), Some("c03114"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0xFE000000,
+            Soc::Bcm2711,
+        );
+    }
+
+    #[test]
+    fn revision_tells_apart_shared_peripheral_base() {
+        let dir = fixture(Some(&PI_3_RANGES), Some("a01041"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x3F000000,
+            Soc::Bcm2836,
+        );
+    }
+
+    #[test]
+    fn falls_back_to_cpuinfo_without_device_tree() {
+        let dir = fixture(None, Some("900093"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x20000000,
+            Soc::Bcm2835,
+        );
+
+        // Old-style revision code with the warranty bit set.
+        let dir = fixture(None, Some("1000015"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x20000000,
+            Soc::Bcm2835,
+        );
+    }
+
+    #[test]
+    fn falls_back_to_cpuinfo_with_unknown_device_tree() {
+        let dir = fixture(Some(&[0x7E000000, 0x12000000, 0x01000000]), Some("c03114"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0xFE000000,
+            Soc::Bcm2711,
+        );
+
+        let dir = fixture(Some(&[0x7E000000]), Some("a02082"));
+        assert_platform(
+            Platform::detect_in(dir.path()).unwrap(),
+            0x3F000000,
+            Soc::Bcm2837,
+        );
+    }
+
+    #[test]
+    fn bus_addresses_of_ram_and_peripherals() {
+        for soc in [Soc::Bcm2835, Soc::Bcm2837, Soc::Bcm2711] {
+            assert_eq!(soc.bus_to_phys(0xC0123400), 0x00123400);
+            assert_eq!(soc.bus_to_phys(0x40123400), 0x00123400);
+            assert_eq!(soc.bus_to_phys(0x00123400), 0x00123400);
+        }
+
+        assert_eq!(Soc::Bcm2835.bus_to_phys(0x7E203000), 0x20203000);
+        assert_eq!(Soc::Bcm2837.bus_to_phys(0x7E203000), 0x3F203000);
+        assert_eq!(Soc::Bcm2711.bus_to_phys(0x7E203000), 0xFE203000);
+    }
+
+    #[test]
+    fn unknown_board() {
+        let dir = fixture(Some(&[0x7E000000, 0x12000000, 0x01000000]), None);
+        assert!(matches!(
+            Platform::detect_in(dir.path()),
+            Err(DetectError::UnknownPeripheralBase(0x12000000))
+        ));
+
+        let dir = fixture(None, Some("c0f114"));
+        assert!(matches!(
+            Platform::detect_in(dir.path()),
+            Err(DetectError::UnknownRevision(0xc0f114))
+        ));
+
+        let dir = fixture(None, None);
+        assert!(matches!(
+            Platform::detect_in(dir.path()),
+            Err(DetectError::Io(_))
+        ));
+    }
 }
@@ -0,0 +1,1223 @@
+//! Software model of the VideoCore mailbox, DMA and SMI peripherals.
+//!
+//! A [`Board`] hands out a [`Mailbox`] and a [`RegisterBackend`] that can be passed to the
+//! regular `open_with`/`alloc` functions. Once a transfer has been started, [`Board::run`]
+//! executes the DMA control blocks found in memory and records the values the SMI bus outputs.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    dma,
+    field::{read_bit_field, write_bit_field},
+    mailbox::{bus_alias, Mailbox, MailboxBackend, MEM_FLAG_DIRECT},
+    mem::{RegisterBackend, SimulatedMem},
+    platform::{Platform, Soc, PAGE_SIZE},
+    smi,
+};
+
+/// Physical address where simulated GPU memory allocations start.
+pub const SIM_GPU_MEM_BASE: u32 = 0x08000000;
+pub const SIM_GPU_MEM_SIZE: u32 = 0x04000000;
+
+pub const SIM_FIRMWARE_REVISION: u32 = 0x6564d2ac;
+pub const SIM_BOARD_SERIAL: u64 = 0x00000000_5eb1a7ed;
+
+/// DMA channels the simulated firmware leaves to the ARM, the default of current firmware.
+pub const SIM_DMA_CHANNEL_MASK: u32 = 0x7f35;
+
+pub const SIM_DEFAULT_CLOCK_RATE: u32 = 400_000_000;
+pub const SIM_MIN_CLOCK_RATE: u32 = 250_000_000;
+pub const SIM_MAX_CLOCK_RATE: u32 = 500_000_000;
+
+const DMA_CHANNELS: usize = dma::DMA_CHANNEL_COUNT as usize;
+
+pub struct Board {
+    hardware: Arc<Hardware>,
+    videocore: VideoCore,
+}
+
+impl Board {
+    pub fn new(soc: Soc) -> Board {
+        Board {
+            hardware: Arc::new(Hardware {
+                soc,
+                memory: SimulatedMem::new(),
+                pll_d_stopped: AtomicBool::new(false),
+                smi_remaining: AtomicU32::new(0),
+            }),
+            videocore: VideoCore::new(soc),
+        }
+    }
+
+    pub fn platform(&self) -> Platform {
+        Platform::from_soc(self.hardware.soc)
+    }
+
+    /// Returns the register backend, which models the side effects of register writes.
+    pub fn memory(&self) -> Arc<dyn RegisterBackend> {
+        self.hardware.clone()
+    }
+
+    pub fn simulated_memory(&self) -> &SimulatedMem {
+        &self.hardware.memory
+    }
+
+    pub fn mailbox(&self) -> Mailbox {
+        Mailbox::with_backend(self.videocore.clone(), self.memory())
+    }
+
+    /// Makes the firmware behave as with `disable_l2cache=1`, memory is locked in the
+    /// uncached alias whatever the allocation flags.
+    pub fn disable_l2_cache(&self) {
+        self.videocore.state.lock().unwrap().l2_cache = false;
+    }
+
+    /// Powers down PLLD, as if the firmware stopped it.
+    pub fn stop_pll_d(&self) {
+        self.hardware.pll_d_stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Runs all active DMA channels until they finish or stall.
+    pub fn run(&self) -> Timeline {
+        self.run_for(Duration::MAX)
+    }
+
+    /// Runs all active DMA channels until they finish, stall or the SMI bus has been
+    /// running for `duration`.
+    ///
+    /// A channel stopped early is left active and resumes at the start of its current
+    /// control block on the next run, the words of that block are output again. The SMI
+    /// transfer continues with its remaining length.
+    pub fn run_for(&self, duration: Duration) -> Timeline {
+        let mut run = Run::new(&self.hardware, duration);
+
+        for index in 0..DMA_CHANNELS {
+            run.run_channel(index);
+        }
+        run.drain();
+        run.finish()
+    }
+}
+
+struct Hardware {
+    soc: Soc,
+    memory: SimulatedMem,
+    pll_d_stopped: AtomicBool,
+    /// Words left of an SMI transfer that was still active at the end of a run.
+    smi_remaining: AtomicU32,
+}
+
+impl Hardware {
+    fn peripheral_phys(&self, offset: usize) -> usize {
+        self.soc.peripheral_base() as usize + offset
+    }
+
+    fn bus_to_phys(&self, bus: u32) -> usize {
+        self.soc.bus_to_phys(bus) as usize
+    }
+
+    fn channel_kind(&self, index: usize) -> dma::ChannelKind {
+        dma::ChannelKind::of(self.soc, index as u32)
+    }
+
+    /// Returns the channel and register offset if `offset` is a register of a DMA4 channel.
+    fn dma4_register(&self, offset: usize) -> Option<(usize, usize)> {
+        let offset = offset.checked_sub(dma::DMA_OFFSET)?;
+        let channel = offset / dma::DMA_CHANNEL_OFFSET;
+        (channel < DMA_CHANNELS && self.channel_kind(channel) == dma::ChannelKind::Dma4)
+            .then_some((channel, offset % dma::DMA_CHANNEL_OFFSET))
+    }
+
+    fn reg(&self, phys: usize) -> u32 {
+        self.memory.read(phys).unwrap_or(0)
+    }
+
+    fn set_reg(&self, phys: usize, value: u32) {
+        self.memory.write(phys, value);
+    }
+
+    /// Writes `value` like the CPU or a DMA engine would, applying register side effects.
+    fn write(&self, phys: usize, value: u32) -> Option<()> {
+        let old = self.memory.read(phys)?;
+        self.memory
+            .write(phys, self.register_write(phys, old, value))
+    }
+
+    /// Returns the value a register holds after `value` has been written to it.
+    fn register_write(&self, phys: usize, old: u32, value: u32) -> u32 {
+        let Some(offset) = phys.checked_sub(self.peripheral_phys(0)) else {
+            return value;
+        };
+
+        let dma_channels =
+            dma::DMA_OFFSET..dma::DMA_OFFSET + DMA_CHANNELS * dma::DMA_CHANNEL_OFFSET;
+
+        match offset {
+            o if matches!(self.dma4_register(o), Some((_, dma::DMA4_CS))) => {
+                let mut cs = value;
+                let clear =
+                    |field| read_bit_field(old, field) == 1 && read_bit_field(value, field) == 0;
+                write_bit_field(&mut cs, dma::DMA4_CS_END, clear(dma::DMA4_CS_END));
+                write_bit_field(&mut cs, dma::DMA4_CS_INT, clear(dma::DMA4_CS_INT));
+                // ERROR is derived from DEBUG when read.
+                write_bit_field(&mut cs, dma::DMA4_CS_ERROR, false);
+                for field in [
+                    dma::DMA4_CS_DMA_BUSY,
+                    dma::DMA4_CS_OUTSTANDING_TRANSACTIONS,
+                    dma::DMA4_CS_WAITING_FOR_OUTSTANDING_WRITES,
+                    dma::DMA4_CS_WR_PAUSED,
+                    dma::DMA4_CS_RD_PAUSED,
+                    dma::DMA4_CS_DREQ,
+                ] {
+                    write_bit_field(&mut cs, field, read_bit_field(old, field));
+                }
+                if read_bit_field(value, dma::DMA4_CS_ABORT) == 1 {
+                    write_bit_field(&mut cs, dma::DMA4_CS_ABORT, false);
+                    write_bit_field(&mut cs, dma::DMA4_CS_ACTIVE, false);
+                }
+                cs
+            }
+            o if matches!(self.dma4_register(o), Some((_, dma::DMA4_DEBUG))) => {
+                let mut debug = old;
+                if read_bit_field(value, dma::DMA4_DEBUG_RESET) == 1 {
+                    let regs = self.peripheral_phys(o - dma::DMA4_DEBUG);
+                    self.set_reg(regs + dma::DMA4_CS, 0);
+                    self.set_reg(regs + dma::DMA4_CB, 0);
+                    return 0;
+                }
+                // The error flags are cleared by writing 1, the configuration bits are
+                // writable.
+                for field in [
+                    dma::DMA4_DEBUG_READ_CB_ERROR,
+                    dma::DMA4_DEBUG_READ_ERROR,
+                    dma::DMA4_DEBUG_FIFO_ERROR,
+                    dma::DMA4_DEBUG_WRITE_ERROR,
+                ] {
+                    if read_bit_field(value, field) == 1 {
+                        write_bit_field(&mut debug, field, false);
+                    }
+                }
+                for field in [
+                    dma::DMA4_DEBUG_DISABLE_CLK_GATE,
+                    dma::DMA4_DEBUG_ABORT_ON_ERROR,
+                    dma::DMA4_DEBUG_HALT_ON_ERROR,
+                    dma::DMA4_DEBUG_INT_ON_ERROR,
+                ] {
+                    write_bit_field(&mut debug, field, read_bit_field(value, field));
+                }
+                debug
+            }
+            o if self.dma4_register(o).is_some() => value,
+            o if o == smi::SMI_CLOCK_OFFSET + smi::SMI_CLOCK_CTL => {
+                if read_bit_field(value, smi::SMI_CLOCK_CTL_PASSWD) != smi::SMI_CLOCK_PASSWD {
+                    return old;
+                }
+                let mut ctl = value;
+                write_bit_field(&mut ctl, smi::SMI_CLOCK_CTL_PASSWD, 0u32);
+                let running = read_bit_field(ctl, smi::SMI_CLOCK_CTL_ENAB) == 1
+                    && read_bit_field(ctl, smi::SMI_CLOCK_CTL_KILL) == 0;
+                write_bit_field(&mut ctl, smi::SMI_CLOCK_CTL_BUSY, running);
+                ctl
+            }
+            o if o == smi::SMI_CLOCK_OFFSET + smi::SMI_CLOCK_DIV => {
+                if read_bit_field(value, smi::SMI_CLOCK_DIV_PASSWD) != smi::SMI_CLOCK_PASSWD {
+                    return old;
+                }
+                let mut div = value;
+                write_bit_field(&mut div, smi::SMI_CLOCK_DIV_PASSWD, 0u32);
+                div
+            }
+            o if o == smi::SMI_OFFSET + smi::SMI_CS => {
+                let mut cs = value;
+                // CLEAR empties the FIFO and reads back as zero.
+                write_bit_field(&mut cs, smi::SMI_CS_CLEAR, false);
+                // Disabling the controller stops a running or pending transfer.
+                let enabled = read_bit_field(value, smi::SMI_CS_ENABLE) == 1;
+                let active = read_bit_field(old, smi::SMI_CS_ACTIVE) == 1 && enabled;
+                write_bit_field(&mut cs, smi::SMI_CS_ACTIVE, active);
+                if !enabled {
+                    write_bit_field(&mut cs, smi::SMI_CS_START, false);
+                }
+                let done = read_bit_field(old, smi::SMI_CS_DONE) == 1
+                    && read_bit_field(value, smi::SMI_CS_DONE) == 0;
+                write_bit_field(&mut cs, smi::SMI_CS_DONE, done);
+                cs
+            }
+            o if dma_channels.contains(&o)
+                && (o - dma::DMA_OFFSET) % dma::DMA_CHANNEL_OFFSET == dma::DMA_CS =>
+            {
+                if read_bit_field(value, dma::DMA_CS_RESET) == 1 {
+                    return 0;
+                }
+                let mut cs = value;
+                let clear =
+                    |field| read_bit_field(old, field) == 1 && read_bit_field(value, field) == 0;
+                write_bit_field(&mut cs, dma::DMA_CS_END, clear(dma::DMA_CS_END));
+                write_bit_field(&mut cs, dma::DMA_CS_INT, clear(dma::DMA_CS_INT));
+                for field in [
+                    dma::DMA_CS_ERROR,
+                    dma::DMA_CS_WAITING_FOR_OUTSTANDING_WRITES,
+                    dma::DMA_CS_PAUSED,
+                    dma::DMA_CS_DREQ,
+                ] {
+                    write_bit_field(&mut cs, field, read_bit_field(old, field));
+                }
+                if read_bit_field(value, dma::DMA_CS_ABORT) == 1 {
+                    write_bit_field(&mut cs, dma::DMA_CS_ABORT, false);
+                    write_bit_field(&mut cs, dma::DMA_CS_ACTIVE, false);
+                }
+                cs
+            }
+            o if dma_channels.contains(&o)
+                && (o - dma::DMA_OFFSET) % dma::DMA_CHANNEL_OFFSET == dma::DMA_DEBUG =>
+            {
+                // Only the error flags can be written, writing 1 clears them.
+                let mut debug = old;
+                for field in [
+                    dma::DMA_DEBUG_READ_ERROR,
+                    dma::DMA_DEBUG_FIFO_ERROR,
+                    dma::DMA_DEBUG_READ_LAST_NOT_SET_ERROR,
+                ] {
+                    if read_bit_field(value, field) == 1 {
+                        write_bit_field(&mut debug, field, false);
+                    }
+                }
+                debug
+            }
+            _ => value,
+        }
+    }
+
+    /// Returns the value read from a register holding `value`, for bits that are fixed in
+    /// hardware.
+    fn register_read(&self, phys: usize, value: u32) -> u32 {
+        if let Some(offset) = phys
+            .checked_sub(self.peripheral_phys(smi::PLL_OFFSET))
+            .filter(|&offset| offset < PAGE_SIZE)
+        {
+            let mut value = pll_register(self.soc, offset);
+            if offset == smi::A2W_PLLD_CTRL && self.pll_d_stopped.load(Ordering::Relaxed) {
+                write_bit_field(&mut value, smi::A2W_PLL_CTRL_PWRDN, true);
+            }
+            return value;
+        }
+
+        let Some(offset) = phys.checked_sub(self.peripheral_phys(dma::DMA_OFFSET)) else {
+            return value;
+        };
+
+        let channel = offset / dma::DMA_CHANNEL_OFFSET;
+        if channel >= DMA_CHANNELS {
+            return value;
+        }
+
+        let register = offset % dma::DMA_CHANNEL_OFFSET;
+        match self.channel_kind(channel) {
+            dma::ChannelKind::Dma4 if register == dma::DMA4_CS => {
+                let debug = self.reg(phys - dma::DMA4_CS + dma::DMA4_DEBUG);
+                let error = [
+                    dma::DMA4_DEBUG_READ_CB_ERROR,
+                    dma::DMA4_DEBUG_READ_ERROR,
+                    dma::DMA4_DEBUG_FIFO_ERROR,
+                    dma::DMA4_DEBUG_WRITE_ERROR,
+                ]
+                .into_iter()
+                .any(|field| read_bit_field(debug, field) == 1);
+
+                let mut cs = value;
+                write_bit_field(&mut cs, dma::DMA4_CS_ERROR, error);
+                cs
+            }
+            kind @ (dma::ChannelKind::Full | dma::ChannelKind::Lite)
+                if register == dma::DMA_DEBUG =>
+            {
+                let mut debug = value;
+                write_bit_field(
+                    &mut debug,
+                    dma::DMA_DEBUG_LITE,
+                    kind == dma::ChannelKind::Lite,
+                );
+                debug
+            }
+            _ => value,
+        }
+    }
+}
+
+impl RegisterBackend for Hardware {
+    unsafe fn map(&self, phys: *const u32, size: usize) -> Result<*mut u32, io::Error> {
+        self.memory.map(phys, size)
+    }
+
+    unsafe fn unmap(&self, virt: *mut u32, size: usize) {
+        self.memory.unmap(virt, size)
+    }
+
+    unsafe fn read(&self, virt: *const u32) -> u32 {
+        let value = virt.read_volatile();
+        match self.memory.phys(virt) {
+            Some(phys) => self.register_read(phys, value),
+            None => value,
+        }
+    }
+
+    unsafe fn write(&self, virt: *mut u32, value: u32) {
+        let value = match self.memory.phys(virt) {
+            Some(phys) => self.register_write(phys, virt.read_volatile(), value),
+            None => value,
+        };
+        virt.write_volatile(value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub time: Duration,
+    pub value: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pulse {
+    pub start: Duration,
+    pub duration: Duration,
+    pub level: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+    pub samples: Vec<Sample>,
+    pub end: Duration,
+}
+
+impl Timeline {
+    /// Returns the runs of equal levels on a single bus line.
+    pub fn pulses(&self, line: u32) -> Vec<Pulse> {
+        let mut pulses: Vec<Pulse> = Vec::new();
+
+        for sample in &self.samples {
+            let level = (sample.value >> line) & 1 == 1;
+            match pulses.last_mut() {
+                Some(pulse) if pulse.level == level => {}
+                _ => pulses.push(Pulse {
+                    start: sample.time,
+                    duration: Duration::ZERO,
+                    level,
+                }),
+            }
+        }
+
+        let mut end = self.end;
+        for pulse in pulses.iter_mut().rev() {
+            pulse.duration = end - pulse.start;
+            end = pulse.start;
+        }
+
+        pulses
+    }
+}
+
+#[derive(Clone)]
+struct VideoCore {
+    state: Arc<Mutex<VideoCoreState>>,
+}
+
+impl VideoCore {
+    fn new(soc: Soc) -> VideoCore {
+        VideoCore {
+            state: Arc::new(Mutex::new(VideoCoreState {
+                soc,
+                next_phys: SIM_GPU_MEM_BASE,
+                next_handle: 0,
+                allocations: HashMap::new(),
+                clocks: HashMap::new(),
+                l2_cache: true,
+            })),
+        }
+    }
+}
+
+struct VideoCoreState {
+    soc: Soc,
+    next_phys: u32,
+    next_handle: u32,
+    allocations: HashMap<u32, Allocation>,
+    clocks: HashMap<u32, u32>,
+    /// Without the L2 cache all memory is returned in the uncached alias.
+    l2_cache: bool,
+}
+
+struct Allocation {
+    phys: u32,
+    flags: u32,
+}
+
+impl VideoCoreState {
+    fn clock_rate(&self, clock: u32) -> u32 {
+        self.clocks
+            .get(&clock)
+            .copied()
+            .unwrap_or(SIM_DEFAULT_CLOCK_RATE)
+    }
+
+    /// Handles a single tag, returns the response length in bytes.
+    fn handle(&mut self, tag: u32, value: &mut [u32]) -> Option<usize> {
+        match (tag, &mut value[..]) {
+            (0x00000001, [revision, ..]) => {
+                *revision = SIM_FIRMWARE_REVISION;
+                Some(4)
+            }
+            (0x00010001, [model, ..]) => {
+                *model = 0;
+                Some(4)
+            }
+            (0x00010002, [revision, ..]) => {
+                *revision = match self.soc {
+                    Soc::Bcm2835 => 0x900093,
+                    Soc::Bcm2836 => 0xa21041,
+                    Soc::Bcm2837 => 0xa02082,
+                    Soc::Bcm2711 => 0xc03111,
+                };
+                Some(4)
+            }
+            (0x00010004, [low, high, ..]) => {
+                *low = SIM_BOARD_SERIAL as u32;
+                *high = (SIM_BOARD_SERIAL >> 32) as u32;
+                Some(8)
+            }
+            (0x00010005, [base, size, ..]) => {
+                *base = 0;
+                *size = SIM_GPU_MEM_BASE;
+                Some(8)
+            }
+            (0x00010006, [base, size, ..]) => {
+                *base = SIM_GPU_MEM_BASE;
+                *size = SIM_GPU_MEM_SIZE;
+                Some(8)
+            }
+            (0x00060001, [mask, ..]) => {
+                *mask = SIM_DMA_CHANNEL_MASK;
+                Some(4)
+            }
+            (0x00030002 | 0x00030047, [clock @ 1..=14, rate, ..]) => {
+                *rate = self.clock_rate(*clock);
+                Some(8)
+            }
+            (0x00030004, [1..=14, rate, ..]) => {
+                *rate = SIM_MAX_CLOCK_RATE;
+                Some(8)
+            }
+            (0x00030007, [1..=14, rate, ..]) => {
+                *rate = SIM_MIN_CLOCK_RATE;
+                Some(8)
+            }
+            (0x00038002, [clock @ 1..=14, rate, ..]) => {
+                let clamped = (*rate).clamp(SIM_MIN_CLOCK_RATE, SIM_MAX_CLOCK_RATE);
+                self.clocks.insert(*clock, clamped);
+                *rate = clamped;
+                Some(8)
+            }
+            (0x3000c, [size, align, flags, ..]) => {
+                let phys = self.next_phys.next_multiple_of((*align).max(4));
+                if phys as u64 + *size as u64 > (SIM_GPU_MEM_BASE + SIM_GPU_MEM_SIZE) as u64 {
+                    *size = 0;
+                    return Some(4);
+                }
+                self.next_phys = phys + *size;
+                self.next_handle += 1;
+                self.allocations.insert(
+                    self.next_handle,
+                    Allocation {
+                        phys,
+                        flags: *flags,
+                    },
+                );
+                *size = self.next_handle;
+                Some(4)
+            }
+            (0x3000d, [handle, ..]) => {
+                *handle = match self.allocations.get(handle) {
+                    Some(allocation) if !self.l2_cache => {
+                        allocation.phys | bus_alias(MEM_FLAG_DIRECT)
+                    }
+                    Some(allocation) => allocation.phys | bus_alias(allocation.flags),
+                    None => 0,
+                };
+                Some(4)
+            }
+            (0x3000e, [handle, ..]) => {
+                *handle = if self.allocations.contains_key(handle) {
+                    0
+                } else {
+                    1
+                };
+                Some(4)
+            }
+            (0x3000f, [handle, ..]) => {
+                *handle = match self.allocations.remove(handle) {
+                    Some(_) => 0,
+                    None => 1,
+                };
+                Some(4)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MailboxBackend for VideoCore {
+    fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut i = 2;
+        while i + 3 <= buffer.len() && buffer[i] != 0 {
+            let tag = buffer[i];
+            let start = i + 3;
+            let end = start + (buffer[i + 1] as usize).div_ceil(4);
+            if end > buffer.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "property tag exceeds the buffer",
+                ));
+            }
+
+            if let Some(len) = state.handle(tag, &mut buffer[start..end]) {
+                buffer[i + 2] = 0x80000000 | len as u32;
+            }
+
+            i = end;
+        }
+
+        buffer[1] = 0x80000000;
+
+        Ok(0)
+    }
+}
+
+enum Stop {
+    Error,
+    Stalled,
+    Limit,
+}
+
+struct SmiState {
+    active: bool,
+    remaining: u32,
+    fifo: VecDeque<u32>,
+    depth: usize,
+    dreq: bool,
+    width: smi::TransferWidth,
+    cycles_per_sample: u64,
+    rate: Option<u64>,
+}
+
+struct Run<'a> {
+    board: &'a Hardware,
+    smi: SmiState,
+    now: u64,
+    limit: Duration,
+    samples: Vec<Sample>,
+}
+
+impl<'a> Run<'a> {
+    fn new(board: &'a Hardware, limit: Duration) -> Run<'a> {
+        let smi_reg = |offset| board.reg(board.peripheral_phys(smi::SMI_OFFSET + offset));
+        let clock_reg = |offset| board.reg(board.peripheral_phys(smi::SMI_CLOCK_OFFSET + offset));
+
+        let cs = smi_reg(smi::SMI_CS);
+        let dc = smi_reg(smi::SMI_DC);
+        let device = read_bit_field(smi_reg(smi::SMI_A), smi::SMI_A_DEVICE) as usize;
+        let dsw = smi_reg(smi::SMI_DSW0 + device * (smi::SMI_DSW1 - smi::SMI_DSW0));
+
+        let cycles_per_sample = [
+            smi::SMI_DSW_WSETUP,
+            smi::SMI_DSW_WSTROBE,
+            smi::SMI_DSW_WHOLD,
+            smi::SMI_DSW_WPACE,
+        ]
+        .into_iter()
+        .map(|field| read_bit_field(dsw, field) as u64 + 1)
+        .sum::<u64>();
+
+        let ctl = clock_reg(smi::SMI_CLOCK_CTL);
+        let divisor = read_bit_field(clock_reg(smi::SMI_CLOCK_DIV), smi::SMI_CLOCK_DIV_DIVI);
+        let rate = if read_bit_field(ctl, smi::SMI_CLOCK_CTL_ENAB) == 1 {
+            clock_source_rate(board.soc, read_bit_field(ctl, smi::SMI_CLOCK_CTL_SRC))
+        } else {
+            None
+        };
+
+        let running = read_bit_field(cs, smi::SMI_CS_ACTIVE) == 1;
+        let active = read_bit_field(cs, smi::SMI_CS_ENABLE) == 1
+            && read_bit_field(cs, smi::SMI_CS_WRITE) == 1
+            && (read_bit_field(cs, smi::SMI_CS_START) == 1 || running);
+
+        Run {
+            board,
+            smi: SmiState {
+                active,
+                // The length register keeps the length of the whole transfer.
+                remaining: if running {
+                    board.smi_remaining.load(Ordering::Relaxed)
+                } else {
+                    smi_reg(smi::SMI_L)
+                },
+                fifo: VecDeque::new(),
+                depth: (read_bit_field(dc, smi::SMI_DC_REQW) as usize).max(1),
+                dreq: read_bit_field(dc, smi::SMI_DC_DMAEN) == 1,
+                width: match read_bit_field(dsw, smi::SMI_DSW_WWIDTH) {
+                    0 => smi::TransferWidth::Bit8,
+                    1 => smi::TransferWidth::Bit16,
+                    2 => smi::TransferWidth::Bit18,
+                    _ => smi::TransferWidth::Bit9,
+                },
+                // The model treats a zero integer divisor like a divisor of one.
+                cycles_per_sample: cycles_per_sample * divisor.max(1) as u64,
+                rate,
+            },
+            now: 0,
+            limit,
+            samples: Vec::new(),
+        }
+    }
+
+    fn time(&self) -> Duration {
+        match self.smi.rate {
+            // Rounded, the PLL rates are not exact multiples of 1 Hz.
+            Some(rate) => Duration::from_nanos(
+                ((self.now as u128 * 1_000_000_000 + rate as u128 / 2) / rate as u128) as u64,
+            ),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn run_channel(&mut self, index: usize) {
+        let regs = self
+            .board
+            .peripheral_phys(dma::DMA_OFFSET + index * dma::DMA_CHANNEL_OFFSET);
+
+        let dma4 = self.board.channel_kind(index) == dma::ChannelKind::Dma4;
+        let cb_reg = if dma4 {
+            dma::DMA4_CB
+        } else {
+            dma::DMA_CONBLK_AD
+        };
+
+        let mut cs = self.board.reg(regs + dma::DMA_CS);
+        write_bit_field(&mut cs, dma::DMA_CS_RESET, false);
+        write_bit_field(&mut cs, dma::DMA_CS_ABORT, false);
+        if read_bit_field(cs, dma::DMA_CS_ACTIVE) == 0 {
+            self.board.set_reg(regs + dma::DMA_CS, cs);
+            return;
+        }
+
+        let mut cb = match self.board.reg(regs + cb_reg) {
+            reg if dma4 && reg != 0 => dma::dma4_bus_address((reg as u64) << 5),
+            reg => reg,
+        };
+        let stop = loop {
+            if cb == 0 {
+                break None;
+            }
+
+            let reg = if dma4 {
+                (dma::dma4_address(cb) >> 5) as u32
+            } else {
+                cb
+            };
+            self.board.set_reg(regs + cb_reg, reg);
+
+            let block = if dma4 {
+                Block::read_dma4(self.board, cb)
+            } else {
+                Block::read(self.board, cb)
+            };
+            match block.and_then(|block| self.execute(block)) {
+                Ok(next) => cb = next,
+                Err(stop) => break Some(stop),
+            }
+
+            // Like the hardware, END is set after every control block, not only at the end
+            // of the chain.
+            write_bit_field(&mut cs, dma::DMA_CS_END, true);
+            self.board.set_reg(regs + dma::DMA_CS, cs);
+        };
+
+        match stop {
+            None => {
+                write_bit_field(&mut cs, dma::DMA_CS_ACTIVE, false);
+                write_bit_field(&mut cs, dma::DMA_CS_END, true);
+                self.board.set_reg(regs + cb_reg, 0);
+            }
+            Some(Stop::Error) if dma4 => {
+                write_bit_field(&mut cs, dma::DMA4_CS_ACTIVE, false);
+
+                let mut debug = self.board.reg(regs + dma::DMA4_DEBUG);
+                write_bit_field(&mut debug, dma::DMA4_DEBUG_READ_ERROR, true);
+                self.board.set_reg(regs + dma::DMA4_DEBUG, debug);
+            }
+            Some(Stop::Error) => {
+                write_bit_field(&mut cs, dma::DMA_CS_ACTIVE, false);
+                write_bit_field(&mut cs, dma::DMA_CS_ERROR, true);
+
+                let mut debug = self.board.reg(regs + dma::DMA_DEBUG);
+                write_bit_field(&mut debug, dma::DMA_DEBUG_READ_ERROR, true);
+                self.board.set_reg(regs + dma::DMA_DEBUG, debug);
+            }
+            Some(Stop::Stalled | Stop::Limit) => {}
+        }
+
+        self.board.set_reg(regs + dma::DMA_CS, cs);
+    }
+
+    /// Executes a control block, returns the address of the next one.
+    fn execute(&mut self, block: Block) -> Result<u32, Stop> {
+        let Block {
+            mut src, mut dest, ..
+        } = block;
+
+        let smi_fifo = 0x7E000000 + (smi::SMI_OFFSET + smi::SMI_D) as u32;
+        let to_smi =
+            block.dest_dreq && block.permap == dma::DMA_PERMAP_SMI as u32 && dest == smi_fifo;
+
+        for _ in 0..block.y_len {
+            for _ in 0..block.x_len.div_ceil(4) {
+                let value = if block.src_ignore {
+                    0
+                } else {
+                    self.board
+                        .memory
+                        .read(self.board.bus_to_phys(src))
+                        .ok_or(Stop::Error)?
+                };
+
+                if to_smi {
+                    self.push_smi(value)?;
+                } else if !block.dest_ignore {
+                    self.write(dest, value)?;
+                }
+
+                if block.src_inc {
+                    src = src.wrapping_add(4);
+                }
+                if block.dest_inc {
+                    dest = dest.wrapping_add(4);
+                }
+            }
+
+            src = src.wrapping_add_signed(block.src_stride);
+            dest = dest.wrapping_add_signed(block.dest_stride);
+        }
+
+        Ok(block.next)
+    }
+
+    fn write(&mut self, dest: u32, value: u32) -> Result<(), Stop> {
+        let phys = self.board.bus_to_phys(dest);
+        self.board.write(phys, value).ok_or(Stop::Error)?;
+
+        let smi_regs = self.board.peripheral_phys(smi::SMI_OFFSET);
+        match phys.checked_sub(smi_regs) {
+            Some(smi::SMI_CS)
+                if !self.smi.active
+                    && read_bit_field(value, smi::SMI_CS_ENABLE) == 1
+                    && read_bit_field(value, smi::SMI_CS_START) == 1 =>
+            {
+                self.smi.active = true;
+                self.smi.remaining = self.board.reg(smi_regs + smi::SMI_L);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn push_smi(&mut self, value: u32) -> Result<(), Stop> {
+        if !self.smi.dreq {
+            return Err(Stop::Stalled);
+        }
+
+        while self.smi.fifo.len() >= self.smi.depth {
+            self.step_smi()?;
+        }
+
+        self.smi.fifo.push_back(value);
+
+        Ok(())
+    }
+
+    /// Outputs the samples of the next word in the FIFO.
+    fn step_smi(&mut self) -> Result<(), Stop> {
+        if !self.smi.active || self.smi.rate.is_none() {
+            return Err(Stop::Stalled);
+        }
+
+        let Some(word) = self.smi.fifo.front().copied() else {
+            return Err(Stop::Stalled);
+        };
+
+        let (count, bits, mask) = match self.smi.width {
+            smi::TransferWidth::Bit8 => (4, 8, 0xFF),
+            smi::TransferWidth::Bit9 => (2, 16, 0x1FF),
+            smi::TransferWidth::Bit16 => (2, 16, 0xFFFF),
+            smi::TransferWidth::Bit18 => (1, 0, 0x3FFFF),
+        };
+
+        for i in 0..count {
+            if self.time() >= self.limit {
+                return Err(Stop::Limit);
+            }
+            if self.smi.remaining == 0 {
+                self.smi.active = false;
+                return Err(Stop::Stalled);
+            }
+
+            self.samples.push(Sample {
+                time: self.time(),
+                value: (word >> (i * bits)) & mask,
+            });
+            self.now += self.smi.cycles_per_sample;
+            self.smi.remaining -= 1;
+        }
+
+        self.smi.fifo.pop_front();
+        if self.smi.remaining == 0 {
+            self.smi.active = false;
+        }
+
+        Ok(())
+    }
+
+    fn drain(&mut self) {
+        while !self.smi.fifo.is_empty() {
+            if self.step_smi().is_err() {
+                break;
+            }
+        }
+    }
+
+    fn finish(self) -> Timeline {
+        let cs_phys = self.board.peripheral_phys(smi::SMI_OFFSET + smi::SMI_CS);
+        let mut cs = self.board.reg(cs_phys);
+        write_bit_field(&mut cs, smi::SMI_CS_START, false);
+        write_bit_field(&mut cs, smi::SMI_CS_ACTIVE, self.smi.active);
+        write_bit_field(&mut cs, smi::SMI_CS_DONE, !self.smi.active);
+        self.board.set_reg(cs_phys, cs);
+        self.board
+            .smi_remaining
+            .store(self.smi.remaining, Ordering::Relaxed);
+
+        Timeline {
+            end: self.time(),
+            samples: self.samples,
+        }
+    }
+}
+
+/// A control block decoded from either format, addresses are legacy bus addresses.
+struct Block {
+    src: u32,
+    dest: u32,
+    x_len: u32,
+    y_len: u32,
+    src_stride: i32,
+    dest_stride: i32,
+    src_inc: bool,
+    dest_inc: bool,
+    src_ignore: bool,
+    dest_ignore: bool,
+    dest_dreq: bool,
+    permap: u32,
+    next: u32,
+}
+
+impl Block {
+    fn read(board: &Hardware, cb: u32) -> Result<Block, Stop> {
+        let cb_phys = board.bus_to_phys(cb);
+        let read_cb = |offset| board.memory.read(cb_phys + offset).ok_or(Stop::Error);
+
+        let ti = read_cb(dma::DMA_CB_TI)?;
+        let len = read_cb(dma::DMA_CB_TXFR_LEN)?;
+        let stride = read_cb(dma::DMA_CB_STRIDE)?;
+
+        let flag = |field| read_bit_field(ti, field) == 1;
+
+        let (x_len, y_len, src_stride, dest_stride) = if flag(dma::DMA_TI_TDMODE) {
+            (
+                len & 0xFFFF,
+                len >> 16,
+                stride as u16 as i16 as i32,
+                (stride >> 16) as u16 as i16 as i32,
+            )
+        } else {
+            (len, 1, 0, 0)
+        };
+
+        Ok(Block {
+            src: read_cb(dma::DMA_CB_SOURCE_AD)?,
+            dest: read_cb(dma::DMA_CB_DEST_AD)?,
+            x_len,
+            y_len,
+            src_stride,
+            dest_stride,
+            src_inc: flag(dma::DMA_TI_SRC_INC),
+            dest_inc: flag(dma::DMA_TI_DEST_INC),
+            src_ignore: flag(dma::DMA_TI_SRC_IGNORE),
+            dest_ignore: flag(dma::DMA_TI_DEST_IGNORE),
+            dest_dreq: flag(dma::DMA_TI_DEST_DREQ),
+            permap: read_bit_field(ti, dma::DMA_TI_PERMAP),
+            next: read_cb(dma::DMA_CB_NEXTCONBK)?,
+        })
+    }
+
+    fn read_dma4(board: &Hardware, cb: u32) -> Result<Block, Stop> {
+        let cb_phys = board.bus_to_phys(cb);
+        let read_cb = |offset: usize| board.memory.read(cb_phys + offset * 4).ok_or(Stop::Error);
+
+        let ti = read_cb(0)?;
+        let src = read_cb(1)?;
+        let srci = read_cb(2)?;
+        let dest = read_cb(3)?;
+        let desti = read_cb(4)?;
+        let len = read_cb(5)?;
+        let next = read_cb(6)?;
+
+        let flag = |value, field| read_bit_field(value, field) == 1;
+        let address = |low, info| {
+            dma::dma4_bus_address(
+                (read_bit_field(info, dma::DMA4_I_ADDR) as u64) << 32 | low as u64,
+            )
+        };
+        let stride = |info| read_bit_field(info, dma::DMA4_I_STRIDE) as u16 as i16 as i32;
+
+        let (x_len, y_len) = if flag(ti, dma::DMA4_TI_TDMODE) {
+            (
+                read_bit_field(len, dma::DMA4_LEN_XLENGTH),
+                read_bit_field(len, dma::DMA4_LEN_YLENGTH),
+            )
+        } else {
+            (len, 1)
+        };
+
+        Ok(Block {
+            src: address(src, srci),
+            dest: address(dest, desti),
+            x_len,
+            y_len,
+            src_stride: stride(srci),
+            dest_stride: stride(desti),
+            src_inc: flag(srci, dma::DMA4_I_INC),
+            dest_inc: flag(desti, dma::DMA4_I_INC),
+            src_ignore: flag(srci, dma::DMA4_I_IGNORE),
+            dest_ignore: flag(desti, dma::DMA4_I_IGNORE),
+            dest_dreq: flag(ti, dma::DMA4_TI_D_DREQ),
+            permap: read_bit_field(ti, dma::DMA4_TI_PERMAP),
+            next: match next {
+                0 => 0,
+                next => dma::dma4_bus_address((next as u64) << 5),
+            },
+        })
+    }
+}
+
+fn clock_source_rate(soc: Soc, source: u32) -> Option<u64> {
+    let source = smi::ClockSource::from_value(source)?;
+    smi::clock_source_rate(soc, source, |offset| pll_register(soc, offset)).map(u64::from)
+}
+
+/// Returns the PLL register at `offset`, PLLD runs at the rate set by the firmware and the
+/// other PLLs are stopped.
+fn pll_register(soc: Soc, offset: usize) -> u32 {
+    // 2 GHz from 19.2 MHz and 3 GHz from 54 MHz, divided by 4 for the peripherals.
+    let (ndiv, frac) = match soc {
+        Soc::Bcm2711 => (55, 0x8E38E),
+        _ => (104, 0x2AAAB),
+    };
+
+    match offset {
+        smi::A2W_PLLD_CTRL => {
+            let mut ctrl = 0;
+            write_bit_field(&mut ctrl, smi::A2W_PLL_CTRL_PDIV, 1u32);
+            write_bit_field(&mut ctrl, smi::A2W_PLL_CTRL_NDIV, ndiv as u32);
+            ctrl
+        }
+        smi::A2W_PLLD_FRAC => frac,
+        smi::A2W_PLLD_PER => 4,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch;
+
+    const WS2812_PERIOD: Duration = Duration::from_nanos(400);
+
+    fn pulse(start: u64, duration: u64, level: bool) -> Pulse {
+        Pulse {
+            start: Duration::from_nanos(start),
+            duration: Duration::from_nanos(duration),
+            level,
+        }
+    }
+
+    // Each WS2812 bit takes three periods: high, the data bit, low.
+    fn ws2812_data(bits: &[bool], line: u32) -> Vec<u32> {
+        bits.iter()
+            .flat_map(|&bit| [1 << line, (bit as u32) << line, 0])
+            .collect()
+    }
+
+    fn output(soc: Soc, channel: u32, data: &[u32], period: Duration) -> Timeline {
+        let board = Board::new(soc);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.take(channel).unwrap();
+
+        let mut transfer = batch::Transfer::new(&mailbox, data.len()).unwrap();
+        let mut transfer = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                period,
+                data.len(),
+            )
+            .unwrap();
+        transfer.set_data(data).unwrap();
+        transfer.start().unwrap();
+
+        let timeline = board.run();
+        assert!(!transfer.is_busy());
+        assert_eq!(transfer.wait(Duration::ZERO), Ok(()));
+        timeline
+    }
+
+    #[test]
+    fn ws2812_bit_timings() {
+        let data = ws2812_data(&[true, false, false, true], 3);
+        let timeline = output(Soc::Bcm2835, 5, &data, WS2812_PERIOD);
+
+        // T1H = 800ns, T1L = 400ns, T0H = 400ns, T0L = 800ns.
+        assert_eq!(
+            timeline.pulses(3),
+            [
+                pulse(0, 800, true),
+                pulse(800, 400, false),
+                pulse(1200, 400, true),
+                pulse(1600, 800, false),
+                pulse(2400, 400, true),
+                pulse(2800, 800, false),
+                pulse(3600, 800, true),
+                pulse(4400, 400, false),
+            ]
+        );
+        assert!(timeline.pulses(2).iter().all(|pulse| !pulse.level));
+        assert_eq!(timeline.end, Duration::from_nanos(4800));
+    }
+
+    #[test]
+    fn samples_are_18_bit_values_at_the_period() {
+        let data = [0xFFFFFFFF, 0x00000001, 0x0003FFFE, 0x12345678];
+        let timeline = output(Soc::Bcm2837, 0, &data, Duration::from_micros(1));
+
+        let samples: Vec<_> = timeline
+            .samples
+            .iter()
+            .map(|sample| (sample.time, sample.value))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (Duration::ZERO, 0x3FFFF),
+                (Duration::from_micros(1), 0x00001),
+                (Duration::from_micros(2), 0x3FFFE),
+                (Duration::from_micros(3), 0x05678),
+            ]
+        );
+    }
+
+    #[test]
+    fn chained_control_blocks_are_continuous() {
+        let data: Vec<u32> = (0..batch::SEGMENT_SIZE as u32 + 3 * batch::CHUNK_SIZE as u32)
+            .map(|i| i & 1)
+            .collect();
+
+        for (soc, channel) in [(Soc::Bcm2835, 8), (Soc::Bcm2711, 11)] {
+            let timeline = output(soc, channel, &data, WS2812_PERIOD);
+
+            assert_eq!(timeline.samples.len(), data.len());
+            assert!(timeline
+                .samples
+                .iter()
+                .zip(&data)
+                .enumerate()
+                .all(|(i, (sample, &value))| sample.value == value
+                    && sample.time == WS2812_PERIOD * i as u32));
+        }
+    }
+
+    #[test]
+    fn smi_clock_rate_is_read_from_the_pll() {
+        for (soc, rate) in [(Soc::Bcm2835, 500_000_001), (Soc::Bcm2711, 749_999_997)] {
+            let board = Board::new(soc);
+            let mut smi = smi::Peripheral::open_with(&board.platform(), board.memory()).unwrap();
+            assert_eq!(smi.controller.clock_rate(), Some(rate));
+
+            assert!(smi
+                .controller
+                .set_clock_source(smi::ClockSource::PllC)
+                .is_err());
+            assert_eq!(smi.controller.clock_source(), smi::ClockSource::PllD);
+
+            smi.controller
+                .set_clock_source(smi::ClockSource::Oscillator)
+                .unwrap();
+            assert_eq!(smi.controller.clock_rate(), Some(soc.oscillator_rate()));
+        }
+    }
+
+    #[test]
+    fn run_for_leaves_the_transfer_running() {
+        let board = Board::new(Soc::Bcm2835);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = batch::Transfer::new(&mailbox, 100).unwrap();
+        let mut transfer = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                WS2812_PERIOD,
+                100,
+            )
+            .unwrap();
+        transfer.set_data(&[1; 100]).unwrap();
+        transfer.start().unwrap();
+
+        let timeline = board.run_for(WS2812_PERIOD * 10);
+        assert_eq!(timeline.samples.len(), 10);
+        assert!(transfer.is_busy());
+
+        transfer.stop();
+        assert!(!transfer.is_busy());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    mailbox::{Mailbox, MailboxBackend, Tag},
+    sim::Board,
+};
+
+/// Directory below the system temporary directory that is removed on drop.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "timed-transfer-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `relative` below the directory, creating parent directories.
+    pub fn write(&self, relative: impl AsRef<Path>, contents: &[u8]) {
+        let path = self.path.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Passes mailbox requests to a simulated board, records them and makes the tags in
+/// `failing` fail.
+pub struct RecordingFirmware {
+    firmware: Mailbox,
+    failing: Vec<Tag>,
+    requests: Mutex<Vec<Vec<u32>>>,
+}
+
+impl RecordingFirmware {
+    /// Returns the firmware and a mailbox talking to it.
+    pub fn new(board: &Board, failing: &[Tag]) -> (Arc<RecordingFirmware>, Mailbox) {
+        let firmware = Arc::new(RecordingFirmware {
+            firmware: board.mailbox(),
+            failing: failing.to_vec(),
+            requests: Mutex::new(Vec::new()),
+        });
+        let mailbox = Mailbox::with_backend(firmware.clone(), board.memory());
+        (firmware, mailbox)
+    }
+
+    /// Returns the request values of every message sent with `tag` as its first tag.
+    pub fn requests(&self, tag: Tag) -> Vec<Vec<u32>> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .filter(|buffer| buffer[0] == tag.id())
+            .map(|buffer| buffer[3..].to_vec())
+            .collect()
+    }
+}
+
+impl MailboxBackend for Arc<RecordingFirmware> {
+    fn send(&self, buffer: &mut [u32]) -> Result<i32, io::Error> {
+        // Without the size, code and end tag.
+        self.requests
+            .lock()
+            .unwrap()
+            .push(buffer[2..buffer.len() - 1].to_vec());
+
+        let result = unsafe { self.firmware.send(buffer.as_ptr().cast()) };
+        if self.failing.iter().any(|tag| tag.id() == buffer[2]) {
+            buffer[5] = 1;
+        }
+        result
+    }
+}
@@ -1,172 +1,1260 @@
-use std::{io, time::Duration};
+use std::{
+    error, fmt, io,
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{dma, field::write_bit_field, mailbox::Mailbox, smi, GpuMem};
+use crate::{dma, mailbox::Mailbox, mem::DmaMemory, smi, ClockGuard, DmaBuffer, GpuMem};
 
-pub struct Transfer<'a> {
-    gpu_mem: GpuMem<'a>,
+#[cfg(any(test, feature = "async"))]
+mod future;
+mod looping;
+mod queue;
+
+#[cfg(any(test, feature = "async"))]
+pub use future::*;
+pub use looping::*;
+pub use queue::*;
+
+/// Largest number of words [`Transfer::new`] puts into a single allocation.
+pub const SEGMENT_SIZE: usize = 0x40000;
+
+/// Largest number of words transferred by a single control block, which fits the
+/// `TXFR_LEN` limit of all channel kinds.
+pub const CHUNK_SIZE: usize = dma::DMA_LITE_MAX_LEN as usize / 4;
+
+/// Data to output over SMI, split into segments of DMA memory.
+///
+/// Each segment holds its data followed by the control blocks transferring it, every
+/// control block moves at most [`CHUNK_SIZE`] words. The control blocks of all segments
+/// are chained, so the SMI output stays continuous across segments.
+///
+/// A transfer can hold several frames of the same layout. The control blocks of the first
+/// frame are used for all of them, only their source addresses are switched.
+pub struct Transfer<M: DmaMemory> {
+    frames: Vec<Vec<DmaBuffer<u32, M>>>,
+    state: FrameState,
     size: usize,
 }
 
-impl<'a> Transfer<'a> {
+// Frame selection shared by all configured transfers of a `Transfer`.
+struct FrameState {
+    /// Frame the control blocks point to.
+    front: usize,
+    /// The frame after `front` has been written since the last start.
+    pending: bool,
+}
+
+impl<'a> Transfer<GpuMem<'a>> {
+    /// Allocates a transfer of `size` words, split into allocations of at most
+    /// [`SEGMENT_SIZE`] words.
     pub fn new(mailbox: &'a Mailbox, size: usize) -> Result<Self, io::Error> {
-        let byte_size = size * 4;
-        let gpu_mem = GpuMem::alloc(mailbox, byte_size + dma::DMA_CONTROL_BLOCK_SIZE)?;
+        Self::new_buffered(mailbox, size, 1)
+    }
+
+    /// Allocates a transfer with `frames` frames of `size` words, see
+    /// [`ConfiguredTransfer::set_data`] for how they are used.
+    pub fn new_buffered(
+        mailbox: &'a Mailbox,
+        size: usize,
+        frames: usize,
+    ) -> Result<Self, io::Error> {
+        Self::with_frames(
+            (0..frames)
+                .map(|_| {
+                    segment_sizes(size)
+                        .map(|len| Ok((GpuMem::alloc(mailbox, Self::memory_size(len))?, len)))
+                        .collect::<Result<Vec<_>, io::Error>>()
+                })
+                .collect::<Result<Vec<_>, io::Error>>()?,
+        )
+    }
+}
 
-        let mut ti = 0;
-        write_bit_field(&mut ti, dma::DMA_TI_DEST_DREQ, true);
-        write_bit_field(&mut ti, dma::DMA_TI_SRC_INC, true);
-        write_bit_field(&mut ti, dma::DMA_TI_WAIT_RESP, true);
-        write_bit_field(&mut ti, dma::DMA_TI_PERMAP, dma::DMA_PERMAP_SMI);
+impl Transfer<GpuMem<'static>> {
+    /// Creates a transfer that keeps `mailbox` alive instead of borrowing it.
+    pub fn new_shared(mailbox: Arc<Mailbox>, size: usize) -> Result<Self, io::Error> {
+        Self::with_segments(
+            segment_sizes(size)
+                .map(|len| {
+                    Ok((
+                        GpuMem::alloc_shared(mailbox.clone(), Self::memory_size(len))?,
+                        len,
+                    ))
+                })
+                .collect::<Result<Vec<_>, io::Error>>()?,
+        )
+    }
+}
 
-        unsafe {
-            let dma_cb_virt = gpu_mem.memmap().virt.byte_add(byte_size);
-            dma_cb_virt.byte_add(dma::DMA_CB_TI).write_volatile(ti);
-            dma_cb_virt
-                .byte_add(dma::DMA_CB_SOURCE_AD)
-                .write_volatile(gpu_mem.memmap().bus as u32);
-            dma_cb_virt
-                .byte_add(dma::DMA_CB_TXFR_LEN)
-                .write_volatile(byte_size as u32);
+impl<M: DmaMemory> Transfer<M> {
+    /// Returns the number of bytes of memory needed for a segment of `size` words.
+    pub fn memory_size(size: usize) -> usize {
+        control_block_offset(size) + chunk_count(size) * dma::DMA_CONTROL_BLOCK_SIZE
+    }
+
+    /// Creates a transfer of `size` words in `memory`, which must be at least
+    /// [`Transfer::memory_size`] bytes large and aligned to 32 bytes.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] otherwise.
+    pub fn with_memory(memory: M, size: usize) -> Result<Self, io::Error> {
+        Self::with_segments([(memory, size)])
+    }
+
+    /// Creates a transfer from segments of memory and the number of words each of them
+    /// holds, see [`Transfer::with_memory`].
+    ///
+    /// Fails if no segment is given.
+    pub fn with_segments(
+        segments: impl IntoIterator<Item = (M, usize)>,
+    ) -> Result<Self, io::Error> {
+        Self::with_frames([segments])
+    }
+
+    /// Creates a transfer with several frames, see [`Transfer::with_segments`].
+    ///
+    /// Fails if no frame or segment is given, if a segment's memory is too small or not
+    /// aligned, or if the segments of the frames differ in length.
+    pub fn with_frames(
+        frames: impl IntoIterator<Item = impl IntoIterator<Item = (M, usize)>>,
+    ) -> Result<Self, io::Error> {
+        let frames: Vec<Vec<_>> = frames
+            .into_iter()
+            .map(|segments| {
+                segments
+                    .into_iter()
+                    .map(|(memory, len)| {
+                        if memory.size() < Self::memory_size(len) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "segment memory is too small for its control blocks",
+                            ));
+                        }
+                        if !(memory.memmap().bus as usize).is_multiple_of(32) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "segment memory is not aligned to 32 bytes",
+                            ));
+                        }
+                        DmaBuffer::new(memory, len)
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+
+        let Some(first) = frames.first().filter(|first| !first.is_empty()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a transfer needs at least one segment",
+            ));
         };
 
-        Ok(Self { gpu_mem, size })
+        let same_layout = |frame: &Vec<DmaBuffer<u32, M>>| {
+            frame.len() == first.len() && frame.iter().zip(first).all(|(a, b)| a.len() == b.len())
+        };
+        if !frames.iter().all(same_layout) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "all frames of a transfer need the same segments",
+            ));
+        }
+
+        Ok(Self {
+            size: first.iter().map(DmaBuffer::len).sum(),
+            frames,
+            state: FrameState {
+                front: 0,
+                pending: false,
+            },
+        })
     }
 
     pub fn size(&self) -> usize {
         self.size
     }
 
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the segments of the frame output by the next start, unless another frame
+    /// is written through a configured transfer first.
+    pub fn segments(&self) -> &[DmaBuffer<u32, M>] {
+        &self.frames[self.state.front]
+    }
+
+    pub fn segments_mut(&mut self) -> &mut [DmaBuffer<u32, M>] {
+        &mut self.frames[self.state.front]
+    }
+
+    /// Writes `data` into the frame returned by [`Transfer::segments`].
     pub fn set_data(&mut self, data: &[u32]) {
-        for i in 0..data.len().min(self.size) {
-            unsafe { self.gpu_mem.memmap().virt.add(i).write_volatile(data[i]) }
+        let mut data = &data[..data.len().min(self.size)];
+        for segment in self.segments_mut() {
+            let len = data.len().min(segment.len());
+            segment.as_mut_slice()[..len].copy_from_slice(&data[..len]);
+            data = &data[len..];
         }
     }
 
+    /// Checks that `dma_channel` can execute a transfer of `size` words.
+    pub fn validate(
+        &self,
+        dma_channel: &impl dma::Channel,
+        size: usize,
+    ) -> Result<(), dma::CapabilityError> {
+        self.control_blocks(0, size.min(self.size))
+            .iter()
+            .try_for_each(|(_, block)| dma_channel.kind().validate(block))
+    }
+
+    /// Configures SMI and the DMA channel to output the first `size` words, each for
+    /// `duration`.
+    ///
+    /// `clock` is held for as long as the transfer is configured. Pass the guard of the
+    /// firmware clock the SMI clock source depends on, if the firmware may change it.
+    /// Fails if the rate of the SMI clock source is unknown.
     pub fn configure<'b, SmiDevice: smi::Device, DmaChannel: dma::Channel>(
         &'b mut self,
         smi_controller: &'b mut smi::Controller,
         smi_device: &'b mut SmiDevice,
         dma_channel: &'b mut DmaChannel,
+        clock: Option<&'b ClockGuard<'_>>,
         duration: Duration,
         size: usize,
-    ) -> ConfiguredTransfer<'b, SmiDevice, DmaChannel>
-    where
-        'a: 'b,
-    {
-        let size = size.min(self.size);
-        let byte_size = size * 4;
+    ) -> Result<ConfiguredTransfer<'b, M, SmiDevice, DmaChannel>, ConfigureError> {
+        self.validate(dma_channel, size)?;
+        self.setup(smi_controller, smi_device, dma_channel, duration, size)?;
 
-        let dma_cb_virt = self.gpu_mem.memmap().virt.wrapping_byte_add(self.size * 4);
-        unsafe {
-            dma_cb_virt
-                .byte_add(dma::DMA_CB_TXFR_LEN)
-                .write_volatile(byte_size as u32);
-            dma_cb_virt
-                .byte_add(dma::DMA_CB_DEST_AD)
-                .write_volatile(smi_controller.regs.bus.wrapping_byte_add(smi::SMI_D) as u32);
-        };
+        Ok(ConfiguredTransfer {
+            frames: &self.frames,
+            state: &mut self.state,
+            size: self.size,
+            smi_controller,
+            _smi_device: smi_device,
+            dma_channel,
+            _clock: clock,
+        })
+    }
 
-        let div = duration.as_nanos() / 2; // 1ns * 500Mhz
-
-        // div = (4 + div_transfer) + div_clock
-        // (4 + div_transfer) = (1 + div_setup) + (1 + div_strobe) + (1 + div_hold) + (1 + div_pace)
-        // div_setup = [0, 63]
-        // div_strobe = [0, 127]
-        // div_hold = [0, 63]
-
-        let div = div.max(4) - 4;
-        let mut div_transfer = (div % 253) as u8;
-        let div_clock = (div / 253) as u16;
-
-        let div_setup = div_transfer.clamp(0, 63);
-        div_transfer -= div_setup;
-        let div_strobe = div_transfer.clamp(0, 127);
-        div_transfer -= div_strobe;
-        let div_hold = div_transfer.clamp(0, 63);
-
-        smi_device.set_write_settings(&smi::WriteSettings {
-            width: smi::TransferWidth::Bit18,
-            setup: div_setup,
-            strobe: div_strobe,
-            hold: div_hold,
-            pace: 0,
-            dreq: false,
-        });
+    /// Same as [`Transfer::configure`], but takes ownership of the transfer, peripherals and
+    /// clock guard.
+    ///
+    /// The result can be stored or moved to another thread, use
+    /// [`OwnedTransfer::into_parts`] to get everything back. Use
+    /// [`ClockGuard::lock_shared`] for a guard that doesn't borrow the mailbox.
+    pub fn configure_owned<SmiDevice: smi::Device, DmaChannel: dma::Channel>(
+        mut self,
+        mut smi_controller: smi::Controller,
+        mut smi_device: SmiDevice,
+        mut dma_channel: DmaChannel,
+        clock: Option<ClockGuard<'static>>,
+        duration: Duration,
+        size: usize,
+    ) -> Result<OwnedTransfer<M, SmiDevice, DmaChannel>, ConfigureError> {
+        self.validate(&dma_channel, size)?;
+        self.setup(
+            &mut smi_controller,
+            &mut smi_device,
+            &mut dma_channel,
+            duration,
+            size,
+        )?;
 
-        smi_controller.select(smi_device);
-        smi_controller.zero();
-        smi_controller.zero_direct();
-        smi_controller.disable();
-        smi_controller.clear();
-
-        smi_controller.set_clock_divisor(div_clock);
-        smi_controller.set_control(&smi::Control {
-            dma_enabled: true,
-            external_dreq_mode: false,
-            read_panic_threshold: 48,
-            write_panic_threshold: 16,
-            read_dreq_threshold: 32,
-            write_dreq_threshold: 32,
-        });
+        Ok(OwnedTransfer {
+            transfer: self,
+            smi_controller,
+            smi_device,
+            dma_channel,
+            clock,
+        })
+    }
 
-        smi_controller.set_length(size as u32);
-        smi_controller.set_dir(smi::TransferDir::Write);
-        smi_controller.enable();
+    fn setup<SmiDevice: smi::Device, DmaChannel: dma::Channel>(
+        &mut self,
+        smi_controller: &mut smi::Controller,
+        smi_device: &mut SmiDevice,
+        dma_channel: &mut DmaChannel,
+        duration: Duration,
+        size: usize,
+    ) -> Result<(), ConfigureError> {
+        let size = size.min(self.size);
+        setup_smi(smi_controller, smi_device, duration, size as u32)?;
+
+        let dest = smi_controller
+            .regs
+            .memmap()
+            .bus
+            .wrapping_byte_add(smi::SMI_D) as u32;
+        for (ptr, block) in self.control_blocks(dest, size) {
+            unsafe { write_control_block(dma_channel.kind(), ptr, &block) };
+        }
 
         dma_channel.enable();
+        Ok(())
+    }
 
-        ConfiguredTransfer {
-            gpu_mem: &self.gpu_mem,
-            size: self.size,
-            smi_controller,
-            _smi_device: smi_device,
-            dma_channel,
+    /// Returns the chained control blocks transferring the first `size` words to `dest`,
+    /// with the location each of them is stored at.
+    fn control_blocks(
+        &self,
+        dest: u32,
+        size: usize,
+    ) -> Vec<(*mut dma::ControlBlock, dma::ControlBlock)> {
+        let ti = smi_transfer_info().src_inc(true);
+
+        let mut blocks: Vec<(*mut dma::ControlBlock, dma::ControlBlock)> = Vec::new();
+        let mut remaining = size;
+        for (segment, data) in self.frames[0].iter().zip(&self.frames[self.state.front]) {
+            let len = remaining.min(segment.len());
+            remaining -= len;
+
+            // An empty transfer still needs a control block to start.
+            if len == 0 && !blocks.is_empty() {
+                break;
+            }
+
+            let memmap = segment.memmap();
+            let first = control_block_offset(segment.len());
+            for chunk in 0..chunk_count(len) {
+                let offset = chunk * CHUNK_SIZE;
+                let chunk_len = (len - offset).min(CHUNK_SIZE);
+                let cb_offset = first + chunk * mem::size_of::<dma::ControlBlock>();
+
+                if let Some((_, previous)) = blocks.last_mut() {
+                    previous.nextconbk = memmap.bus.wrapping_byte_add(cb_offset) as u32;
+                }
+
+                blocks.push((
+                    memmap.virt.wrapping_byte_add(cb_offset).cast(),
+                    dma::ControlBlock::new(
+                        ti,
+                        data.memmap().bus.wrapping_byte_add(offset * 4) as u32,
+                        dest,
+                        (chunk_len * 4) as u32,
+                    ),
+                ));
+            }
         }
+
+        blocks
     }
 }
 
-pub struct ConfiguredTransfer<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
-    gpu_mem: &'a GpuMem<'a>,
+/// Dropping the transfer stops a running output, call [`ConfiguredTransfer::wait`] first to
+/// let it finish.
+pub struct ConfiguredTransfer<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
+    frames: &'a [Vec<DmaBuffer<u32, M>>],
+    state: &'a mut FrameState,
     size: usize,
     smi_controller: &'a mut smi::Controller,
     _smi_device: &'a mut SmiDevice,
     dma_channel: &'a mut DmaChannel,
+    _clock: Option<&'a ClockGuard<'a>>,
 }
 
-impl<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel>
-    ConfiguredTransfer<'a, SmiDevice, DmaChannel>
+impl<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    ConfiguredTransfer<'a, M, SmiDevice, DmaChannel>
 {
     pub fn size(&self) -> usize {
         self.size
     }
 
-    pub fn set_data(&mut self, data: &[u32]) {
-        for i in 0..data.len().max(self.size) {
-            unsafe { self.gpu_mem.memmap().virt.add(i).write_volatile(data[i]) }
+    /// Replaces the data output by the next start.
+    ///
+    /// With a single frame this fails with [`BusyError`] while a transfer is running. With
+    /// more frames the data is written into the frame after the one being output, without
+    /// waiting, and the next start switches to it.
+    pub fn set_data(&mut self, data: &[u32]) -> Result<(), BusyError> {
+        self.control().set_data(data)
+    }
+
+    /// Starts the output, fails with [`BusyError`] while the last one is still running.
+    ///
+    /// Use [`wait`](Self::wait) to let the last output finish first.
+    pub fn start(&mut self) -> Result<(), BusyError> {
+        self.control().start()
+    }
+
+    /// Returns whether a transfer is still being output.
+    pub fn is_busy(&self) -> bool {
+        is_busy(&*self.smi_controller, &*self.dma_channel)
+    }
+
+    /// Waits until the running transfer is finished or stopped with a DMA error, or until
+    /// `timeout` has passed.
+    ///
+    /// The transfer keeps running after a timeout.
+    pub fn wait(&mut self, timeout: Duration) -> Result<(), WaitError> {
+        self.control().wait(timeout)
+    }
+
+    /// Stops the running transfer immediately, the rest of its data is dropped.
+    pub fn stop(&mut self) {
+        self.control().stop();
+    }
+
+    fn control(&mut self) -> Control<'_, M, DmaChannel> {
+        Control {
+            frames: self.frames,
+            state: self.state,
+            size: self.size,
+            smi_controller: self.smi_controller,
+            dma_channel: self.dma_channel,
         }
     }
+}
+
+impl<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> Drop
+    for ConfiguredTransfer<'a, M, SmiDevice, DmaChannel>
+{
+    fn drop(&mut self) {
+        // Waiting for the running frame could block forever if SMI is stuck.
+        self.control().stop();
+    }
+}
 
-    pub fn start(&mut self) {
+/// Dropping the transfer stops a running output like [`ConfiguredTransfer`].
+pub struct OwnedTransfer<M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
+    transfer: Transfer<M>,
+    smi_controller: smi::Controller,
+    smi_device: SmiDevice,
+    dma_channel: DmaChannel,
+    clock: Option<ClockGuard<'static>>,
+}
+
+impl<M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    OwnedTransfer<M, SmiDevice, DmaChannel>
+{
+    pub fn size(&self) -> usize {
+        self.transfer.size
+    }
+
+    /// Replaces the data output by the next start.
+    ///
+    /// With a single frame this fails with [`BusyError`] while a transfer is running. With
+    /// more frames the data is written into the frame after the one being output, without
+    /// waiting, and the next start switches to it.
+    pub fn set_data(&mut self, data: &[u32]) -> Result<(), BusyError> {
+        self.control().set_data(data)
+    }
+
+    /// Starts the output, fails with [`BusyError`] while the last one is still running.
+    ///
+    /// Use [`wait`](Self::wait) to let the last output finish first.
+    pub fn start(&mut self) -> Result<(), BusyError> {
+        self.control().start()
+    }
+
+    /// Returns whether a transfer is still being output.
+    pub fn is_busy(&self) -> bool {
+        is_busy(&self.smi_controller, &self.dma_channel)
+    }
+
+    /// Waits until the running transfer is finished or stopped with a DMA error, or until
+    /// `timeout` has passed.
+    ///
+    /// The transfer keeps running after a timeout.
+    pub fn wait(&mut self, timeout: Duration) -> Result<(), WaitError> {
+        self.control().wait(timeout)
+    }
+
+    /// Stops the running transfer immediately, the rest of its data is dropped.
+    pub fn stop(&mut self) {
+        self.control().stop();
+    }
+
+    /// Returns the transfer, peripherals and clock guard.
+    ///
+    /// A running transfer is stopped like on drop, call [`wait`](Self::wait) first to let
+    /// it finish.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        mut self,
+    ) -> (
+        Transfer<M>,
+        smi::Controller,
+        SmiDevice,
+        DmaChannel,
+        Option<ClockGuard<'static>>,
+    ) {
+        self.control().stop();
+
+        let this = ManuallyDrop::new(self);
+        // The fields are moved out exactly once and `Drop` is skipped.
+        unsafe {
+            (
+                ptr::read(&this.transfer),
+                ptr::read(&this.smi_controller),
+                ptr::read(&this.smi_device),
+                ptr::read(&this.dma_channel),
+                ptr::read(&this.clock),
+            )
+        }
+    }
+
+    fn control(&mut self) -> Control<'_, M, DmaChannel> {
+        Control {
+            frames: &self.transfer.frames,
+            state: &mut self.transfer.state,
+            size: self.transfer.size,
+            smi_controller: &mut self.smi_controller,
+            dma_channel: &mut self.dma_channel,
+        }
+    }
+}
+
+impl<M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> Drop
+    for OwnedTransfer<M, SmiDevice, DmaChannel>
+{
+    fn drop(&mut self) {
+        // Waiting for the running frame could block forever if SMI is stuck.
+        self.control().stop();
+    }
+}
+
+// Shared implementation of the borrowed and owned configured transfers.
+struct Control<'a, M: DmaMemory, DmaChannel: dma::Channel> {
+    frames: &'a [Vec<DmaBuffer<u32, M>>],
+    state: &'a mut FrameState,
+    size: usize,
+    smi_controller: &'a mut smi::Controller,
+    dma_channel: &'a mut DmaChannel,
+}
+
+impl<'a, M: DmaMemory, DmaChannel: dma::Channel> Control<'a, M, DmaChannel> {
+    fn set_data(&mut self, data: &[u32]) -> Result<(), BusyError> {
+        let frame = if self.frames.len() == 1 {
+            self.check_idle()?;
+            0
+        } else {
+            self.state.pending = true;
+            self.back()
+        };
+
+        let mut data = &data[..data.len().min(self.size)];
+        for segment in &self.frames[frame] {
+            let len = data.len().min(segment.len());
+            // The configured transfer holds the only reference to the segments, and the
+            // back frame is not read by the DMA engine.
+            unsafe { segment.write_unchecked(&data[..len]) };
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn back(&self) -> usize {
+        (self.state.front + 1) % self.frames.len()
+    }
+
+    // Points the control blocks to the data of `frame`, which must not be running.
+    fn switch_frame(&mut self, frame: usize) {
+        let kind = self.dma_channel.kind();
+        for (segment, data) in self.frames[0].iter().zip(&self.frames[frame]) {
+            let first = control_block_offset(segment.len());
+            for chunk in 0..chunk_count(segment.len()) {
+                let source = data.memmap().bus.wrapping_byte_add(chunk * CHUNK_SIZE * 4) as u32;
+                let cb_offset = first + chunk * mem::size_of::<dma::ControlBlock>();
+                unsafe {
+                    write_source_address(
+                        kind,
+                        segment.memmap().virt.wrapping_byte_add(cb_offset).cast(),
+                        source,
+                    )
+                };
+            }
+        }
+        self.state.front = frame;
+    }
+
+    fn start(&mut self) -> Result<(), BusyError> {
         // Prevent interrupting already running transfer.
-        while self.smi_controller.active() {}
+        self.check_idle()?;
+
+        if self.state.pending {
+            self.state.pending = false;
+            self.switch_frame(self.back());
+        }
 
+        for segment in &self.frames[self.state.front] {
+            segment.set_in_flight(true);
+        }
+
+        let first = &self.frames[0][0];
         self.dma_channel.reset();
         self.dma_channel.set_control_block_address(
-            self.gpu_mem.memmap().bus.wrapping_byte_add(self.size * 4) as u32,
+            first
+                .memmap()
+                .bus
+                .wrapping_byte_add(control_block_offset(first.len())) as u32,
         );
         self.dma_channel.clear_end();
         self.dma_channel.clear_error();
+        self.dma_channel.clear_debug();
         self.dma_channel.start();
 
         self.smi_controller.start();
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), WaitError> {
+        let start = Instant::now();
+        while is_busy(self.smi_controller, self.dma_channel) {
+            if start.elapsed() >= timeout {
+                return Err(WaitError::Timeout);
+            }
+            thread::yield_now();
+        }
+        self.set_idle();
+        self.dma_channel.check_error().map_err(WaitError::Dma)
+    }
+
+    fn stop(&mut self) {
+        stop_hardware(self.smi_controller, self.dma_channel);
+        self.set_idle();
+    }
+
+    fn check_idle(&mut self) -> Result<(), BusyError> {
+        if is_busy(self.smi_controller, self.dma_channel) {
+            return Err(BusyError);
+        }
+        self.set_idle();
+        Ok(())
+    }
+
+    fn set_idle(&self) {
+        for segment in self.frames.iter().flatten() {
+            segment.set_in_flight(false);
+        }
     }
 }
 
-impl<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel> Drop
-    for ConfiguredTransfer<'a, SmiDevice, DmaChannel>
-{
-    fn drop(&mut self) {
-        // Prevent loosing configuration when the transfer is still active.
-        while self.smi_controller.active() {}
+// SMI never finishes if the DMA engine stopped with an error, and the DMA engine can still
+// be feeding the FIFO when SMI didn't start yet.
+fn is_busy(smi_controller: &smi::Controller, dma_channel: &impl dma::Channel) -> bool {
+    let status = dma_channel.status();
+    !status.error && (smi_controller.active() || status.active)
+}
+
+/// Returned by `wait` when the transfer didn't finish in time or stopped with an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitError {
+    Timeout,
+    Dma(dma::DmaError),
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitError::Timeout => write!(f, "transfer did not finish in time"),
+            WaitError::Dma(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl error::Error for WaitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WaitError::Timeout => None,
+            WaitError::Dma(err) => Some(err),
+        }
+    }
+}
+
+impl From<WaitError> for io::Error {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::Timeout => io::Error::new(io::ErrorKind::TimedOut, err),
+            WaitError::Dma(err) => err.into(),
+        }
+    }
+}
+
+/// Returned when the data of a running transfer would be changed or another transfer
+/// would be started while it runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusyError;
+
+impl fmt::Display for BusyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a transfer is still running")
+    }
+}
+
+impl error::Error for BusyError {}
+
+impl From<BusyError> for io::Error {
+    fn from(err: BusyError) -> Self {
+        io::Error::new(io::ErrorKind::ResourceBusy, err)
+    }
+}
+
+/// Returned by `configure` when the transfer can't be set up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigureError {
+    Capability(dma::CapabilityError),
+    /// The rate of the SMI clock source is unknown, or the source is stopped.
+    UnknownClockRate(smi::ClockSource),
+}
+
+impl fmt::Display for ConfigureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigureError::Capability(err) => write!(f, "{err}"),
+            ConfigureError::UnknownClockRate(source) => {
+                write!(f, "the rate of SMI clock source {source:?} is unknown")
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigureError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigureError::Capability(err) => Some(err),
+            ConfigureError::UnknownClockRate(_) => None,
+        }
+    }
+}
+
+impl From<dma::CapabilityError> for ConfigureError {
+    fn from(err: dma::CapabilityError) -> Self {
+        ConfigureError::Capability(err)
+    }
+}
+
+impl From<ConfigureError> for io::Error {
+    fn from(err: ConfigureError) -> Self {
+        match err {
+            ConfigureError::Capability(err) => err.into(),
+            ConfigureError::UnknownClockRate(_) => io::Error::other(err),
+        }
+    }
+}
+
+// Configures SMI to output `length` words of 18 bits, each for `duration`.
+fn setup_smi<SmiDevice: smi::Device>(
+    smi_controller: &mut smi::Controller,
+    smi_device: &mut SmiDevice,
+    duration: Duration,
+    length: u32,
+) -> Result<(), ConfigureError> {
+    // A stopped source would otherwise select the fastest timing.
+    let rate = smi_controller
+        .clock_rate()
+        .ok_or(ConfigureError::UnknownClockRate(
+            smi_controller.clock_source(),
+        ))?;
+
+    // Rounded to the nearest cycle, a source slightly below its nominal rate would
+    // otherwise lose a whole cycle per word.
+    let cycles = (duration.as_nanos() * rate as u128 + 500_000_000) / 1_000_000_000;
+    let (div_clock, div_setup, div_strobe, div_hold) = smi_divisors(cycles);
+
+    smi_device.set_write_settings(&smi::WriteSettings {
+        width: smi::TransferWidth::Bit18,
+        setup: div_setup,
+        strobe: div_strobe,
+        hold: div_hold,
+        pace: 0,
+        dreq: false,
+    });
+
+    smi_controller.select(smi_device);
+    smi_controller.zero();
+    smi_controller.zero_direct();
+    smi_controller.disable();
+    smi_controller.clear();
+
+    smi_controller.set_clock_divisor(div_clock);
+    smi_controller.set_control(&smi::Control {
+        dma_enabled: true,
+        external_dreq_mode: false,
+        read_panic_threshold: 48,
+        write_panic_threshold: 16,
+        read_dreq_threshold: 32,
+        write_dreq_threshold: 32,
+    });
+
+    smi_controller.set_length(length);
+    smi_controller.set_dir(smi::TransferDir::Write);
+    smi_controller.enable();
+    Ok(())
+}
+
+// Splits the source clock cycles of a word into the SMI clock divisor and the setup,
+// strobe and hold cycles of a write.
+//
+// The clock divisor divides the source clock and every write phase lasts at least one
+// divided cycle, so the cycles multiply:
+// cycles = div_clock * (4 + div_transfer)
+// (4 + div_transfer) = (1 + div_setup) + (1 + div_strobe) + (1 + div_hold) + (1 + div_pace)
+// div_clock = [1, 4095]
+// div_setup = [0, 63]
+// div_strobe = [0, 127]
+// div_hold = [0, 63]
+fn smi_divisors(cycles: u128) -> (u16, u8, u8, u8) {
+    let div_clock = cycles.div_ceil(4 + 253).clamp(1, 4095);
+    let mut div_transfer = (((cycles + div_clock / 2) / div_clock).clamp(4, 4 + 253) - 4) as u8;
+
+    let div_setup = div_transfer.clamp(0, 63);
+    div_transfer -= div_setup;
+    let div_strobe = div_transfer.clamp(0, 127);
+    div_transfer -= div_strobe;
+    let div_hold = div_transfer.clamp(0, 63);
+
+    (div_clock as u16, div_setup, div_strobe, div_hold)
+}
+
+// Paced by the SMI DREQ, the source increment is left to the caller.
+fn smi_transfer_info() -> dma::TransferInfo {
+    dma::TransferInfo::new()
+        .dest_dreq(true)
+        .wait_resp(true)
+        .permap(dma::DMA_PERMAP_SMI)
+}
+
+// Control blocks have to be aligned to 32 bytes.
+fn control_block_offset(size: usize) -> usize {
+    (size * 4).next_multiple_of(mem::size_of::<dma::ControlBlock>())
+}
+
+// Writes `block` to `ptr` in the format of the channel kind.
+unsafe fn write_control_block(
+    kind: dma::ChannelKind,
+    ptr: *mut dma::ControlBlock,
+    block: &dma::ControlBlock,
+) {
+    match kind {
+        dma::ChannelKind::Dma4 => ptr
+            .cast::<dma::Dma4ControlBlock>()
+            .write_volatile(dma::Dma4ControlBlock::from_legacy(block)),
+        _ => ptr.write_volatile(*block),
+    }
+}
+
+// Source addresses are the second word in both control block formats.
+unsafe fn write_source_address(kind: dma::ChannelKind, ptr: *mut dma::ControlBlock, source: u32) {
+    let source = match kind {
+        dma::ChannelKind::Dma4 => dma::dma4_address(source) as u32,
+        _ => source,
+    };
+    ptr.wrapping_byte_add(dma::DMA_CB_SOURCE_AD)
+        .cast::<u32>()
+        .write_volatile(source);
+}
+
+// Links the control block at `ptr` to `next` with a single write.
+unsafe fn write_next_address(kind: dma::ChannelKind, ptr: *mut dma::ControlBlock, next: u32) {
+    let (offset, next) = match kind {
+        dma::ChannelKind::Dma4 => (
+            mem::offset_of!(dma::Dma4ControlBlock, next_cb),
+            (dma::dma4_address(next) >> 5) as u32,
+        ),
+        _ => (dma::DMA_CB_NEXTCONBK, next),
+    };
+    ptr.wrapping_byte_add(offset)
+        .cast::<u32>()
+        .write_volatile(next);
+}
+
+// Stops the DMA channel and the SMI transfer, SMI is left enabled for the next start.
+fn stop_hardware(smi_controller: &mut smi::Controller, dma_channel: &mut impl dma::Channel) {
+    dma_channel.abort();
+    dma_channel.reset();
+
+    // Disabling the controller ends the SMI transfer.
+    smi_controller.disable();
+    smi_controller.clear();
+    smi_controller.enable();
+}
+
+fn chunk_count(size: usize) -> usize {
+    size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+fn segment_sizes(size: usize) -> impl Iterator<Item = usize> {
+    let count = size.div_ceil(SEGMENT_SIZE).max(1);
+    (0..count).map(move |i| (size - i * SEGMENT_SIZE).min(SEGMENT_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dma::Channel, platform::Soc, sim::Board};
+
+    const PERIOD: Duration = Duration::from_micros(1);
+
+    fn word_cycles((div_clock, setup, strobe, hold): (u16, u8, u8, u8)) -> u128 {
+        div_clock as u128 * (4 + setup as u128 + strobe as u128 + hold as u128)
+    }
+
+    #[test]
+    fn smi_divisors_multiply_to_the_word_cycles() {
+        assert_eq!(smi_divisors(200), (1, 63, 127, 6));
+        assert_eq!(smi_divisors(500), (2, 63, 127, 56));
+        assert_eq!(word_cycles(smi_divisors(1000)), 1000);
+
+        for cycles in (4..4095 * 257).step_by(97) {
+            let divisors = smi_divisors(cycles);
+            assert!(
+                word_cycles(divisors).abs_diff(cycles) <= divisors.0 as u128 / 2,
+                "{cycles} cycles: {divisors:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn smi_divisors_are_clamped() {
+        assert_eq!(smi_divisors(0), (1, 0, 0, 0));
+        assert_eq!(word_cycles(smi_divisors(u32::MAX as u128)), 4095 * 257);
+    }
+
+    #[test]
+    fn data_is_spread_across_segments() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+
+        let mut transfer = Transfer::new(&mailbox, SEGMENT_SIZE + 16).unwrap();
+        let lens: Vec<_> = transfer.segments().iter().map(DmaBuffer::len).collect();
+        assert_eq!(lens, [SEGMENT_SIZE, 16]);
+
+        let data: Vec<u32> = (0..SEGMENT_SIZE as u32 + 16).collect();
+        transfer.set_data(&data);
+        let segments = transfer.segments();
+        assert_eq!(segments[0].as_slice(), &data[..SEGMENT_SIZE]);
+        assert_eq!(segments[1].as_slice(), &data[SEGMENT_SIZE..]);
+
+        // Short data only replaces the start, longer data is cut off.
+        transfer.set_data(&[7; SEGMENT_SIZE + 4]);
+        transfer.set_data(&[9; 2]);
+        let segments = transfer.segments();
+        assert_eq!(segments[0].as_slice()[..3], [9, 9, 7]);
+        assert_eq!(
+            segments[1].as_slice()[..5],
+            [7, 7, 7, 7, SEGMENT_SIZE as u32 + 4]
+        );
+        assert_eq!(segments[1][15], SEGMENT_SIZE as u32 + 15);
+
+        let mut data = data.clone();
+        data.push(0xDEAD);
+        transfer.set_data(&data);
+        assert_eq!(transfer.segments()[1][15], SEGMENT_SIZE as u32 + 15);
+    }
+
+    #[test]
+    fn configured_data_is_spread_across_segments() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let size = SEGMENT_SIZE + 16;
+        let mut transfer = Transfer::new(&mailbox, size).unwrap();
+        let mut configured = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                size,
+            )
+            .unwrap();
+        configured.set_data(&[1; SEGMENT_SIZE + 8]).unwrap();
+        drop(configured);
+
+        let segments = transfer.segments();
+        assert!(segments[0].as_slice().iter().all(|&word| word == 1));
+        assert_eq!(segments[1].as_slice()[..9], [1, 1, 1, 1, 1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn configure_fails_without_a_clock_rate() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+        let clock = ClockGuard::pin(&mailbox, crate::ClockId::Core).unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 16).unwrap();
+        assert!(transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                Some(&clock),
+                PERIOD,
+                16
+            )
+            .is_ok());
+
+        board.stop_pll_d();
+        assert_eq!(
+            transfer
+                .configure(
+                    &mut smi.controller,
+                    &mut device,
+                    &mut channel,
+                    Some(&clock),
+                    PERIOD,
+                    16
+                )
+                .err(),
+            Some(ConfigureError::UnknownClockRate(smi::ClockSource::PllD))
+        );
+    }
+
+    #[test]
+    fn wait_returns_the_dma_error() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 16).unwrap();
+        let mut transfer = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                16,
+            )
+            .unwrap();
+
+        // Point the data block to memory that doesn't exist.
+        let segment = &transfer.frames[0][0];
+        let block = segment
+            .memmap()
+            .virt
+            .wrapping_byte_add(control_block_offset(segment.len()));
+        unsafe { write_source_address(dma::ChannelKind::Full, block.cast(), 0xDEAD_0000) };
+
+        transfer.start().unwrap();
+        board.run();
+        assert!(!transfer.is_busy());
+        assert_eq!(
+            transfer.wait(Duration::ZERO),
+            Err(WaitError::Dma(dma::DmaError::Read))
+        );
+    }
+
+    #[test]
+    fn owned_transfers_can_be_sent() {
+        fn assert_send<T: Send>() {}
+        assert_send::<OwnedTransfer<GpuMem<'static>, smi::Device0, dma::Channel5>>();
+
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = Arc::new(board.mailbox());
+        let smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let device = smi.devices.device0().unwrap();
+        let channel = dma.channels.channel5().unwrap();
+        let clock = ClockGuard::pin_shared(mailbox.clone(), crate::ClockId::Core).unwrap();
+
+        let mut transfer = Transfer::new_shared(mailbox, 16).unwrap();
+        transfer.set_data(&[0x2AAAA; 16]);
+        let mut owned = transfer
+            .configure_owned(smi.controller, device, channel, Some(clock), PERIOD, 16)
+            .unwrap();
+
+        let (started, running) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            owned.start().unwrap();
+            started.send(()).unwrap();
+            owned.wait(Duration::from_secs(10)).unwrap();
+            owned
+        });
+        running.recv().unwrap();
+        let timeline = board.run();
+        let (transfer, controller, _, channel, clock) = thread.join().unwrap().into_parts();
+
+        assert_eq!(timeline.samples.len(), 16);
+        assert!(timeline
+            .samples
+            .iter()
+            .all(|sample| sample.value == 0x2AAAA));
+        assert_eq!(transfer.size(), 16);
+        assert!(!controller.active());
+        assert!(!channel.status().active);
+        assert!(clock.is_some());
+    }
+
+    #[test]
+    fn running_transfers_are_not_changed() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 100).unwrap();
+        let mut configured = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                100,
+            )
+            .unwrap();
+        configured.set_data(&[1; 100]).unwrap();
+        configured.start().unwrap();
+        board.run_for(PERIOD * 10);
+
+        assert_eq!(configured.start(), Err(BusyError));
+        assert_eq!(configured.set_data(&[2; 100]), Err(BusyError));
+
+        let timeline = board.run();
+        assert_eq!(timeline.samples.len(), 90);
+        assert!(timeline.samples.iter().all(|sample| sample.value == 1));
+
+        configured.set_data(&[2; 100]).unwrap();
+        configured.start().unwrap();
+        let timeline = board.run();
+        assert_eq!(timeline.samples.len(), 100);
+        assert!(timeline.samples.iter().all(|sample| sample.value == 2));
+    }
+
+    #[test]
+    fn buffered_data_is_written_to_the_back_frame() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new_buffered(&mailbox, 100, 2).unwrap();
+        let mut configured = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                100,
+            )
+            .unwrap();
+        configured.set_data(&[1; 100]).unwrap();
+        configured.start().unwrap();
+        board.run_for(PERIOD * 10);
+
+        // The running frame is untouched, only the back frame is written.
+        configured.set_data(&[2; 100]).unwrap();
+        let front = configured.state.front;
+        let back = (front + 1) % 2;
+        assert!(configured.frames[front][0]
+            .as_slice()
+            .iter()
+            .all(|&word| word == 1));
+        assert!(configured.frames[back][0]
+            .as_slice()
+            .iter()
+            .all(|&word| word == 2));
+        assert_eq!(configured.start(), Err(BusyError));
+
+        let timeline = board.run();
+        assert_eq!(timeline.samples.len(), 90);
+        assert!(timeline.samples.iter().all(|sample| sample.value == 1));
+
+        configured.wait(Duration::ZERO).unwrap();
+        configured.start().unwrap();
+        let timeline = board.run();
+        assert_eq!(timeline.samples.len(), 100);
+        assert!(timeline.samples.iter().all(|sample| sample.value == 2));
+        assert_eq!(configured.state.front, back);
+    }
+
+    #[test]
+    fn small_segment_memory_is_rejected() {
+        let board = Board::new(Soc::Bcm2837);
+        let mailbox = board.mailbox();
+
+        // A page of data leaves no room for the control blocks.
+        let memory = GpuMem::alloc(&mailbox, 1024 * 4).unwrap();
+        let err = Transfer::with_memory(memory, 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let memory = GpuMem::alloc(&mailbox, Transfer::<GpuMem>::memory_size(1024)).unwrap();
+        assert_eq!(Transfer::with_memory(memory, 1024).unwrap().size(), 1024);
+    }
+
+    #[test]
+    fn into_parts_stops_a_running_transfer() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = Arc::new(board.mailbox());
+        let smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let device = smi.devices.device0().unwrap();
+        let channel = dma.channels.channel5().unwrap();
+
+        let transfer = Transfer::new_shared(mailbox, 1000).unwrap();
+        let mut owned = transfer
+            .configure_owned(smi.controller, device, channel, None, PERIOD, 1000)
+            .unwrap();
+        owned.start().unwrap();
+        board.run_for(PERIOD * 10);
+        assert!(owned.is_busy());
+
+        let (transfer, controller, _, channel, _) = owned.into_parts();
+        assert!(!controller.active());
+        assert!(!channel.status().active);
+        assert!(!transfer.segments()[0].is_in_flight());
+    }
+
+    #[test]
+    fn drop_stops_a_running_transfer() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 1000).unwrap();
+        let mut configured = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                1000,
+            )
+            .unwrap();
+        configured.start().unwrap();
+        board.run_for(PERIOD * 10);
+
+        assert!(configured.is_busy());
+        assert_eq!(
+            configured.wait(Duration::from_millis(1)),
+            Err(WaitError::Timeout)
+        );
+        drop(configured);
+
+        assert!(!channel.status().active);
+        assert!(!smi.controller.active());
     }
 }
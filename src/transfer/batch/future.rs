@@ -0,0 +1,286 @@
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{Condvar, Mutex, Once},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use super::*;
+
+/// How often a [`TransferFuture`] checks the DMA channel by default.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+impl<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    ConfiguredTransfer<'a, M, SmiDevice, DmaChannel>
+{
+    /// Starts the transfer and returns a future resolving once it is finished.
+    ///
+    /// Dropping the future before it resolves stops the transfer. Fails like
+    /// [`ConfiguredTransfer::start`].
+    pub fn start_async(&mut self) -> Result<TransferFuture<'_, M, DmaChannel>, BusyError> {
+        let mut control = self.control();
+        control.start()?;
+        Ok(TransferFuture::new(control))
+    }
+}
+
+impl<M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    OwnedTransfer<M, SmiDevice, DmaChannel>
+{
+    /// Starts the transfer and returns a future resolving once it is finished.
+    ///
+    /// Dropping the future before it resolves stops the transfer. Fails like
+    /// [`OwnedTransfer::start`].
+    pub fn start_async(&mut self) -> Result<TransferFuture<'_, M, DmaChannel>, BusyError> {
+        let mut control = self.control();
+        control.start()?;
+        Ok(TransferFuture::new(control))
+    }
+}
+
+/// Resolves when the DMA channel of a started transfer reports the end of the transfer or
+/// an error.
+///
+/// The channel is polled from a timer thread shared by all futures, which doesn't depend on
+/// any async runtime.
+pub struct TransferFuture<'a, M: DmaMemory, DmaChannel: dma::Channel> {
+    control: Control<'a, M, DmaChannel>,
+    poll_interval: Duration,
+    done: bool,
+}
+
+impl<'a, M: DmaMemory, DmaChannel: dma::Channel> TransferFuture<'a, M, DmaChannel> {
+    fn new(control: Control<'a, M, DmaChannel>) -> Self {
+        Self {
+            control,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            done: false,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+impl<'a, M: DmaMemory, DmaChannel: dma::Channel> Future for TransferFuture<'a, M, DmaChannel> {
+    type Output = Result<(), dma::DmaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        assert!(!this.done, "transfer future polled after completion");
+
+        if is_busy(this.control.smi_controller, this.control.dma_channel) {
+            wake_after(this.poll_interval, cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        this.done = true;
+        this.control.set_idle();
+        Poll::Ready(this.control.dma_channel.check_error())
+    }
+}
+
+impl<'a, M: DmaMemory, DmaChannel: dma::Channel> Drop for TransferFuture<'a, M, DmaChannel> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.control.stop();
+        }
+    }
+}
+
+struct Timer {
+    wakers: Mutex<Vec<(Instant, Waker)>>,
+    condvar: Condvar,
+}
+
+static TIMER: Timer = Timer {
+    wakers: Mutex::new(Vec::new()),
+    condvar: Condvar::new(),
+};
+
+// Wakes `waker` once `delay` has passed.
+fn wake_after(delay: Duration, waker: Waker) {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("transfer-timer".into())
+            .spawn(|| run_timer(&TIMER))
+            .expect("failed to spawn the transfer timer thread");
+    });
+
+    TIMER
+        .wakers
+        .lock()
+        .unwrap()
+        .push((Instant::now() + delay, waker));
+    TIMER.condvar.notify_one();
+}
+
+fn run_timer(timer: &Timer) {
+    let mut wakers = timer.wakers.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let (expired, pending): (Vec<_>, Vec<_>) = mem::take(&mut *wakers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        *wakers = pending;
+
+        if !expired.is_empty() {
+            // Wakers can poll inline and register again, which locks the list.
+            drop(wakers);
+            for (_, waker) in expired {
+                waker.wake();
+            }
+            wakers = timer.wakers.lock().unwrap();
+            continue;
+        }
+
+        wakers = match wakers.iter().map(|(deadline, _)| *deadline).min() {
+            Some(deadline) => {
+                timer
+                    .condvar
+                    .wait_timeout(wakers, deadline.saturating_duration_since(now))
+                    .unwrap()
+                    .0
+            }
+            None => timer.condvar.wait(wakers).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        sync::{mpsc, Arc},
+        task::Wake,
+    };
+
+    use super::*;
+    use crate::{dma::Channel, platform::Soc, sim::Board};
+
+    const PERIOD: Duration = Duration::from_micros(1);
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Polls `future` on the current thread, calling `idle` whenever it is pending.
+    fn block_on<F: Future>(future: F, mut idle: impl FnMut()) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            idle();
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn future_resolves_when_the_transfer_is_finished() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 100).unwrap();
+        let mut transfer = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                100,
+            )
+            .unwrap();
+        transfer.set_data(&[0x3ffff; 100]).unwrap();
+
+        let mut polls = 0;
+        let result = block_on(transfer.start_async().unwrap(), || {
+            polls += 1;
+            board.run();
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(polls, 1);
+        assert!(!transfer.is_busy());
+    }
+
+    #[test]
+    fn dropping_the_future_stops_the_transfer() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new(&mailbox, 1000).unwrap();
+        let mut transfer = transfer
+            .configure(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                None,
+                PERIOD,
+                1000,
+            )
+            .unwrap();
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut future = Box::pin(transfer.start_async().unwrap());
+        board.run_for(PERIOD * 10);
+        let poll = future.as_mut().poll(&mut Context::from_waker(&waker));
+        assert!(poll.is_pending());
+        drop(future);
+
+        assert!(!transfer.is_busy());
+        drop(transfer);
+        assert!(!channel.status().active);
+    }
+
+    // Registers itself again when woken, like a future polled inline by its waker.
+    struct RearmingWaker {
+        remaining: Mutex<u32>,
+        done: Mutex<mpsc::Sender<()>>,
+    }
+
+    impl Wake for RearmingWaker {
+        fn wake(self: Arc<Self>) {
+            let mut remaining = self.remaining.lock().unwrap();
+            if *remaining == 0 {
+                self.done.lock().unwrap().send(()).unwrap();
+            } else {
+                *remaining -= 1;
+                wake_after(Duration::from_millis(1), Waker::from(self.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn wakers_can_register_again_while_woken() {
+        let (sender, receiver) = mpsc::channel();
+        let waker = Arc::new(RearmingWaker {
+            remaining: Mutex::new(3),
+            done: Mutex::new(sender),
+        });
+
+        wake_after(Duration::ZERO, Waker::from(waker));
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+use std::{
+    mem, ops,
+    sync::atomic::{self, Ordering},
+    thread,
+};
+
+use crate::mailbox::BUS_ALIAS_MASK;
+
+use super::*;
+
+/// Length of the single SMI transfer a loop runs in, in words.
+///
+/// SMI is not reconfigured while the loop runs, so the loop ends after this many words.
+pub const LOOP_SMI_LENGTH: u32 = u32::MAX;
+
+// The ring memory starts with the words read by the gap blocks and copied by the link
+// blocks.
+const RING_HEADER_SIZE: usize = mem::size_of::<dma::ControlBlock>();
+const RING_ZERO_OFFSET: usize = 0;
+const RING_LINK_OFFSET: usize = 4;
+
+impl<'a> Transfer<GpuMem<'a>> {
+    /// Configures the transfer to output its frames in a loop without CPU involvement.
+    ///
+    /// All frames but one are linked into a ring of slots, the remaining frame is used by
+    /// [`LoopingTransfer::replace_frame`], so at least two frames are needed. `size` must
+    /// not be zero. After every frame, zero words are output for `gap`, paced by SMI like
+    /// the data. The control blocks of the ring are allocated through the mailbox of the
+    /// transfer.
+    ///
+    /// The whole loop is a single SMI transfer of [`LOOP_SMI_LENGTH`] words, it stops once
+    /// these are output, after about 71 minutes at 1 µs per word. Use
+    /// [`LoopingTransfer::is_running`] to check for the end and
+    /// [`LoopingTransfer::start`] to restart it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn configure_loop<'b, SmiDevice: smi::Device, DmaChannel: dma::Channel>(
+        &'b mut self,
+        smi_controller: &'b mut smi::Controller,
+        smi_device: &'b mut SmiDevice,
+        dma_channel: &'b mut DmaChannel,
+        duration: Duration,
+        size: usize,
+        gap: Duration,
+    ) -> Result<LoopingTransfer<'b, GpuMem<'a>, SmiDevice, DmaChannel>, io::Error> {
+        let this: &'b Self = self;
+        if this.frames.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a looping transfer needs at least two frames",
+            ));
+        }
+
+        let size = size.min(this.size);
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a looping transfer needs at least one word per frame",
+            ));
+        }
+
+        let gap_size = gap.as_nanos().div_ceil(duration.as_nanos().max(1)) as usize;
+        let layout = RingLayout {
+            frames: this.frames.len(),
+            data_blocks: data_blocks_of(&this.frames[0], 0, size).len(),
+            tail_blocks: gap_size.div_ceil(CHUNK_SIZE) + 1,
+        };
+        let slots: Vec<usize> = (0..this.frames.len() - 1).collect();
+
+        let ring = GpuMem::alloc(
+            this.frames[0][0].memory().mailbox(),
+            RING_HEADER_SIZE
+                + layout.block_count(slots.len()) * mem::size_of::<dma::ControlBlock>(),
+        )?;
+
+        let smi_data = smi_controller
+            .regs
+            .memmap()
+            .bus
+            .wrapping_byte_add(smi::SMI_D) as u32;
+        let ring_bus = ring.memmap().bus;
+
+        let mut blocks = Vec::with_capacity(layout.block_count(slots.len()));
+        for (frame, data) in this.frames.iter().enumerate() {
+            // The chain of the spare frame is linked once it is put into a slot.
+            let slot = frame.min(slots.len() - 1);
+            let mut chain = data_blocks_of(data, smi_data, size);
+            for (index, block) in chain.iter_mut().enumerate() {
+                block.nextconbk = if index + 1 < layout.data_blocks {
+                    ring_block_bus(&ring, layout.chain_block(frame) + index + 1)
+                } else {
+                    ring_block_bus(&ring, layout.tail_block(slot))
+                };
+            }
+            blocks.extend(chain);
+        }
+        for slot in 0..slots.len() {
+            let tail = layout.tail_block(slot);
+            blocks.extend((0..layout.tail_blocks - 1).map(|chunk| {
+                let mut block = dma::ControlBlock::new(
+                    smi_transfer_info(),
+                    ring_bus.wrapping_byte_add(RING_ZERO_OFFSET) as u32,
+                    smi_data,
+                    ((gap_size - chunk * CHUNK_SIZE).min(CHUNK_SIZE) * 4) as u32,
+                );
+                block.nextconbk = ring_block_bus(&ring, tail + chunk + 1);
+                block
+            }));
+            // Enters the next slot, the copy within the ring header has no effect.
+            let mut link = dma::ControlBlock::new(
+                dma::TransferInfo::new().wait_resp(true),
+                ring_bus.wrapping_byte_add(RING_ZERO_OFFSET) as u32,
+                ring_bus.wrapping_byte_add(RING_LINK_OFFSET) as u32,
+                4,
+            );
+            let next = slots[(slot + 1) % slots.len()];
+            link.nextconbk = ring_block_bus(&ring, layout.chain_block(next));
+            blocks.push(link);
+        }
+
+        for block in &blocks {
+            dma_channel.kind().validate(block)?;
+        }
+
+        unsafe {
+            let header = ring.memmap().virt;
+            header.wrapping_byte_add(RING_ZERO_OFFSET).write_volatile(0);
+            for (index, block) in blocks.iter().enumerate() {
+                write_control_block(dma_channel.kind(), ring_block_ptr(&ring, index), block);
+            }
+        }
+
+        setup_smi(smi_controller, smi_device, duration, LOOP_SMI_LENGTH)?;
+        dma_channel.enable();
+
+        Ok(LoopingTransfer {
+            frames: &this.frames,
+            spare: slots.len(),
+            slots,
+            ring,
+            layout,
+            size,
+            smi_controller,
+            _smi_device: smi_device,
+            dma_channel,
+        })
+    }
+}
+
+/// Positions of the control blocks in the ring memory.
+///
+/// Every frame has its own chain of data blocks, followed by the tail of each slot with the
+/// gap blocks and the link block. The link block of a slot links to the chain of the frame
+/// in the next slot, so a frame is switched by changing that single link.
+#[derive(Clone, Copy, Debug)]
+struct RingLayout {
+    frames: usize,
+    data_blocks: usize,
+    /// Gap blocks and the link block.
+    tail_blocks: usize,
+}
+
+impl RingLayout {
+    fn block_count(&self, slots: usize) -> usize {
+        self.frames * self.data_blocks + slots * self.tail_blocks
+    }
+
+    fn chain_block(&self, frame: usize) -> usize {
+        frame * self.data_blocks
+    }
+
+    fn chain(&self, frame: usize) -> ops::Range<usize> {
+        self.chain_block(frame)..self.chain_block(frame + 1)
+    }
+
+    fn tail_block(&self, slot: usize) -> usize {
+        self.frames * self.data_blocks + slot * self.tail_blocks
+    }
+
+    fn link_block(&self, slot: usize) -> usize {
+        self.tail_block(slot) + self.tail_blocks - 1
+    }
+}
+
+/// Frames of a transfer output in a loop by the DMA engine, see
+/// [`Transfer::configure_loop`].
+pub struct LoopingTransfer<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
+    frames: &'a [Vec<DmaBuffer<u32, M>>],
+    /// Frame output by each slot of the ring.
+    slots: Vec<usize>,
+    /// Frame not used by the ring.
+    spare: usize,
+    ring: GpuMem<'a>,
+    layout: RingLayout,
+    size: usize,
+    smi_controller: &'a mut smi::Controller,
+    _smi_device: &'a mut SmiDevice,
+    dma_channel: &'a mut DmaChannel,
+}
+
+impl<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    LoopingTransfer<'a, M, SmiDevice, DmaChannel>
+{
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of frames in the ring.
+    pub fn slot_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Starts the loop at the first slot, a running loop is restarted.
+    pub fn start(&mut self) {
+        stop_hardware(self.smi_controller, self.dma_channel);
+
+        for &frame in &self.slots {
+            for segment in &self.frames[frame] {
+                segment.set_in_flight(true);
+            }
+        }
+
+        self.smi_controller.set_length(LOOP_SMI_LENGTH);
+
+        self.dma_channel.set_control_block_address(ring_block_bus(
+            &self.ring,
+            self.layout.chain_block(self.slots[0]),
+        ));
+        self.dma_channel.clear_end();
+        self.dma_channel.clear_error();
+        self.dma_channel.clear_debug();
+        self.dma_channel.start();
+
+        self.smi_controller.start();
+    }
+
+    /// Returns whether the loop is being output, it stops after [`LOOP_SMI_LENGTH`] words.
+    ///
+    /// The DMA engine keeps waiting for SMI once the loop ended, so only SMI is checked.
+    pub fn is_running(&self) -> bool {
+        self.smi_controller.active() && !self.dma_channel.status().error
+    }
+
+    pub fn stop(&mut self) {
+        stop_hardware(self.smi_controller, self.dma_channel);
+
+        for segment in self.frames.iter().flatten() {
+            segment.set_in_flight(false);
+        }
+    }
+
+    /// Returns the slot whose frame or gap is being output, read from the control block
+    /// address of the DMA channel.
+    pub fn current_slot(&self) -> Option<usize> {
+        let index = self.current_block()?;
+        if index >= self.layout.tail_block(0) {
+            return Some((index - self.layout.tail_block(0)) / self.layout.tail_blocks);
+        }
+
+        // A replaced frame can still be finished after it became the spare.
+        let frame = index / self.layout.data_blocks;
+        self.slots
+            .iter()
+            .position(|&slot_frame| slot_frame == frame)
+    }
+
+    /// Replaces the frame of `slot` without interrupting the loop.
+    ///
+    /// The data is written into the spare frame, which takes the place of the frame of
+    /// `slot` the next time the DMA engine enters the slot. The frame is switched as a whole,
+    /// a pass through the slot outputs either the old or the new frame. This waits until the
+    /// DMA engine is done with the old frame, which becomes the new spare.
+    ///
+    /// If the DMA engine is still in the old frame after `timeout` the loop is stopped and
+    /// [`WaitError::Timeout`] is returned, so the old frame can be reused. `timeout` should
+    /// be longer than a pass through the slot. A loop stopped by a DMA error returns it.
+    pub fn replace_frame(
+        &mut self,
+        slot: usize,
+        data: &[u32],
+        timeout: Duration,
+    ) -> Result<(), WaitError> {
+        assert!(slot < self.slots.len());
+
+        let spare = &self.frames[self.spare];
+        let mut remaining = &data[..data.len().min(self.size)];
+        for segment in spare {
+            let len = remaining.len().min(segment.len());
+            // The spare frame is not read by the DMA engine.
+            unsafe { segment.write_unchecked(&remaining[..len]) };
+            remaining = &remaining[len..];
+        }
+        for segment in spare {
+            segment.set_in_flight(true);
+        }
+
+        let kind = self.dma_channel.kind();
+        let layout = self.layout;
+        let entry = layout.link_block((slot + self.slots.len() - 1) % self.slots.len());
+        unsafe {
+            // The chain of the spare frame is not linked into the ring yet.
+            write_next_address(
+                kind,
+                ring_block_ptr(&self.ring, layout.chain(self.spare).end - 1),
+                ring_block_bus(&self.ring, layout.tail_block(slot)),
+            );
+            atomic::fence(Ordering::SeqCst);
+            write_next_address(
+                kind,
+                ring_block_ptr(&self.ring, entry),
+                ring_block_bus(&self.ring, layout.chain_block(self.spare)),
+            );
+        }
+
+        // The engine can still enter the old chain if it loaded the entry block before the
+        // switch, and runs until the end of the old chain once it is in there.
+        let previous = mem::replace(&mut self.slots[slot], self.spare);
+        let old_chain = layout.chain(previous);
+        let start = Instant::now();
+        let result = loop {
+            if !self
+                .current_block()
+                .is_some_and(|index| index == entry || old_chain.contains(&index))
+            {
+                break self.dma_channel.check_error().map_err(WaitError::Dma);
+            }
+            if start.elapsed() >= timeout {
+                self.stop();
+                break Err(WaitError::Timeout);
+            }
+            thread::yield_now();
+        };
+
+        for segment in &self.frames[previous] {
+            segment.set_in_flight(false);
+        }
+        self.spare = previous;
+        result
+    }
+
+    fn current_block(&self) -> Option<usize> {
+        if !self.dma_channel.status().active {
+            return None;
+        }
+
+        let address = self.dma_channel.control_block_address() & !BUS_ALIAS_MASK;
+        let first = ring_block_bus(&self.ring, 0) & !BUS_ALIAS_MASK;
+        let index = address.checked_sub(first)? as usize / mem::size_of::<dma::ControlBlock>();
+        (index < self.layout.block_count(self.slots.len())).then_some(index)
+    }
+}
+
+impl<'a, M: DmaMemory, SmiDevice: smi::Device, DmaChannel: dma::Channel> Drop
+    for LoopingTransfer<'a, M, SmiDevice, DmaChannel>
+{
+    fn drop(&mut self) {
+        // The loop never ends, it has to be stopped before the ring is freed.
+        self.stop();
+    }
+}
+
+// Unlinked control blocks moving the first `size` words of `frame` to `dest`.
+fn data_blocks_of<M: DmaMemory>(
+    frame: &[DmaBuffer<u32, M>],
+    dest: u32,
+    size: usize,
+) -> Vec<dma::ControlBlock> {
+    let mut blocks = Vec::new();
+    let mut remaining = size;
+    for segment in frame {
+        let len = remaining.min(segment.len());
+        remaining -= len;
+
+        for offset in (0..len).step_by(CHUNK_SIZE) {
+            blocks.push(dma::ControlBlock::new(
+                smi_transfer_info().src_inc(true),
+                segment.memmap().bus.wrapping_byte_add(offset * 4) as u32,
+                dest,
+                ((len - offset).min(CHUNK_SIZE) * 4) as u32,
+            ));
+        }
+    }
+    blocks
+}
+
+fn ring_block_ptr(ring: &GpuMem, index: usize) -> *mut dma::ControlBlock {
+    ring.memmap()
+        .virt
+        .wrapping_byte_add(RING_HEADER_SIZE + index * mem::size_of::<dma::ControlBlock>())
+        .cast()
+}
+
+fn ring_block_bus(ring: &GpuMem, index: usize) -> u32 {
+    ring.memmap()
+        .bus
+        .wrapping_byte_add(RING_HEADER_SIZE + index * mem::size_of::<dma::ControlBlock>())
+        as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::{platform::Soc, sim::Board, sim::Timeline};
+
+    const PERIOD: Duration = Duration::from_micros(1);
+    const GAP: Duration = Duration::from_micros(4);
+    // Two data blocks per frame.
+    const SIZE: usize = CHUNK_SIZE + 10;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // Returns the values of the timeline with the number of consecutive samples.
+    fn runs(timeline: &Timeline) -> Vec<(u32, usize)> {
+        let mut runs: Vec<(u32, usize)> = Vec::new();
+        for sample in &timeline.samples {
+            match runs.last_mut() {
+                Some((value, count)) if *value == sample.value => *count += 1,
+                _ => runs.push((sample.value, 1)),
+            }
+        }
+        runs
+    }
+
+    fn pass_duration() -> Duration {
+        PERIOD * (2 * (SIZE + 4)) as u32
+    }
+
+    #[test]
+    fn frames_are_output_in_a_loop() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new_buffered(&mailbox, SIZE, 3).unwrap();
+        let mut looping = transfer
+            .configure_loop(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                PERIOD,
+                SIZE,
+                GAP,
+            )
+            .unwrap();
+        looping.replace_frame(0, &[1; SIZE], TIMEOUT).unwrap();
+        looping.replace_frame(1, &[2; SIZE], TIMEOUT).unwrap();
+        assert_eq!(looping.current_slot(), None);
+        assert!(!looping.is_running());
+
+        looping.start();
+        let timeline = board.run_for(pass_duration() * 2);
+        assert_eq!(
+            runs(&timeline),
+            [(1, SIZE), (0, 4), (2, SIZE), (0, 4)].repeat(2)
+        );
+        assert_eq!(looping.current_slot(), Some(0));
+        assert!(looping.is_running());
+
+        looping.stop();
+        assert_eq!(looping.current_slot(), None);
+        assert!(!looping.is_running());
+    }
+
+    #[test]
+    fn stuck_replacements_time_out() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut transfer = Transfer::new_buffered(&mailbox, SIZE, 3).unwrap();
+        let mut looping = transfer
+            .configure_loop(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                PERIOD,
+                SIZE,
+                GAP,
+            )
+            .unwrap();
+        looping.replace_frame(0, &[1; SIZE], TIMEOUT).unwrap();
+        looping.replace_frame(1, &[2; SIZE], TIMEOUT).unwrap();
+
+        // The board doesn't run, so the DMA engine never leaves the first frame.
+        looping.start();
+        assert_eq!(looping.current_slot(), Some(0));
+        assert_eq!(
+            looping.replace_frame(0, &[3; SIZE], Duration::from_millis(10)),
+            Err(WaitError::Timeout)
+        );
+        assert_eq!(looping.current_slot(), None);
+        assert!(looping
+            .frames
+            .iter()
+            .flatten()
+            .all(|segment| !segment.is_in_flight()));
+    }
+
+    #[test]
+    fn replaced_frames_are_switched_as_a_whole() {
+        let board = Board::new(Soc::Bcm2711);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        // A DMA4 channel, whose links are encoded differently.
+        let mut channel = dma.channels.take(11).unwrap();
+
+        let mut transfer = Transfer::new_buffered(&mailbox, SIZE, 3).unwrap();
+        let mut looping = transfer
+            .configure_loop(
+                &mut smi.controller,
+                &mut device,
+                &mut channel,
+                PERIOD,
+                SIZE,
+                GAP,
+            )
+            .unwrap();
+        looping.replace_frame(0, &[1; SIZE], TIMEOUT).unwrap();
+        looping.replace_frame(1, &[2; SIZE], TIMEOUT).unwrap();
+        looping.start();
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    board.run_for(pass_duration());
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+
+            for value in 3..8 {
+                looping.replace_frame(0, &[value; SIZE], TIMEOUT).unwrap();
+                let spare = &looping.frames[looping.spare];
+                assert!(spare.iter().all(|segment| !segment.is_in_flight()));
+            }
+            done.store(true, Ordering::Release);
+        });
+
+        // The first run repeats the block the last run stopped in.
+        let timeline = board.run_for(pass_duration() * 3);
+        let runs = runs(&timeline);
+        let passes = [(7, SIZE), (0, 4), (2, SIZE), (0, 4)];
+        let start = runs.iter().position(|&run| run == passes[0]).unwrap();
+        assert!(start <= 4, "{:?}", &runs[..start]);
+        assert_eq!(runs[start..start + 8], passes.repeat(2));
+    }
+}
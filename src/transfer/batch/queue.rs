@@ -0,0 +1,425 @@
+use std::{collections::VecDeque, sync::mpsc, thread};
+
+use super::*;
+
+/// Time a frame may take longer than its expected duration before it is stopped.
+pub const FRAME_TIMEOUT_MARGIN: Duration = Duration::from_millis(100);
+
+/// Data of a single frame of a [`TransferQueue`] with its own timing.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub data: Vec<u32>,
+    /// Duration of each word.
+    pub period: Duration,
+    /// Time the bus stays idle after the frame before the next one starts.
+    ///
+    /// The delay is waited for with `thread::sleep` before the next frame is configured, so
+    /// it is only a lower bound and not timed by the hardware.
+    pub delay: Duration,
+}
+
+impl Frame {
+    pub fn new(data: Vec<u32>, period: Duration) -> Self {
+        Frame {
+            data,
+            period,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Timing of a completed frame.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    /// Id returned by [`TransferQueue::push`].
+    pub id: u64,
+    pub size: usize,
+    pub period: Duration,
+    /// `size` times `period`.
+    pub expected: Duration,
+    /// Time from starting the frame until its end was seen.
+    pub actual: Duration,
+}
+
+/// Totals over all frames completed by a [`TransferQueue`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    pub frames: u64,
+    pub words: u64,
+    pub expected: Duration,
+    pub actual: Duration,
+}
+
+type Completion<'a> = Box<dyn FnMut(&FrameStats) + 'a>;
+
+/// Outputs frames of different length and timing one after another.
+///
+/// SMI and DMA are configured again for every frame. The transfer memory is shared by all
+/// frames and grows with the largest frame pushed.
+pub struct TransferQueue<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel> {
+    mailbox: &'a Mailbox,
+    transfer: Option<Transfer<GpuMem<'a>>>,
+    frames: VecDeque<(u64, Frame)>,
+    next_id: u64,
+    /// Earliest start of the next frame, set by the delay of the last one.
+    next_start: Option<Instant>,
+    callbacks: Vec<Completion<'a>>,
+    stats: QueueStats,
+    smi_controller: &'a mut smi::Controller,
+    smi_device: &'a mut SmiDevice,
+    dma_channel: &'a mut DmaChannel,
+    clock: Option<&'a ClockGuard<'a>>,
+}
+
+impl<'a, SmiDevice: smi::Device, DmaChannel: dma::Channel>
+    TransferQueue<'a, SmiDevice, DmaChannel>
+{
+    /// Creates an empty queue, `clock` is held like by [`Transfer::configure`].
+    pub fn new(
+        mailbox: &'a Mailbox,
+        smi_controller: &'a mut smi::Controller,
+        smi_device: &'a mut SmiDevice,
+        dma_channel: &'a mut DmaChannel,
+        clock: Option<&'a ClockGuard<'a>>,
+    ) -> Self {
+        TransferQueue {
+            mailbox,
+            transfer: None,
+            frames: VecDeque::new(),
+            next_id: 0,
+            next_start: None,
+            callbacks: Vec::new(),
+            stats: QueueStats::default(),
+            smi_controller,
+            smi_device,
+            dma_channel,
+            clock,
+        }
+    }
+
+    /// Adds a frame to the end of the queue and returns its id.
+    pub fn push(&mut self, frame: Frame) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.frames.push_back((id, frame));
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Calls `callback` whenever a frame is completed.
+    pub fn on_complete(&mut self, callback: impl FnMut(&FrameStats) + 'a) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Sends the stats of every completed frame to `sender`.
+    pub fn notify(&mut self, sender: mpsc::Sender<FrameStats>) {
+        self.on_complete(move |stats| {
+            // A dropped receiver only stops the notifications.
+            let _ = sender.send(*stats);
+        });
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+
+    /// Outputs the next frame and waits until it is finished.
+    ///
+    /// Returns `None` if the queue is empty. A frame failing with an error is removed from
+    /// the queue without calling the completion callbacks. A frame not finished
+    /// [`FRAME_TIMEOUT_MARGIN`] after its expected duration is stopped and fails with
+    /// [`QueueError::Timeout`].
+    pub fn run_next(&mut self) -> Option<Result<FrameStats, QueueError>> {
+        let (id, frame) = self.frames.pop_front()?;
+        Some(self.output(id, frame))
+    }
+
+    /// Outputs all queued frames, stopping at the first error.
+    pub fn run(&mut self) -> Result<(), QueueError> {
+        while let Some(result) = self.run_next() {
+            result?;
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, id: u64, frame: Frame) -> Result<FrameStats, QueueError> {
+        let size = frame.data.len();
+        let expected = expected_duration(frame.period, size);
+        let transfer = match &mut self.transfer {
+            Some(transfer) if transfer.size() >= size => transfer,
+            // The current transfer is kept if the larger one can't be allocated.
+            _ => self
+                .transfer
+                .insert(Transfer::new(self.mailbox, size).map_err(QueueError::Alloc)?),
+        };
+
+        if let Some(next_start) = self.next_start.take() {
+            thread::sleep(next_start.saturating_duration_since(Instant::now()));
+        }
+
+        let actual = if size == 0 {
+            Duration::ZERO
+        } else {
+            let mut configured = transfer.configure(
+                self.smi_controller,
+                self.smi_device,
+                self.dma_channel,
+                self.clock,
+                frame.period,
+                size,
+            )?;
+            configured.set_data(&frame.data)?;
+
+            let start = Instant::now();
+            configured.start()?;
+            configured.wait(expected.saturating_add(FRAME_TIMEOUT_MARGIN))?;
+            start.elapsed()
+        };
+        self.next_start = Some(Instant::now() + frame.delay);
+
+        let stats = FrameStats {
+            id,
+            size,
+            period: frame.period,
+            expected,
+            actual,
+        };
+
+        self.stats.frames += 1;
+        self.stats.words += size as u64;
+        self.stats.expected = self.stats.expected.saturating_add(stats.expected);
+        self.stats.actual = self.stats.actual.saturating_add(stats.actual);
+
+        for callback in &mut self.callbacks {
+            callback(&stats);
+        }
+
+        Ok(stats)
+    }
+}
+
+// `size` times `period`, saturated at `Duration::MAX`.
+fn expected_duration(period: Duration, size: usize) -> Duration {
+    u32::try_from(size)
+        .ok()
+        .and_then(|size| period.checked_mul(size))
+        .unwrap_or(Duration::MAX)
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// The transfer memory for a frame could not be allocated.
+    Alloc(io::Error),
+    Configure(ConfigureError),
+    /// SMI or DMA were still busy with a transfer started elsewhere.
+    Busy(BusyError),
+    Dma(dma::DmaError),
+    /// The frame didn't finish in time and was stopped.
+    Timeout,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Alloc(err) => write!(f, "failed to allocate frame memory: {err}"),
+            QueueError::Configure(err) => write!(f, "{err}"),
+            QueueError::Busy(err) => write!(f, "{err}"),
+            QueueError::Dma(err) => write!(f, "{err}"),
+            QueueError::Timeout => write!(f, "frame did not finish in time"),
+        }
+    }
+}
+
+impl error::Error for QueueError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            QueueError::Alloc(err) => Some(err),
+            QueueError::Configure(err) => Some(err),
+            QueueError::Busy(err) => Some(err),
+            QueueError::Dma(err) => Some(err),
+            QueueError::Timeout => None,
+        }
+    }
+}
+
+impl From<ConfigureError> for QueueError {
+    fn from(err: ConfigureError) -> Self {
+        QueueError::Configure(err)
+    }
+}
+
+impl From<BusyError> for QueueError {
+    fn from(err: BusyError) -> Self {
+        QueueError::Busy(err)
+    }
+}
+
+impl From<dma::DmaError> for QueueError {
+    fn from(err: dma::DmaError) -> Self {
+        QueueError::Dma(err)
+    }
+}
+
+impl From<WaitError> for QueueError {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::Timeout => QueueError::Timeout,
+            WaitError::Dma(err) => QueueError::Dma(err),
+        }
+    }
+}
+
+impl From<QueueError> for io::Error {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::Alloc(err) => err,
+            QueueError::Configure(err) => err.into(),
+            QueueError::Busy(err) => err.into(),
+            QueueError::Dma(err) => err.into(),
+            QueueError::Timeout => io::Error::new(io::ErrorKind::TimedOut, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::atomic::{AtomicBool, Ordering},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{
+        platform::Soc,
+        sim::{Board, Timeline},
+    };
+
+    #[test]
+    fn expected_duration_saturates() {
+        let period = Duration::from_micros(1);
+        assert_eq!(expected_duration(period, 1000), Duration::from_millis(1));
+        assert_eq!(expected_duration(Duration::MAX, 2), Duration::MAX);
+        assert_eq!(
+            expected_duration(period, u32::MAX as usize + 1),
+            Duration::MAX
+        );
+    }
+
+    #[test]
+    fn failed_allocation_keeps_the_transfer() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let mut queue = TransferQueue::new(
+            &mailbox,
+            &mut smi.controller,
+            &mut device,
+            &mut channel,
+            None,
+        );
+        let period = Duration::from_micros(1);
+        queue.push(Frame::new(Vec::new(), period));
+        queue.run_next().unwrap().unwrap();
+        assert!(queue.transfer.is_some());
+
+        // Larger than the simulated GPU memory.
+        queue.push(Frame::new(vec![0; 0x1000_0000], period));
+        let result = queue.run_next().unwrap();
+        assert!(matches!(result, Err(QueueError::Alloc(_))));
+        assert!(queue.transfer.is_some());
+    }
+
+    #[test]
+    fn frames_are_output_with_their_own_period() {
+        let board = Board::new(Soc::Bcm2837);
+        let platform = board.platform();
+        let mailbox = board.mailbox();
+        let mut smi = smi::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let dma = dma::Peripheral::open_with(&platform, board.memory()).unwrap();
+        let mut device = smi.devices.device0().unwrap();
+        let mut channel = dma.channels.channel5().unwrap();
+
+        let completed = RefCell::new(Vec::new());
+        let (sender, receiver) = mpsc::channel();
+        let mut queue = TransferQueue::new(
+            &mailbox,
+            &mut smi.controller,
+            &mut device,
+            &mut channel,
+            None,
+        );
+        queue.on_complete(|stats| completed.borrow_mut().push(*stats));
+        queue.notify(sender);
+
+        let fast = Duration::from_micros(1);
+        let slow = Duration::from_micros(3);
+        let first = queue.push(Frame::new(vec![1; 50], fast));
+        let second = queue.push(Frame::new(vec![2; 20], slow));
+
+        // The queue waits for every frame, so the board runs next to it.
+        let timelines = Mutex::new(Vec::<Timeline>::new());
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    let timeline = board.run();
+                    if !timeline.samples.is_empty() {
+                        timelines.lock().unwrap().push(timeline);
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let result = queue.run();
+            done.store(true, Ordering::Release);
+            result.unwrap();
+        });
+
+        let mut values = Vec::new();
+        for timeline in timelines.into_inner().unwrap() {
+            for pair in timeline.samples.windows(2) {
+                if pair[0].value == pair[1].value {
+                    let period = if pair[0].value == 1 { fast } else { slow };
+                    assert_eq!(pair[1].time - pair[0].time, period);
+                }
+            }
+            values.extend(timeline.samples.iter().map(|sample| sample.value));
+        }
+        assert_eq!(values, [vec![1; 50], vec![2; 20]].concat());
+
+        assert_eq!(queue.stats().frames, 2);
+        assert_eq!(queue.stats().words, 70);
+        drop(queue);
+
+        let completed = completed.into_inner();
+        let notified: Vec<_> = receiver.try_iter().collect();
+        for stats in [&completed, &notified] {
+            let stats: Vec<_> = stats
+                .iter()
+                .map(|stats| (stats.id, stats.size, stats.period, stats.expected))
+                .collect();
+            assert_eq!(
+                stats,
+                [(first, 50, fast, fast * 50), (second, 20, slow, slow * 20)]
+            );
+        }
+        assert!(completed.iter().all(|stats| stats.actual > Duration::ZERO));
+    }
+}